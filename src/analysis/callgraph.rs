@@ -0,0 +1,169 @@
+use crate::instructions::Instruction;
+use crate::module::*;
+
+/// Calls between the functions of a module, from the `Call`/`TailCall`
+/// instructions of their reachable and unreachable blocks alike. The targets
+/// of `CallClosure` are not known, effect summaries treat them as unknown.
+///
+/// `sccs` lists the strongly connected components bottom-up: every
+/// component comes after all the components it calls into.
+#[derive(Default)]
+pub struct CallGraph {
+    pub callees: Vec<Vec<u32>>,
+    pub callers: Vec<Vec<u32>>,
+    pub sccs: Vec<Vec<u32>>,
+    scc_index: Vec<usize>,
+    self_calls: Vec<bool>,
+}
+
+impl CallGraph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn analyze(&mut self, module: &Module) {
+        let n = module.functions.len();
+        self.callees = vec![vec![]; n];
+        self.callers = vec![vec![]; n];
+        self.self_calls = vec![false; n];
+        for (caller, function) in module.functions.iter().enumerate() {
+            for block in function.cfg.blocks.iter() {
+                for ins in block.borrow().instructions.iter() {
+                    if let Instruction::Call(callee) | Instruction::TailCall(callee) = ins {
+                        if (*callee as usize) < n {
+                            self.callees[caller].push(*callee);
+                        }
+                    }
+                }
+            }
+            self.callees[caller].sort_unstable();
+            self.callees[caller].dedup();
+            for callee in self.callees[caller].iter() {
+                self.callers[*callee as usize].push(caller as u32);
+                if *callee as usize == caller {
+                    self.self_calls[caller] = true;
+                }
+            }
+        }
+        self.compute_sccs();
+    }
+
+    // Tarjan's algorithm with an explicit stack. Components are completed
+    // callees first, which is the bottom-up order.
+    fn compute_sccs(&mut self) {
+        let n = self.callees.len();
+        let mut index = vec![usize::MAX; n];
+        let mut low = vec![0; n];
+        let mut on_stack = vec![false; n];
+        let mut stack = vec![];
+        let mut next = 0;
+        self.sccs.clear();
+        self.scc_index = vec![0; n];
+
+        for root in 0..n {
+            if index[root] != usize::MAX {
+                continue;
+            }
+            let mut work = vec![(root, 0)];
+            while let Some((f, i)) = work.pop() {
+                if i == 0 {
+                    index[f] = next;
+                    low[f] = next;
+                    next += 1;
+                    stack.push(f);
+                    on_stack[f] = true;
+                }
+                if let Some(callee) = self.callees[f].get(i) {
+                    let callee = *callee as usize;
+                    work.push((f, i + 1));
+                    if index[callee] == usize::MAX {
+                        work.push((callee, 0));
+                    } else if on_stack[callee] {
+                        low[f] = std::cmp::min(low[f], index[callee]);
+                    }
+                    continue;
+                }
+
+                if let Some((caller, _)) = work.last() {
+                    low[*caller] = std::cmp::min(low[*caller], low[f]);
+                }
+                if low[f] == index[f] {
+                    let mut scc = vec![];
+                    loop {
+                        let g = stack.pop().unwrap();
+                        on_stack[g] = false;
+                        self.scc_index[g] = self.sccs.len();
+                        scc.push(g as u32);
+                        if g == f {
+                            break;
+                        }
+                    }
+                    scc.sort_unstable();
+                    self.sccs.push(scc);
+                }
+            }
+        }
+    }
+
+    /// The component containing `function`, as an index into `sccs`.
+    pub fn scc_of(&self, function: u32) -> usize {
+        self.scc_index[function as usize]
+    }
+
+    /// Whether `function` can call itself, directly or through others.
+    pub fn is_recursive(&self, function: u32) -> bool {
+        self.self_calls[function as usize] || self.sccs[self.scc_of(function)].len() > 1
+    }
+
+    /// Functions ordered so that callees come before their callers, except
+    /// within a recursive cycle.
+    pub fn bottom_up_order(&self) -> Vec<u32> {
+        self.sccs.iter().flatten().copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::EdgeType::*;
+    use crate::cfg::ControlFlowGraph;
+
+    fn calling(callees: &[u32]) -> Function {
+        let cfg = ControlFlowGraph::from_blocks(
+            vec![callees.iter().map(|n| Instruction::Call(*n)).collect()],
+            &[(0, 2, FallThrough), (2, 1, FallThrough)],
+        );
+        Function::new("f", 0, cfg)
+    }
+
+    // 0 calls 1 and 3, 1 and 2 call each other, 3 calls itself and 2 calls
+    // the leaf 4.
+    fn module() -> Module {
+        let mut module = Module::new();
+        for callees in [&[1, 3][..], &[2], &[1, 4], &[3], &[]].iter() {
+            module.add_function(calling(callees));
+        }
+        module
+    }
+
+    #[test]
+    fn finds_components_bottom_up() {
+        let mut cg = CallGraph::new();
+        cg.analyze(&module());
+
+        assert_eq!(cg.sccs, vec![vec![4], vec![1, 2], vec![3], vec![0]]);
+        assert_eq!(cg.bottom_up_order(), vec![4, 1, 2, 3, 0]);
+        assert_eq!(cg.scc_of(2), cg.scc_of(1));
+        assert_eq!(cg.callers[1], vec![0, 2]);
+        assert_eq!(cg.callers[4], vec![2]);
+    }
+
+    #[test]
+    fn detects_direct_and_mutual_recursion() {
+        let mut cg = CallGraph::new();
+        cg.analyze(&module());
+
+        let recursive = (0..5).map(|f| cg.is_recursive(f)).collect::<Vec<_>>();
+        assert_eq!(recursive, vec![false, true, true, true, false]);
+    }
+}
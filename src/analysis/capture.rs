@@ -0,0 +1,124 @@
+use crate::instructions::Instruction;
+use crate::module::*;
+
+use std::collections::BTreeSet;
+
+/// Which environment slots closures can reach.
+///
+/// A closure of function `c` created by `f` runs with a copy of `f`'s
+/// environment, and so do the closures `c` creates in turn. The slots any of them accesses are
+/// captured and have to stay boxed in the environment. The other slots of a
+/// function that does not itself run as a closure are only ever seen by its
+/// own frame and can be plain locals.
+#[derive(Default, Debug)]
+pub struct CaptureAnalysis {
+    /// Environment slots of each function that its closures may access.
+    pub captured: Vec<BTreeSet<u32>>,
+    /// Environment slots each function accesses itself.
+    pub accessed: Vec<BTreeSet<u32>>,
+    /// Functions closures are created of.
+    pub closures: BTreeSet<u32>,
+}
+
+impl CaptureAnalysis {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn analyze(&mut self, module: &Module) {
+        let n = module.functions.len();
+        let mut creates = vec![BTreeSet::new(); n];
+        self.accessed = vec![BTreeSet::new(); n];
+        self.closures.clear();
+        for (f, function) in module.functions.iter().enumerate() {
+            for block in function.cfg.blocks.iter() {
+                for ins in block.borrow().instructions.iter() {
+                    match ins {
+                        Instruction::LdEnv(slot) | Instruction::StEnv(slot) => {
+                            self.accessed[f].insert(*slot);
+                        }
+                        Instruction::MkClosure(c) if (*c as usize) < n => {
+                            creates[f].insert(*c);
+                            self.closures.insert(*c);
+                        }
+                        _ => (),
+                    }
+                }
+            }
+        }
+
+        self.captured = vec![BTreeSet::new(); n];
+        for f in 0..n {
+            let mut visited = BTreeSet::new();
+            let mut stack = creates[f].iter().copied().collect::<Vec<_>>();
+            while let Some(c) = stack.pop() {
+                if !visited.insert(c) {
+                    continue;
+                }
+                self.captured[f].extend(self.accessed[c as usize].iter().copied());
+                stack.extend(creates[c as usize].iter().copied());
+            }
+        }
+    }
+
+    pub fn is_captured(&self, function: u32, slot: u32) -> bool {
+        self.captured
+            .get(function as usize)
+            .is_some_and(|captured| captured.contains(&slot))
+    }
+
+    /// The environment slots of `function` that can be plain locals. None for
+    /// closures, whose environment starts out as a copy of their creator's.
+    pub fn unboxed(&self, function: u32) -> Vec<u32> {
+        if self.closures.contains(&function) {
+            return vec![];
+        }
+        match self.accessed.get(function as usize) {
+            Some(accessed) => accessed
+                .iter()
+                .filter(|slot| !self.is_captured(function, **slot))
+                .copied()
+                .collect(),
+            None => vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::EdgeType::FallThrough;
+    use crate::cfg::ControlFlowGraph;
+    use Instruction::{LdEnv, MkClosure, StEnv};
+
+    fn function(instructions: Vec<Instruction>) -> Function {
+        let cfg = ControlFlowGraph::from_blocks(
+            vec![instructions],
+            &[(0, 2, FallThrough), (2, 1, FallThrough)],
+        );
+        Function::new("f", 0, cfg)
+    }
+
+    #[test]
+    fn finds_slots_reached_through_nested_closures() {
+        let mut module = Module::new();
+        module.add_function(function(vec![LdEnv(0), StEnv(1), MkClosure(1)]));
+        module.add_function(function(vec![LdEnv(1), MkClosure(2), StEnv(3)]));
+        module.add_function(function(vec![LdEnv(2), StEnv(2)]));
+        module.add_function(function(vec![LdEnv(0), StEnv(0)]));
+        let mut ca = CaptureAnalysis::new();
+        ca.analyze(&module);
+
+        assert_eq!(ca.closures, [1, 2].iter().copied().collect());
+        assert_eq!(ca.captured[0], [1, 2, 3].iter().copied().collect());
+        assert_eq!(ca.captured[1], [2].iter().copied().collect());
+        assert!(ca.captured[3].is_empty());
+        assert!(ca.is_captured(0, 1));
+        assert!(!ca.is_captured(0, 0));
+
+        assert_eq!(ca.unboxed(0), vec![0]);
+        assert_eq!(ca.unboxed(1), vec![]);
+        assert_eq!(ca.unboxed(3), vec![0]);
+        assert_eq!(ca.unboxed(4), vec![]);
+    }
+}
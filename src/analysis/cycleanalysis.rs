@@ -4,6 +4,12 @@ use crate::cfg::*;
 use std::cell::RefCell;
 use std::rc::Rc;
 
+/// Finds the back edges of a graph by a depth-first search from the entry.
+///
+/// Exception edges count like any other edge. A handler that jumps back into
+/// the code it protects closes a loop, and so does a throw to a handler that
+/// leads back to the throwing block. Either loop has to be found for it to be
+/// preempted and for loop passes to see it.
 #[derive(Debug)]
 pub struct CycleAnalysis {
     pub back_edges: Vec<Rc<RefCell<Edge>>>,
@@ -14,20 +20,33 @@ impl CycleAnalysis {
     }
 
     pub fn analyze(&mut self, cfg: &ControlFlowGraph) {
-        let mut visited = BlockSet::new();
+        // an edge is a back edge when its tail is still on the DFS stack
+        let mut visited = std::collections::HashSet::new();
+        let mut on_stack = std::collections::HashSet::new();
         let mut stack = vec![];
 
-        stack.push(cfg.blocks.first().unwrap().clone());
-        while !stack.is_empty() {
-            let block = stack.pop().unwrap();
-            for edge in block.borrow().out_edges.iter() {
-                if visited.insert(edge.borrow().tail.as_ref().unwrap().clone()) {
-                    stack.push(edge.borrow().tail.as_ref().unwrap().clone());
-                } else {
-                    if !self.back_edges.contains(edge) {
-                        self.back_edges.push(edge.clone());
-                    }
+        let entry = cfg.get_entry_block();
+        visited.insert(entry.borrow().id);
+        on_stack.insert(entry.borrow().id);
+        stack.push((entry, 0));
+        while let Some((block, next)) = stack.pop() {
+            let edge = block.borrow().out_edges.get(next).cloned();
+            let edge = match edge {
+                Some(edge) => edge,
+                None => {
+                    on_stack.remove(&block.borrow().id);
+                    continue;
                 }
+            };
+            stack.push((block, next + 1));
+
+            let tail = edge.borrow().tail.as_ref().unwrap().clone();
+            let id = tail.borrow().id;
+            if visited.insert(id) {
+                on_stack.insert(id);
+                stack.push((tail, 0));
+            } else if on_stack.contains(&id) && !self.is_back_edge(&edge) {
+                self.back_edges.push(edge);
             }
         }
     }
@@ -46,3 +65,63 @@ impl CycleAnalysis {
             != 0
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instructions::Instruction::*;
+    use EdgeType::*;
+
+    fn back_edges(cfg: &ControlFlowGraph) -> Vec<(usize, usize, EdgeType)> {
+        let mut ca = CycleAnalysis::new();
+        ca.analyze(cfg);
+        ca.all_back_edges()
+            .iter()
+            .map(|edge| {
+                let edge = edge.borrow();
+                let head = edge.head.as_ref().unwrap().borrow().id;
+                let tail = edge.tail.as_ref().unwrap().borrow().id;
+                (head, tail, edge.ty)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn handler_jumping_back_closes_loop() {
+        // 2: try { return 1 / a } 3: catch { a = 1; retry }
+        let cfg = ControlFlowGraph::from_blocks(
+            vec![
+                vec![LdInt(1), LdLocal(0), Div, Ret],
+                vec![Pop(1), LdInt(1), StLocal(0), Jmp(2)],
+            ],
+            &[
+                (0, 2, FallThrough),
+                (2, 3, Exception),
+                (2, 1, Return),
+                (3, 2, Branch),
+            ],
+        );
+        assert_eq!(back_edges(&cfg), vec![(3, 2, Branch)]);
+    }
+
+    #[test]
+    fn throw_to_handler_closes_loop() {
+        // 2: a = 0 3: catch, loop head 4: try { a = a + 1; throw a }
+        let cfg = ControlFlowGraph::from_blocks(
+            vec![
+                vec![LdInt(0), StLocal(0), Jmp(4)],
+                vec![Pop(1)],
+                vec![LdLocal(0), LdInt(1), Add, Dup, StLocal(0), Throw],
+            ],
+            &[
+                (0, 2, FallThrough),
+                (2, 4, Branch),
+                (3, 4, FallThrough),
+                (4, 3, Exception),
+            ],
+        );
+        // the throw is the only way from 4 back to 3
+        assert_eq!(back_edges(&cfg), vec![(3, 4, FallThrough)]);
+    }
+}
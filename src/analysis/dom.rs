@@ -1,6 +1,13 @@
 use crate::block::*;
 use crate::cfg::*;
 
+/// Dominators of the blocks reachable from the entry.
+///
+/// Exception edges count like any other edge, so a handler entered only from
+/// the blocks it protects is dominated by them. A throw leaves a block in the
+/// middle though: when the handler runs, a dominating protected block has
+/// been entered but not necessarily completed. Passes reusing what a block
+/// computes have to check for handler entries themselves, as GVN does.
 pub struct DominatorTree {
     blocks: Vec<CodeBlockRef>,
     i_dom: Vec<i32>,
@@ -35,14 +42,17 @@ impl DominatorTree {
     }
 
     pub fn analyze(&mut self, cfg: &ControlFlowGraph) {
-        let post_order = cfg.topological_sequence();
-        let mut i = 0;
-        for block in post_order.iter() {
-            self.blocks.push(block.clone());
-            self.blocks_to_index.insert(block.clone(), i);
+        self.blocks.clear();
+        self.i_dom.clear();
+        self.dominated.clear();
+        self.blocks_to_index.clear();
 
+        // blocks are numbered in post order, so the entry gets the highest
+        // index and `intersect` walks up from the lower one
+        for (i, block) in cfg.post_order().into_iter().enumerate() {
+            self.blocks.push(block.clone());
+            self.blocks_to_index.insert(block, i);
             self.i_dom.push(-1);
-            i += 1;
         }
         self.compute_dt(cfg);
     }
@@ -58,15 +68,18 @@ impl DominatorTree {
         while changed {
             changed = false;
 
-            for b_ind in 0..self.blocks.len() {
+            for b_ind in (0..self.blocks.len()).rev() {
                 if b_ind == start_node {
-                    break;
+                    continue;
                 }
                 let b: CodeBlockRef = self.blocks[b_ind].clone();
                 let mut new_idom = 0;
                 let mut processed = false;
                 for pred in b.borrow().predecessors.iter() {
-                    let p = *self.blocks_to_index.get(pred).unwrap();
+                    let p = match self.blocks_to_index.get(pred) {
+                        Some(p) => *p,
+                        None => continue,
+                    };
                     if self.i_dom[p] != -1 {
                         if !processed {
                             new_idom = p as i32;
@@ -87,7 +100,7 @@ impl DominatorTree {
 
         self.dominated.resize(self.blocks.len(), vec![]);
         for n in 0..self.blocks.len() {
-            if self.i_dom[n] >= 0 {
+            if self.i_dom[n] >= 0 && n != start_node {
                 self.dominated[self.i_dom[n] as usize].push(n);
             }
         }
@@ -114,6 +127,14 @@ impl DominatorTree {
         dominates || next_id == id
     }
 
+    pub fn get_index(&self, block: &CodeBlockRef) -> Option<usize> {
+        self.blocks_to_index.get(block).copied()
+    }
+
+    pub fn get_block(&self, n: usize) -> CodeBlockRef {
+        self.blocks[n].clone()
+    }
+
     pub fn get_dominator(&self, block: CodeBlockRef) -> Option<CodeBlockRef> {
         let n = *self.blocks_to_index.get(&block).unwrap();
         self.blocks.get(self.i_dom[n] as usize).cloned()
@@ -142,3 +163,36 @@ impl DominatorTree {
         }
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::EdgeType::*;
+    use crate::instructions::Instruction::*;
+
+    #[test]
+    fn protected_block_dominates_handler_and_join() {
+        // 2: try { x = 1 / a } 3: catch { x = 0 } 4: return x
+        let cfg = ControlFlowGraph::from_blocks(
+            vec![
+                vec![LdInt(1), LdLocal(0), Div, StLocal(1), Jmp(4)],
+                vec![Pop(1), LdInt(0), StLocal(1)],
+                vec![LdLocal(1), Ret],
+            ],
+            &[
+                (0, 2, FallThrough),
+                (2, 4, Branch),
+                (2, 3, Exception),
+                (3, 4, FallThrough),
+                (4, 1, Return),
+            ],
+        );
+        let block = |id| cfg.find_block(id).unwrap();
+        let mut dt = DominatorTree::new();
+        dt.analyze(&cfg);
+        assert!(dt.get_dominator(block(3)) == Some(block(2)));
+        assert!(dt.get_dominator(block(4)) == Some(block(2)));
+        assert!(!dt.dominates(block(3), block(4), &cfg));
+    }
+}
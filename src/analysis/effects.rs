@@ -0,0 +1,365 @@
+use super::callgraph::*;
+use super::cycleanalysis::*;
+use crate::instructions::Instruction;
+use crate::module::*;
+
+use std::collections::BTreeSet;
+
+/// A set of slot numbers that may also stand for every slot.
+#[derive(Clone, PartialEq, Eq, Default, Debug)]
+pub struct Locations {
+    pub all: bool,
+    pub slots: BTreeSet<u32>,
+}
+
+impl Locations {
+    pub fn everything() -> Self {
+        Self {
+            all: true,
+            slots: BTreeSet::new(),
+        }
+    }
+
+    pub fn insert(&mut self, n: u32) {
+        if !self.all {
+            self.slots.insert(n);
+        }
+    }
+
+    pub fn contains(&self, n: u32) -> bool {
+        self.all || self.slots.contains(&n)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.all && self.slots.is_empty()
+    }
+
+    pub fn union(&mut self, other: &Locations) {
+        if other.all {
+            *self = Self::everything();
+        } else {
+            for n in other.slots.iter() {
+                self.insert(*n);
+            }
+        }
+    }
+}
+
+/// What executing some code may do outside of its own frame.
+///
+/// Environment slots are numbered per function, so the environment accesses
+/// of a callee show up as `all` in the summary of a call.
+#[derive(Clone, PartialEq, Eq, Default, Debug)]
+pub struct Effects {
+    pub reads_statics: Locations,
+    pub writes_statics: Locations,
+    pub reads_globals: Locations,
+    pub reads_envs: Locations,
+    pub writes_envs: Locations,
+    pub reads_fields: bool,
+    pub writes_fields: bool,
+    pub may_yield: bool,
+    pub may_not_terminate: bool,
+}
+
+impl Effects {
+    /// Effects of code nothing is known about.
+    pub fn unknown() -> Self {
+        Self {
+            reads_statics: Locations::everything(),
+            writes_statics: Locations::everything(),
+            reads_globals: Locations::everything(),
+            reads_envs: Locations::everything(),
+            writes_envs: Locations::everything(),
+            reads_fields: true,
+            writes_fields: true,
+            may_yield: true,
+            may_not_terminate: true,
+        }
+    }
+
+    /// Effects of a single instruction, with calls looked up in `summaries`
+    /// and treated as unknown without them.
+    pub fn of(ins: &Instruction, summaries: Option<&EffectAnalysis>) -> Self {
+        use Instruction::*;
+        let mut effects = Self::default();
+        match ins {
+            LdStatic(n) => effects.reads_statics.insert(*n),
+            StStatic(n) => effects.writes_statics.insert(*n),
+            LdGlobal(n) => effects.reads_globals.insert(*n),
+            LdEnv(n) => effects.reads_envs.insert(*n),
+            StEnv(n) => effects.writes_envs.insert(*n),
+            LdField | LdFieldAt(_) => effects.reads_fields = true,
+            StField | StFieldAt(_) => effects.writes_fields = true,
+            ThreadYield => effects.may_yield = true,
+            Call(n) | TailCall(n) => {
+                return match summaries {
+                    Some(summaries) => summaries.call(*n),
+                    None => Self::unknown(),
+                }
+            }
+            // the closure's function is only known at run time
+            CallClosure(_) => return Self::unknown(),
+            _ => (),
+        }
+        effects
+    }
+
+    pub fn union(&mut self, other: &Effects) {
+        self.reads_statics.union(&other.reads_statics);
+        self.writes_statics.union(&other.writes_statics);
+        self.reads_globals.union(&other.reads_globals);
+        self.reads_envs.union(&other.reads_envs);
+        self.writes_envs.union(&other.writes_envs);
+        self.reads_fields |= other.reads_fields;
+        self.writes_fields |= other.writes_fields;
+        self.may_yield |= other.may_yield;
+        self.may_not_terminate |= other.may_not_terminate;
+    }
+
+    // Other threads may run at a yield point and change any of these.
+
+    pub fn may_write_static(&self, n: u32) -> bool {
+        self.may_yield || self.writes_statics.contains(n)
+    }
+
+    pub fn may_write_global(&self) -> bool {
+        self.may_yield
+    }
+
+    pub fn may_write_env(&self, n: u32) -> bool {
+        self.may_yield || self.writes_envs.contains(n)
+    }
+
+    pub fn may_write_fields(&self) -> bool {
+        self.may_yield || self.writes_fields
+    }
+
+    /// Whether the code reads or writes anything outside its frame or lets
+    /// other threads run.
+    pub fn observes_side_effects(&self) -> bool {
+        !self.reads_statics.is_empty()
+            || !self.writes_statics.is_empty()
+            || !self.reads_globals.is_empty()
+            || !self.reads_envs.is_empty()
+            || !self.writes_envs.is_empty()
+            || self.reads_fields
+            || self.writes_fields
+            || self.may_yield
+    }
+
+    /// Whether the code only computes a result: removing, repeating or
+    /// reordering it cannot be observed, except for traps.
+    pub fn is_pure(&self) -> bool {
+        !self.observes_side_effects() && !self.may_not_terminate
+    }
+}
+
+/// Effect summaries of every function of a module, computed bottom-up over
+/// the call graph. Functions in a recursive cycle share one summary and may
+/// not terminate, as may functions with loops.
+#[derive(Clone, Default)]
+pub struct EffectAnalysis {
+    pub summaries: Vec<Effects>,
+}
+
+impl EffectAnalysis {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn analyze(&mut self, module: &Module, cg: &CallGraph) {
+        self.summaries = vec![Effects::default(); module.functions.len()];
+        for scc in cg.sccs.iter() {
+            let mut effects = Effects::default();
+            for f in scc.iter() {
+                let function = &module.functions[*f as usize];
+                for block in function.cfg.blocks.iter() {
+                    for ins in block.borrow().instructions.iter() {
+                        match ins {
+                            // calls within the component are accounted for by
+                            // summarizing all of it at once
+                            Instruction::Call(n) | Instruction::TailCall(n)
+                                if scc.contains(n) =>
+                            {
+                                effects.may_not_terminate = true
+                            }
+                            _ => effects.union(&Effects::of(ins, Some(self))),
+                        }
+                    }
+                }
+                let mut ca = CycleAnalysis::new();
+                ca.analyze(&function.cfg);
+                effects.may_not_terminate |= !ca.all_back_edges().is_empty();
+            }
+            for f in scc.iter() {
+                self.summaries[*f as usize] = effects.clone();
+            }
+        }
+    }
+
+    pub fn summary(&self, function: u32) -> Option<&Effects> {
+        self.summaries.get(function as usize)
+    }
+
+    /// Effects of calling `function`, as seen by the caller.
+    pub fn call(&self, function: u32) -> Effects {
+        let mut effects = match self.summary(function) {
+            Some(effects) => effects.clone(),
+            None => return Effects::unknown(),
+        };
+        if !effects.reads_envs.is_empty() {
+            effects.reads_envs = Locations::everything();
+        }
+        if !effects.writes_envs.is_empty() {
+            effects.writes_envs = Locations::everything();
+        }
+        effects
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::saferegion::*;
+    use crate::block::{CodeBlock, CodeBlockRef, EdgeType::*};
+    use crate::cfg::ControlFlowGraph;
+    use crate::opt::gvn::GlobalValueNumbering;
+    use Instruction::*;
+
+    fn straight_line(instructions: Vec<Instruction>) -> Function {
+        let cfg = ControlFlowGraph::from_blocks(
+            vec![instructions],
+            &[(0, 2, FallThrough), (2, 1, FallThrough)],
+        );
+        Function::new("f", 0, cfg)
+    }
+
+    // 0 is pure, 1 writes static 0, 2 calls 1, 3 calls itself, 4 loops and
+    // 5 writes its environment slot 0.
+    fn module() -> Module {
+        let mut module = Module::new();
+        module.add_function(straight_line(vec![LdInt(1), LdInt(2), Add]));
+        module.add_function(straight_line(vec![LdInt(1), StStatic(0)]));
+        module.add_function(straight_line(vec![Call(1)]));
+        module.add_function(straight_line(vec![Call(3)]));
+        let cfg = ControlFlowGraph::from_blocks(
+            vec![vec![Jmp(2)]],
+            &[(0, 2, FallThrough), (2, 2, Branch)],
+        );
+        module.add_function(Function::new("spin", 0, cfg));
+        let mut function = straight_line(vec![LdInt(1), StEnv(0)]);
+        function.env = vec!["x".to_owned()];
+        module.add_function(function);
+        module.add_static("s", Literal::Nil);
+        module.add_static("t", Literal::Nil);
+        module
+    }
+
+    fn analyze(module: &Module) -> EffectAnalysis {
+        let mut cg = CallGraph::new();
+        cg.analyze(module);
+        let mut ea = EffectAnalysis::new();
+        ea.analyze(module, &cg);
+        ea
+    }
+
+    #[test]
+    fn summarizes_callees_bottom_up() {
+        let ea = analyze(&module());
+        let summary = |n| ea.summary(n).unwrap();
+
+        assert!(summary(0).is_pure());
+        assert!(summary(1).writes_statics.contains(0));
+        assert!(!summary(1).writes_statics.contains(1));
+        assert!(!summary(1).may_not_terminate);
+        assert_eq!(summary(2), summary(1));
+        assert!(summary(3).may_not_terminate);
+        assert!(!summary(3).observes_side_effects());
+        assert!(summary(4).may_not_terminate);
+
+        assert!(summary(5).writes_envs.contains(0));
+        assert!(!summary(5).writes_envs.contains(1));
+        assert_eq!(ea.call(5).writes_envs, Locations::everything());
+        assert_eq!(ea.call(9), Effects::unknown());
+    }
+
+    #[test]
+    fn safe_regions_ignore_calls_without_side_effects() {
+        let ea = analyze(&module());
+        let block = |instructions| {
+            CodeBlockRef::new(CodeBlock {
+                instructions,
+                ..Default::default()
+            })
+        };
+        let blocks = vec![block(vec![Call(0)]), block(vec![Call(2)])];
+
+        let set: Vec<_> =
+            get_blocks_with_calls_to_functions_that_observe_side_effects(&blocks, &ea)
+                .into_iter()
+                .collect();
+        assert_eq!(set, vec![blocks[1].clone()]);
+    }
+
+    #[test]
+    fn pure_call_does_not_kill_static_load() {
+        let ea = analyze(&module());
+        let code = |callee| {
+            vec![
+                LdStatic(0),
+                StStatic(1),
+                Call(callee),
+                Pop(1),
+                LdStatic(0),
+                StStatic(1),
+            ]
+        };
+
+        let mut function = straight_line(code(0));
+        assert!(GlobalValueNumbering::with_effects(ea.clone()).run(&mut function));
+        assert_eq!(
+            function.cfg.find_block(2).unwrap().borrow().instructions,
+            vec![
+                LdStatic(0),
+                Dup,
+                StLocal(0),
+                StStatic(1),
+                Call(0),
+                Pop(1),
+                LdLocal(0),
+                StStatic(1),
+            ]
+        );
+
+        let mut function = straight_line(code(1));
+        assert!(!GlobalValueNumbering::with_effects(ea).run(&mut function));
+        let mut function = straight_line(code(0));
+        assert!(!GlobalValueNumbering::new().run(&mut function));
+    }
+
+    #[test]
+    fn closure_calls_have_unknown_effects() {
+        let mut module = module();
+        module.add_function(straight_line(vec![MkClosure(5), CallClosure(0)]));
+        let ea = analyze(&module);
+        assert_eq!(ea.summary(6), Some(&Effects::unknown()));
+
+        let blocks = vec![module.functions[6].cfg.find_block(2).unwrap()];
+        assert_eq!(
+            get_blocks_with_calls_to_functions_that_observe_side_effects(&blocks, &ea).len(),
+            1
+        );
+
+        let mut function = straight_line(vec![
+            LdStatic(0),
+            StStatic(1),
+            MkClosure(0),
+            CallClosure(0),
+            Pop(1),
+            LdStatic(0),
+            StStatic(1),
+        ]);
+        assert!(!GlobalValueNumbering::with_effects(ea).run(&mut function));
+    }
+}
@@ -72,12 +72,19 @@ impl HammockAnalysis {
         if dominator == *entry || dominator == parent_exit {
             return false;
         }
+        // a block that may throw to its handler has two ways out like a
+        // branch, and the handler rejoins the normal path no earlier than at
+        // the post dominator, which follows exception edges as well
         while dominator.borrow().successors.len() < 2 {
             dominator = dt.get_dominator(dominator).unwrap();
             if dominator == parent_entry || dominator == parent_exit {
                 return false;
             }
         }
+        // blocks that never reach the exit have no post dominator
+        if pdt.get_index(&dominator).is_none() {
+            return false;
+        }
         let post_dominator = pdt.get_post_dominator(dominator.clone());
         if !pdt.dominates(post_dominator.clone(), exit.clone(), cfg) {
             return false;
@@ -152,7 +159,6 @@ impl HammockAnalysis {
             unvisited_iter.push(v.1.clone());
         }
         for v in unvisited_iter.iter() {
-            let mut v: Rc<RefCell<Hammock>> = v.clone();
             if v.borrow().entry == entry {
                 continue;
             }
@@ -167,21 +173,15 @@ impl HammockAnalysis {
                 continue;
             }
 
-            v.borrow_mut().parent = Some(new_hammock.clone());
-
-            for x in new_hammock
-                .borrow()
-                .parent
-                .as_ref()
-                .unwrap()
-                .borrow()
-                .children
-                .iter()
-            {
-                if unvisited_iter.contains(x) {
-                    new_hammock.borrow_mut().children.push(x.clone());
-                }
+            // move the enclosed hammock from its old parent to the new one
+            let parent = v.borrow_mut().parent.replace(new_hammock.clone());
+            if let Some(parent) = parent {
+                parent
+                    .borrow_mut()
+                    .children
+                    .retain(|child| !Rc::ptr_eq(child, v));
             }
+            new_hammock.borrow_mut().children.push(v.clone());
             unvisited.remove(&v.borrow().entry);
         }
 
@@ -217,3 +217,80 @@ impl HammockAnalysis {
         }
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::EdgeType::*;
+    use crate::instructions::Instruction::*;
+
+    // (depth, entry, exit) of every hammock, parents first
+    fn hammocks(cfg: &ControlFlowGraph) -> Vec<(usize, usize, usize)> {
+        fn walk(
+            hammock: &Rc<RefCell<Hammock>>,
+            depth: usize,
+            out: &mut Vec<(usize, usize, usize)>,
+        ) {
+            let hammock = hammock.borrow();
+            out.push((depth, hammock.entry.borrow().id, hammock.exit.borrow().id));
+            for child in hammock.children.iter() {
+                walk(child, depth + 1, out);
+            }
+        }
+        let mut dt = DominatorTree::new();
+        dt.analyze(cfg);
+        let mut pdt = PostDominatorTree::new();
+        pdt.analyze(cfg);
+        let mut analysis = HammockAnalysis {
+            root: Default::default(),
+            map: Default::default(),
+        };
+        analysis.analyze(cfg, &dt, &pdt);
+        let mut out = vec![];
+        walk(&analysis.root, 0, &mut out);
+        out
+    }
+
+    #[test]
+    fn branch_starts_hammock() {
+        // 2: if a { 3: x = 0 } 4: return x
+        let cfg = ControlFlowGraph::from_blocks(
+            vec![
+                vec![LdLocal(0), JmpZ(4)],
+                vec![LdInt(0), StLocal(1)],
+                vec![LdLocal(1), Ret],
+            ],
+            &[
+                (0, 2, FallThrough),
+                (2, 4, Branch),
+                (2, 3, FallThrough),
+                (3, 4, FallThrough),
+                (4, 1, Return),
+            ],
+        );
+        let expected = vec![(0, 0, 1), (1, 2, 4), (2, 3, 3), (1, 4, 4)];
+        assert_eq!(hammocks(&cfg), expected);
+    }
+
+    #[test]
+    fn protected_block_starts_hammock() {
+        // 2: try { x = 1 / a } 3: catch { x = 0 } 4: return x
+        let cfg = ControlFlowGraph::from_blocks(
+            vec![
+                vec![LdInt(1), LdLocal(0), Div, StLocal(1), Jmp(4)],
+                vec![Pop(1), LdInt(0), StLocal(1)],
+                vec![LdLocal(1), Ret],
+            ],
+            &[
+                (0, 2, FallThrough),
+                (2, 4, Branch),
+                (2, 3, Exception),
+                (3, 4, FallThrough),
+                (4, 1, Return),
+            ],
+        );
+        let expected = vec![(0, 0, 1), (1, 2, 4), (2, 3, 3), (1, 4, 4)];
+        assert_eq!(hammocks(&cfg), expected);
+    }
+}
@@ -0,0 +1,359 @@
+use crate::block::*;
+use crate::cfg::*;
+use crate::instructions::Instruction;
+
+use std::collections::{BTreeSet, HashMap};
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum Slot {
+    Local(u32),
+    Env(u32),
+}
+
+pub type SlotSet = BTreeSet<Slot>;
+
+#[derive(Default, Debug)]
+pub struct BlockLiveness {
+    pub live_in: SlotSet,
+    pub live_out: SlotSet,
+    /// `live_before[i]` holds the slots live right before instruction `i`.
+    pub live_before: Vec<SlotSet>,
+}
+
+/// Backward may-liveness of local and environment slots.
+///
+/// Environment slots outlive the frame and can be read by callees and other
+/// threads, so they are treated as used at calls, yield points and exits.
+/// What a handler reads is live before every instruction that may throw to
+/// it.
+#[derive(Default, Debug)]
+pub struct LivenessAnalysis {
+    pub blocks: HashMap<usize, BlockLiveness>,
+    pub env_slots: SlotSet,
+}
+
+impl LivenessAnalysis {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn analyze(&mut self, cfg: &ControlFlowGraph) {
+        self.blocks.clear();
+        self.env_slots.clear();
+        for block in cfg.blocks.iter() {
+            for ins in block.borrow().instructions.iter() {
+                match ins {
+                    Instruction::LdEnv(n) | Instruction::StEnv(n) => {
+                        self.env_slots.insert(Slot::Env(*n));
+                    }
+                    _ => (),
+                }
+            }
+            self.blocks
+                .insert(block.borrow().id, BlockLiveness::default());
+        }
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for block in cfg.blocks.iter().rev() {
+                let block = block.borrow();
+                let mut live = if block.successors.is_empty() {
+                    self.env_slots.clone()
+                } else {
+                    let mut live = SlotSet::new();
+                    for edge in block.out_edges.iter() {
+                        let edge = edge.borrow();
+                        if edge.ty == EdgeType::Exception {
+                            continue;
+                        }
+                        let succ = edge.tail.as_ref().unwrap().borrow().id;
+                        if let Some(info) = self.blocks.get(&succ) {
+                            live.extend(info.live_in.iter().copied());
+                        }
+                    }
+                    live
+                };
+                // the handler can be entered from any instruction that throws
+                let handler = block
+                    .handler
+                    .and_then(|handler| self.blocks.get(&handler))
+                    .map(|info| info.live_in.clone())
+                    .unwrap_or_default();
+                let live_out = live.clone();
+                let mut live_before = vec![SlotSet::new(); block.instructions.len()];
+                for (i, ins) in block.instructions.iter().enumerate().rev() {
+                    self.transfer(ins, &mut live);
+                    if ins.may_throw() {
+                        live.extend(handler.iter().copied());
+                    }
+                    live_before[i] = live.clone();
+                }
+
+                let info = self.blocks.get_mut(&block.id).unwrap();
+                if info.live_in != live || info.live_out != live_out {
+                    changed = true;
+                }
+                info.live_in = live;
+                info.live_out = live_out;
+                info.live_before = live_before;
+            }
+        }
+    }
+
+    fn transfer(&self, ins: &Instruction, live: &mut SlotSet) {
+        use Instruction::*;
+        match ins {
+            LdLocal(n) => {
+                live.insert(Slot::Local(*n));
+            }
+            StLocal(n) => {
+                live.remove(&Slot::Local(*n));
+            }
+            LdEnv(n) => {
+                live.insert(Slot::Env(*n));
+            }
+            StEnv(n) => {
+                live.remove(&Slot::Env(*n));
+            }
+            Call(_) | TailCall(_) | CallClosure(_) | ThreadYield => {
+                live.extend(self.env_slots.iter().copied());
+            }
+            _ => (),
+        }
+    }
+
+    pub fn live_in(&self, block: usize) -> Option<&SlotSet> {
+        self.blocks.get(&block).map(|info| &info.live_in)
+    }
+
+    pub fn live_out(&self, block: usize) -> Option<&SlotSet> {
+        self.blocks.get(&block).map(|info| &info.live_out)
+    }
+
+    pub fn live_before(&self, block: usize, index: usize) -> Option<&SlotSet> {
+        let info = self.blocks.get(&block)?;
+        if index == info.live_before.len() {
+            return Some(&info.live_out);
+        }
+        info.live_before.get(index)
+    }
+
+    pub fn live_after(&self, block: usize, index: usize) -> Option<&SlotSet> {
+        self.live_before(block, index + 1)
+    }
+
+    pub fn is_live_after(&self, block: usize, index: usize, slot: Slot) -> bool {
+        self.live_after(block, index)
+            .map(|live| live.contains(&slot))
+            .unwrap_or(true)
+    }
+
+    /// Assigns every local a new slot so that locals which are never live at
+    /// the same time share one. Slots below `reserved` (parameters) keep their
+    /// numbers.
+    pub fn local_slot_mapping(&self, cfg: &ControlFlowGraph, reserved: u32) -> HashMap<u32, u32> {
+        let mut interference: HashMap<u32, BTreeSet<u32>> = HashMap::new();
+
+        for block in cfg.blocks.iter() {
+            let block = block.borrow();
+            let info = &self.blocks[&block.id];
+            for (i, ins) in block.instructions.iter().enumerate() {
+                let live = self.live_after(block.id, i).unwrap();
+                let locals = live.iter().filter_map(|slot| match slot {
+                    Slot::Local(n) => Some(*n),
+                    _ => None,
+                });
+                match ins {
+                    Instruction::StLocal(n) => {
+                        interference.entry(*n).or_default();
+                        for other in locals.clone() {
+                            interfere(&mut interference, *n, other);
+                        }
+                    }
+                    Instruction::LdLocal(n) => {
+                        interference.entry(*n).or_default();
+                    }
+                    _ => (),
+                }
+                let locals = locals.collect::<Vec<_>>();
+                for (j, a) in locals.iter().enumerate() {
+                    for b in locals[j + 1..].iter() {
+                        interfere(&mut interference, *a, *b);
+                    }
+                }
+            }
+            for slot in info.live_in.iter() {
+                if let Slot::Local(n) = slot {
+                    interference.entry(*n).or_default();
+                    for other in info.live_in.iter() {
+                        if let Slot::Local(m) = other {
+                            interfere(&mut interference, *n, *m);
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut mapping = HashMap::new();
+        for n in 0..reserved {
+            mapping.insert(n, n);
+        }
+        let mut locals = interference.keys().copied().collect::<Vec<_>>();
+        locals.sort_unstable();
+        for local in locals {
+            if local < reserved {
+                continue;
+            }
+            let taken = interference[&local]
+                .iter()
+                .filter_map(|other| mapping.get(other).copied())
+                .collect::<BTreeSet<_>>();
+            let mut slot = reserved;
+            while taken.contains(&slot) {
+                slot += 1;
+            }
+            mapping.insert(local, slot);
+        }
+        mapping
+    }
+}
+
+fn interfere(set: &mut HashMap<u32, BTreeSet<u32>>, a: u32, b: u32) {
+    if a != b {
+        set.entry(a).or_default().insert(b);
+        set.entry(b).or_default().insert(a);
+    }
+}
+
+pub fn remap_locals(cfg: &mut ControlFlowGraph, mapping: &HashMap<u32, u32>) {
+    for block in cfg.blocks.iter_mut() {
+        for ins in block.borrow_mut().instructions.iter_mut() {
+            match ins {
+                Instruction::LdLocal(n) | Instruction::StLocal(n) => {
+                    if let Some(slot) = mapping.get(n) {
+                        *n = *slot;
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::EdgeType::*;
+    use Instruction::*;
+
+    fn slots(slots: &[Slot]) -> SlotSet {
+        slots.iter().copied().collect()
+    }
+
+    #[test]
+    fn local_is_live_from_store_to_last_load() {
+        let cfg = ControlFlowGraph::from_blocks(
+            vec![
+                vec![LdInt(1), StLocal(0), LdInt(2), StLocal(1)],
+                vec![LdLocal(0), Pop(1)],
+            ],
+            &[
+                (0, 2, FallThrough),
+                (2, 3, FallThrough),
+                (3, 1, FallThrough),
+            ],
+        );
+        let mut liveness = LivenessAnalysis::new();
+        liveness.analyze(&cfg);
+
+        assert_eq!(liveness.live_in(2), Some(&slots(&[])));
+        assert_eq!(liveness.live_before(2, 1), Some(&slots(&[])));
+        assert_eq!(liveness.live_after(2, 1), Some(&slots(&[Slot::Local(0)])));
+        assert!(!liveness.is_live_after(2, 3, Slot::Local(1)));
+        assert_eq!(liveness.live_out(2), Some(&slots(&[Slot::Local(0)])));
+        assert_eq!(liveness.live_in(3), Some(&slots(&[Slot::Local(0)])));
+        assert_eq!(liveness.live_out(3), Some(&slots(&[])));
+    }
+
+    #[test]
+    fn local_stays_live_around_loop() {
+        let cfg = ControlFlowGraph::from_blocks(
+            vec![
+                vec![LdLocal(0), LdInt(1), Sub, StLocal(0), LdLocal(0), JmpNz(2)],
+                vec![],
+            ],
+            &[
+                (0, 2, FallThrough),
+                (2, 2, Branch),
+                (2, 3, FallThrough),
+                (3, 1, FallThrough),
+            ],
+        );
+        let mut liveness = LivenessAnalysis::new();
+        liveness.analyze(&cfg);
+
+        assert_eq!(liveness.live_in(2), Some(&slots(&[Slot::Local(0)])));
+        assert_eq!(liveness.live_out(2), Some(&slots(&[Slot::Local(0)])));
+        assert_eq!(liveness.live_after(2, 2), Some(&slots(&[])));
+        assert_eq!(liveness.live_in(0), Some(&slots(&[Slot::Local(0)])));
+    }
+
+    #[test]
+    fn env_slots_are_live_at_calls_and_exit() {
+        let cfg = ControlFlowGraph::from_blocks(
+            vec![vec![LdInt(1), StEnv(0), Call(0), LdInt(2), StEnv(0)]],
+            &[(0, 2, FallThrough), (2, 1, FallThrough)],
+        );
+        let mut liveness = LivenessAnalysis::new();
+        liveness.analyze(&cfg);
+
+        assert_eq!(liveness.env_slots, slots(&[Slot::Env(0)]));
+        assert!(liveness.is_live_after(2, 1, Slot::Env(0)));
+        assert!(!liveness.is_live_after(2, 3, Slot::Env(0)));
+        assert_eq!(liveness.live_out(2), Some(&slots(&[Slot::Env(0)])));
+    }
+
+    #[test]
+    fn locals_never_live_together_share_a_slot() {
+        let mut cfg = ControlFlowGraph::from_blocks(
+            vec![vec![
+                LdLocal(0),
+                StLocal(1),
+                LdLocal(1),
+                Pop(1),
+                LdInt(2),
+                StLocal(2),
+                LdLocal(2),
+                LdLocal(0),
+                Add,
+                Pop(1),
+            ]],
+            &[(0, 2, FallThrough), (2, 1, FallThrough)],
+        );
+        let mut liveness = LivenessAnalysis::new();
+        liveness.analyze(&cfg);
+        let mapping = liveness.local_slot_mapping(&cfg, 1);
+
+        assert_eq!(mapping[&0], 0);
+        assert_eq!(mapping[&1], 1);
+        assert_eq!(mapping[&2], 1);
+
+        remap_locals(&mut cfg, &mapping);
+        assert_eq!(
+            cfg.blocks[2].borrow().instructions,
+            vec![
+                LdLocal(0),
+                StLocal(1),
+                LdLocal(1),
+                Pop(1),
+                LdInt(2),
+                StLocal(1),
+                LdLocal(1),
+                LdLocal(0),
+                Add,
+                Pop(1),
+            ]
+        );
+    }
+}
@@ -1,8 +1,13 @@
+pub mod callgraph;
+pub mod capture;
 pub mod cycleanalysis;
 pub mod dom;
+pub mod effects;
 pub mod hammockgraph;
+pub mod liveness;
 pub mod postdom;
 pub mod saferegion;
+pub mod stackmap;
 
 pub struct Analysis<'a> {
     cfg: &'a crate::cfg::ControlFlowGraph,
@@ -45,13 +45,17 @@ impl PostDominatorTree {
     }
 
     pub fn analyze(&mut self, cfg: &ControlFlowGraph) {
-        let post_order = cfg.reverse_topological_sequence();
-        let mut i = 0;
-        for block in post_order.iter() {
+        self.blocks.clear();
+        self.p_dom.clear();
+        self.dominated.clear();
+        self.frontiers.clear();
+        self.blocks_to_index.clear();
+
+        // post order of the reversed graph: the exit gets the highest index
+        for (i, block) in cfg.post_order_to_exit().into_iter().enumerate() {
             self.blocks.push(block.clone());
-            self.blocks_to_index.insert(block.clone(), i);
+            self.blocks_to_index.insert(block, i);
             self.p_dom.push(-1);
-            i += 1;
         }
         self.compute_dt(cfg);
     }
@@ -77,16 +81,18 @@ impl PostDominatorTree {
 
         while changed {
             changed = false;
-            for b_ind in 0..self.blocks.len() {
+            for b_ind in (0..self.blocks.len()).rev() {
                 if b_ind == end_node {
                     continue;
                 }
                 let b: CodeBlockRef = self.blocks[b_ind].clone();
-                assert!(b.borrow().successors.is_empty());
                 let mut new_pdom = 0;
                 let mut processed = false;
                 for succ in b.borrow().successors.iter() {
-                    let p = *self.blocks_to_index.get(succ).unwrap();
+                    let p = match self.blocks_to_index.get(succ) {
+                        Some(p) => *p,
+                        None => continue,
+                    };
                     assert!(p < self.p_dom.len());
                     if self.p_dom[p] != -1 {
                         if !processed {
@@ -108,7 +114,7 @@ impl PostDominatorTree {
 
         self.dominated.resize(self.blocks.len(), vec![]);
         for n in 0..self.blocks.len() {
-            if self.p_dom[n] >= 0 {
+            if self.p_dom[n] >= 0 && n != end_node {
                 self.dominated[self.p_dom[n] as usize].push(n as _);
             }
         }
@@ -123,6 +129,9 @@ impl PostDominatorTree {
                 std::collections::HashSet::new();
 
             for successor in block.borrow().successors.iter() {
+                if !self.blocks_to_index.contains_key(successor) {
+                    continue;
+                }
                 let mut runner: CodeBlockRef = successor.clone();
 
                 while runner != self.get_post_dominator(block.clone()) {
@@ -138,6 +147,10 @@ impl PostDominatorTree {
         }
     }
 
+    pub fn get_index(&self, block: &CodeBlockRef) -> Option<usize> {
+        self.blocks_to_index.get(block).copied()
+    }
+
     pub fn get_post_dominator(&self, block: CodeBlockRef) -> CodeBlockRef {
         let n = *self.blocks_to_index.get(&block).unwrap();
         self.blocks[self.p_dom[n] as usize].clone()
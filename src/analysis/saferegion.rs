@@ -1,4 +1,5 @@
 use super::cycleanalysis::*;
+use super::effects::*;
 use crate::block::*;
 use crate::cfg::*;
 use std::cell::RefCell;
@@ -44,7 +45,7 @@ pub fn get_branch(block: &CodeBlockRef) -> Option<Instruction> {
     }
     let branch = block.borrow().instructions.last().unwrap().clone();
     match branch {
-        Instruction::Jmp(_) | Instruction::JmpNz(_) | Instruction::JmpZ(_) => {
+        Instruction::Jmp(_) | Instruction::JmpNz(_) | Instruction::JmpZ(_) | Instruction::Switch => {
             return Some(branch.clone())
         }
         _ => return None,
@@ -67,12 +68,18 @@ pub fn get_block_that_can_observe_side_effects(blocks: &[CodeBlockRef]) -> Vec<I
 
 pub fn get_blocks_with_calls_to_functions_that_observe_side_effects(
     blocks: &[CodeBlockRef],
+    effects: &EffectAnalysis,
 ) -> BlockSet {
     let mut set = BlockSet::new();
     for block in blocks.iter() {
         for instruction in block.borrow().instructions.iter() {
             match instruction {
-                Instruction::Call(_) | Instruction::TailCall(_) => {
+                Instruction::Call(n) | Instruction::TailCall(n)
+                    if effects.call(*n).observes_side_effects() =>
+                {
+                    set.insert(block.clone());
+                }
+                Instruction::CallClosure(_) => {
                     set.insert(block.clone());
                 }
                 _ => (),
@@ -0,0 +1,383 @@
+use super::liveness::*;
+use crate::block::*;
+use crate::instructions::Instruction;
+use crate::module::*;
+use crate::verifier::{self, VerifyError};
+
+use std::collections::{BTreeMap, HashMap};
+
+/// What a stack slot or local may hold.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Kind {
+    /// Nil, an integer or a float.
+    Scalar,
+    /// Possibly a reference to a heap cell.
+    Reference,
+}
+
+/// The slots of a frame that may hold references at a safe point.
+#[derive(Clone, PartialEq, Eq, Default, Debug)]
+pub struct StackMap {
+    /// Locals that may hold a reference and are live across the safe point.
+    pub locals: Vec<u32>,
+    /// Operand stack positions, counted from the bottom, that may hold a
+    /// reference. At a call the arguments are not included, they belong to
+    /// the callee's frame.
+    pub stack: Vec<u32>,
+}
+
+/// Stack maps of a function keyed by block id and instruction index.
+pub type StackMaps = BTreeMap<(usize, usize), StackMap>;
+
+#[derive(Clone, PartialEq, Default, Debug)]
+struct State {
+    stack: Vec<Kind>,
+    locals: Vec<Kind>,
+}
+
+impl State {
+    fn join(&mut self, other: &State) -> bool {
+        let mut changed = false;
+        let slots = self.stack.iter_mut().zip(other.stack.iter());
+        for (kind, other) in slots.chain(self.locals.iter_mut().zip(other.locals.iter())) {
+            if *other > *kind {
+                *kind = *other;
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    fn pop(&mut self) -> Kind {
+        self.stack.pop().unwrap_or(Kind::Reference)
+    }
+}
+
+/// Computes a stack map for every `ThreadYield`, `Call` and `CallClosure` of
+/// a function.
+///
+/// A forward analysis tracks which operand stack slots and locals may hold
+/// references; locals are further restricted to the ones that are live across
+/// the safe point. The frame's environment is always a root and does not
+/// appear in the maps.
+#[derive(Default, Debug)]
+pub struct StackMapAnalysis {
+    pub maps: StackMaps,
+}
+
+impl StackMapAnalysis {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Analyzes function `n`, which has to pass verification.
+    pub fn analyze(&mut self, module: &Module, n: u32) -> Result<(), VerifyError> {
+        self.maps.clear();
+        verifier::verify_function(module, n)?;
+        let function = &module.functions[n as usize];
+        let cfg = &function.cfg;
+        let mut liveness = LivenessAnalysis::new();
+        liveness.analyze(cfg);
+
+        let mut entry = State {
+            stack: vec![],
+            locals: vec![Kind::Scalar; function.locals as usize],
+        };
+        for kind in entry.locals.iter_mut().take(function.params as usize) {
+            *kind = Kind::Reference;
+        }
+        let mut states: HashMap<usize, State> = HashMap::new();
+        states.insert(cfg.entry.borrow().id, entry);
+        let mut worklist = vec![cfg.get_entry_block()];
+        while let Some(block) = worklist.pop() {
+            let block = block.borrow();
+            let mut state = states[&block.id].clone();
+            // what the handler sees: the exception and the locals at any
+            // instruction that may throw
+            let mut thrown: Option<State> = None;
+            for ins in block.instructions.iter() {
+                if ins.may_throw() {
+                    let at = State {
+                        stack: vec![Kind::Reference],
+                        locals: state.locals.clone(),
+                    };
+                    match thrown.as_mut() {
+                        Some(thrown) => {
+                            thrown.join(&at);
+                        }
+                        None => thrown = Some(at),
+                    }
+                }
+                transfer(module, ins, &mut state);
+            }
+
+            for edge in block.out_edges.iter() {
+                let edge = edge.borrow();
+                let out = match edge.ty {
+                    EdgeType::Return => continue,
+                    EdgeType::Exception => match thrown.as_ref() {
+                        Some(thrown) => thrown,
+                        None => continue,
+                    },
+                    _ => &state,
+                };
+                let succ = edge.tail.as_ref().unwrap();
+                let id = succ.borrow().id;
+                let changed = match states.get_mut(&id) {
+                    Some(existing) => existing.join(out),
+                    None => {
+                        states.insert(id, out.clone());
+                        true
+                    }
+                };
+                if changed {
+                    worklist.push(succ.clone());
+                }
+            }
+        }
+
+        for block in cfg.blocks.iter() {
+            let block = block.borrow();
+            let mut state = match states.get(&block.id) {
+                Some(state) => state.clone(),
+                None => continue,
+            };
+            for (i, ins) in block.instructions.iter().enumerate() {
+                let args = match ins {
+                    Instruction::ThreadYield => Some(0),
+                    Instruction::Call(_) | Instruction::CallClosure(_) => {
+                        module.stack_effect(ins).map(|(pops, _)| pops)
+                    }
+                    _ => None,
+                };
+                if let Some(args) = args {
+                    let height = state.stack.len().saturating_sub(args as usize);
+                    // neither reads nor writes locals, so this is also what
+                    // a handler entered from a throwing call needs
+                    let live = liveness.live_before(block.id, i);
+                    let locals = state.locals.iter().enumerate().filter(|(n, kind)| {
+                        let slot = Slot::Local(*n as u32);
+                        **kind == Kind::Reference && live.is_some_and(|live| live.contains(&slot))
+                    });
+                    let stack = state.stack[..height].iter().enumerate();
+                    self.maps.insert(
+                        (block.id, i),
+                        StackMap {
+                            locals: locals.map(|(n, _)| n as u32).collect(),
+                            stack: stack
+                                .filter(|(_, kind)| **kind == Kind::Reference)
+                                .map(|(n, _)| n as u32)
+                                .collect(),
+                        },
+                    );
+                }
+                transfer(module, ins, &mut state);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, block: usize, index: usize) -> Option<&StackMap> {
+        self.maps.get(&(block, index))
+    }
+}
+
+// Slots that do not exist, which verified code never touches, may hold
+// anything.
+fn transfer(module: &Module, ins: &Instruction, state: &mut State) {
+    use Instruction::*;
+    match ins {
+        LdLocal(n) => {
+            let kind = state.locals.get(*n as usize).copied();
+            state.stack.push(kind.unwrap_or(Kind::Reference));
+        }
+        StLocal(n) => {
+            let kind = state.pop();
+            if let Some(local) = state.locals.get_mut(*n as usize) {
+                *local = kind;
+            }
+        }
+        Dup => {
+            let kind = state.pop();
+            state.stack.extend_from_slice(&[kind, kind]);
+        }
+        LdGlobal(_) | LdStatic(_) | LdEnv(_) | LdField | LdFieldAt(_) | NewObject
+        | MkClosure(_) | Call(_) | CallClosure(_) => {
+            let (pops, _) = module.stack_effect(ins).unwrap_or_default();
+            for _ in 0..pops {
+                state.pop();
+            }
+            state.stack.push(Kind::Reference);
+        }
+        // everything else produces numbers
+        _ => {
+            let (pops, pushes) = module.stack_effect(ins).unwrap_or_default();
+            for _ in 0..pops {
+                state.pop();
+            }
+            for _ in 0..pushes {
+                state.stack.push(Kind::Scalar);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::{self, checksum, DecodeError, HEADER_SIZE};
+    use crate::cfg::ControlFlowGraph;
+    use crate::verifier::ErrorKind;
+    use EdgeType::*;
+    use Instruction::{Call, JmpZ, LdInt, LdLocal, NewObject, Pop, StLocal, ThreadYield};
+
+    fn module(params: u32, locals: u32, cfg: ControlFlowGraph) -> Module {
+        let mut function = Function::new("f", params, cfg);
+        function.locals = locals;
+        let mut module = Module::new();
+        module.add_function(function);
+        module
+    }
+
+    // f(a): b = {}; c = 1; yield; f(c); return b
+    fn straight_line() -> Module {
+        let code = vec![
+            NewObject,
+            StLocal(1),
+            LdInt(1),
+            StLocal(2),
+            LdLocal(1),
+            LdInt(2),
+            ThreadYield,
+            Pop(2),
+            LdLocal(0),
+            LdLocal(2),
+            Call(0),
+            LdLocal(1),
+            Pop(2),
+        ];
+        let cfg =
+            ControlFlowGraph::from_blocks(vec![code], &[(0, 2, FallThrough), (2, 1, FallThrough)]);
+        module(1, 3, cfg)
+    }
+
+    fn maps(module: &Module) -> StackMaps {
+        let mut analysis = StackMapAnalysis::new();
+        analysis.analyze(module, 0).unwrap();
+        analysis.maps
+    }
+
+    // Stores the checksum of a modified section, so decoding gets past the
+    // header.
+    fn reseal(bytes: &mut [u8]) {
+        let checksum = checksum(&bytes[HEADER_SIZE..]).to_le_bytes();
+        bytes[HEADER_SIZE - 4..HEADER_SIZE].copy_from_slice(&checksum);
+    }
+
+    #[test]
+    fn maps_live_references_at_safe_points() {
+        let maps = maps(&straight_line());
+        assert_eq!(maps.len(), 2);
+        let at_yield = StackMap {
+            locals: vec![0, 1],
+            stack: vec![0],
+        };
+        assert_eq!(maps.get(&(2, 6)), Some(&at_yield));
+        // the argument belongs to the callee, `a` and `c` are dead
+        let at_call = StackMap {
+            locals: vec![1],
+            stack: vec![0],
+        };
+        assert_eq!(maps.get(&(2, 10)), Some(&at_call));
+    }
+
+    #[test]
+    fn local_holding_reference_on_one_path_is_mapped() {
+        // f(a): if a != 0 { b = x }; yield; return b
+        let diamond = |x| {
+            let cfg = ControlFlowGraph::from_blocks(
+                vec![
+                    vec![LdLocal(0), JmpZ(4)],
+                    vec![x, StLocal(1)],
+                    vec![ThreadYield, LdLocal(1)],
+                ],
+                &[
+                    (0, 2, FallThrough),
+                    (2, 4, Branch),
+                    (2, 3, FallThrough),
+                    (3, 4, FallThrough),
+                    (4, 1, FallThrough),
+                ],
+            );
+            maps(&module(1, 2, cfg))[&(4, 0)].clone()
+        };
+        assert_eq!(diamond(NewObject).locals, vec![1]);
+        assert_eq!(diamond(LdInt(5)).locals, Vec::<u32>::new());
+    }
+
+    #[test]
+    fn rejects_function_that_does_not_verify() {
+        let cfg = ControlFlowGraph::from_blocks(
+            vec![vec![LdLocal(3), ThreadYield]],
+            &[(0, 2, FallThrough), (2, 1, FallThrough)],
+        );
+        let error = StackMapAnalysis::new().analyze(&module(0, 1, cfg), 0);
+        assert_eq!(error.unwrap_err().kind, ErrorKind::LocalOutOfRange(3));
+    }
+
+    #[test]
+    fn unknown_locals_may_hold_references() {
+        let module = straight_line();
+        let mut state = State::default();
+        for ins in [LdInt(1), StLocal(3), LdLocal(3)].iter() {
+            transfer(&module, ins, &mut state);
+        }
+        assert_eq!(state.stack, vec![Kind::Reference]);
+        assert!(state.locals.is_empty());
+    }
+
+    #[test]
+    fn round_trip() {
+        let maps = vec![maps(&straight_line()), StackMaps::new()];
+        let bytes = bytecode::encode_stack_maps(&maps);
+        assert_eq!(&bytes[..4], b"RTSM");
+        assert_eq!(bytecode::decode_stack_maps(&bytes), Ok(maps));
+    }
+
+    #[test]
+    fn truncated_section_is_rejected() {
+        let bytes = bytecode::encode_stack_maps(&[maps(&straight_line())]);
+        for len in 0..bytes.len() {
+            let mut truncated = bytes[..len].to_vec();
+            assert!(bytecode::decode_stack_maps(&truncated).is_err());
+            if len >= HEADER_SIZE {
+                reseal(&mut truncated);
+                let error = bytecode::decode_stack_maps(&truncated);
+                assert_eq!(error, Err(DecodeError::UnexpectedEnd), "{} bytes", len);
+            }
+        }
+    }
+
+    #[test]
+    fn malformed_section_is_rejected() {
+        let module = bytecode::encode(&straight_line());
+        let error = bytecode::decode_stack_maps(&module);
+        assert_eq!(error, Err(DecodeError::BadMagic));
+
+        let mut maps = StackMaps::new();
+        let unordered = StackMap {
+            locals: vec![2, 1],
+            stack: vec![],
+        };
+        maps.insert((2, 0), unordered);
+        let bytes = bytecode::encode_stack_maps(&[maps]);
+        let error = bytecode::decode_stack_maps(&bytes);
+        assert_eq!(error, Err(DecodeError::InvalidStackMap));
+
+        let mut bytes = bytecode::encode_stack_maps(&[]);
+        bytes.push(0);
+        reseal(&mut bytes);
+        let error = bytecode::decode_stack_maps(&bytes);
+        assert_eq!(error, Err(DecodeError::TrailingBytes));
+    }
+}
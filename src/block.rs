@@ -1,4 +1,5 @@
 use std::cell::RefCell;
+use std::convert::TryFrom;
 use std::rc::Rc;
 
 use crate::instructions::Instruction;
@@ -7,6 +8,10 @@ use crate::instructions::Instruction;
 pub enum EdgeType {
     Branch,
     FallThrough,
+    /// From a block ending in a return, tail call or trap to the exit.
+    Return,
+    /// From a block that may throw to its handler.
+    Exception,
     Dummy,
     Invalid,
 }
@@ -24,10 +29,69 @@ pub struct Edge {
     pub tail: Option<CodeBlockRef>,
 }
 
+/// Targets of the `Switch` ending a block, as block ids. Keys without a
+/// target fall through.
+#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+pub enum SwitchTable {
+    /// `targets[i]` is the target of the key `low + i`.
+    Dense { low: i64, targets: Vec<u32> },
+    /// Cases sorted by key, each key appearing once.
+    Sparse(Vec<(i64, u32)>),
+}
+
+impl SwitchTable {
+    pub fn target(&self, key: i64) -> Option<u32> {
+        match self {
+            SwitchTable::Dense { low, targets } => {
+                let index = key.checked_sub(*low)?;
+                targets.get(usize::try_from(index).ok()?).copied()
+            }
+            SwitchTable::Sparse(cases) => cases
+                .binary_search_by_key(&key, |(case, _)| *case)
+                .ok()
+                .map(|i| cases[i].1),
+        }
+    }
+
+    /// The distinct targets in ascending order.
+    pub fn targets(&self) -> Vec<u32> {
+        let mut targets = match self {
+            SwitchTable::Dense { targets, .. } => targets.clone(),
+            SwitchTable::Sparse(cases) => cases.iter().map(|(_, target)| *target).collect(),
+        };
+        targets.sort_unstable();
+        targets.dedup();
+        targets
+    }
+
+    pub fn targets_mut(&mut self) -> Vec<&mut u32> {
+        match self {
+            SwitchTable::Dense { targets, .. } => targets.iter_mut().collect(),
+            SwitchTable::Sparse(cases) => cases.iter_mut().map(|(_, target)| target).collect(),
+        }
+    }
+
+    /// Whether every key maps to one target: sparse cases are strictly
+    /// ascending and a dense table does not run past `i64::MAX`.
+    pub fn is_well_formed(&self) -> bool {
+        match self {
+            SwitchTable::Dense { low, targets } => {
+                targets.is_empty() || low.checked_add(targets.len() as i64 - 1).is_some()
+            }
+            SwitchTable::Sparse(cases) => cases.windows(2).all(|pair| pair[0].0 < pair[1].0),
+        }
+    }
+}
+
 #[derive(PartialEq, Eq, Default, Debug)]
 pub struct CodeBlock {
     pub children: Vec<CodeBlockRef>,
     pub instructions: Vec<Instruction>,
+    /// Present when the block ends in `Switch`.
+    pub switch: Option<SwitchTable>,
+    /// Block that catches what the instructions of this block throw. The
+    /// blocks sharing a handler make up the range it protects.
+    pub handler: Option<usize>,
     pub id: usize,
     pub in_edges: Vec<Rc<RefCell<Edge>>>,
     pub out_edges: Vec<Rc<RefCell<Edge>>>,
@@ -51,6 +115,34 @@ impl CodeBlock {
     pub fn is_leaf(&self) -> bool {
         self.children.len() == 0
     }
+
+    pub fn may_throw(&self) -> bool {
+        self.instructions.iter().any(|ins| ins.may_throw())
+    }
+
+    pub fn retarget_branch(&mut self, from: usize, to: usize) {
+        self.retarget_branches(&std::collections::HashMap::from([(from, to)]));
+    }
+
+    /// Retargets the final branch or switch from every key of `map` to its
+    /// value at once.
+    pub fn retarget_branches(&mut self, map: &std::collections::HashMap<usize, usize>) {
+        let mut targets = match self.instructions.last_mut() {
+            Some(Instruction::Jmp(target))
+            | Some(Instruction::JmpZ(target))
+            | Some(Instruction::JmpNz(target)) => vec![target],
+            Some(Instruction::Switch) => match self.switch.as_mut() {
+                Some(table) => table.targets_mut(),
+                None => vec![],
+            },
+            _ => vec![],
+        };
+        for target in targets.iter_mut() {
+            if let Some(to) = map.get(&(**target as usize)) {
+                **target = *to as u32;
+            }
+        }
+    }
 }
 
 #[derive(PartialEq, Eq, Clone, Debug)]
@@ -82,6 +174,94 @@ impl CodeBlockRef {
     pub fn borrow_mut(&mut self) -> std::cell::RefMut<'_, CodeBlock> {
         self.val.borrow_mut()
     }
+    pub fn ptr_eq(&self, other: &CodeBlockRef) -> bool {
+        Rc::ptr_eq(&self.val, &other.val)
+    }
 }
 
 pub type BlockSet = std::collections::HashSet<CodeBlockRef>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cfg::ControlFlowGraph;
+    use std::collections::HashMap;
+
+    #[test]
+    fn tables_map_keys_to_targets() {
+        let dense = SwitchTable::Dense {
+            low: -2,
+            targets: vec![3, 4, 3],
+        };
+        assert_eq!(dense.target(-2), Some(3));
+        assert_eq!(dense.target(-1), Some(4));
+        assert_eq!(dense.target(0), Some(3));
+        assert_eq!(dense.target(1), None);
+        assert_eq!(dense.target(-3), None);
+        assert_eq!(dense.target(i64::MIN), None);
+        assert_eq!(dense.target(i64::MAX), None);
+        assert_eq!(dense.targets(), vec![3, 4]);
+
+        let sparse = SwitchTable::Sparse(vec![(-5, 4), (7, 2), (1000, 4)]);
+        assert_eq!(sparse.target(7), Some(2));
+        assert_eq!(sparse.target(1000), Some(4));
+        assert_eq!(sparse.target(8), None);
+        assert_eq!(sparse.targets(), vec![2, 4]);
+    }
+
+    #[test]
+    fn malformed_tables_are_detected() {
+        assert!(SwitchTable::Sparse(vec![(1, 2), (3, 2)]).is_well_formed());
+        assert!(!SwitchTable::Sparse(vec![(1, 2), (1, 3)]).is_well_formed());
+        assert!(!SwitchTable::Sparse(vec![(3, 2), (1, 3)]).is_well_formed());
+        let dense = |low, targets| SwitchTable::Dense { low, targets };
+        assert!(dense(i64::MAX, vec![2]).is_well_formed());
+        assert!(!dense(i64::MAX, vec![2, 3]).is_well_formed());
+        assert!(dense(i64::MAX, vec![]).is_well_formed());
+    }
+
+    #[test]
+    fn switch_links_each_target_once() {
+        let mut cfg = ControlFlowGraph::new();
+        let mut block = |instructions, switch| {
+            let id = cfg.new_id();
+            cfg.insert_block(CodeBlockRef::new(CodeBlock {
+                id,
+                instructions,
+                switch,
+                ..Default::default()
+            }))
+        };
+        let table = SwitchTable::Dense {
+            low: 0,
+            targets: vec![3, 4, 3],
+        };
+        let switch = block(
+            vec![Instruction::LdInt(1), Instruction::Switch],
+            Some(table),
+        );
+        block(vec![], None);
+        block(vec![], None);
+        block(vec![], None);
+        let fallthrough = HashMap::from([(0, 2), (2, 5), (3, 1), (4, 1), (5, 1)]);
+        cfg.link_blocks(&fallthrough).unwrap();
+
+        let mut edges = switch
+            .borrow()
+            .out_edges
+            .iter()
+            .map(|edge| {
+                let edge = edge.borrow();
+                let id = edge.tail.as_ref().unwrap().borrow().id;
+                (id, edge.ty)
+            })
+            .collect::<Vec<_>>();
+        edges.sort_by_key(|edge| edge.0);
+        let expected = vec![
+            (3, EdgeType::Branch),
+            (4, EdgeType::Branch),
+            (5, EdgeType::FallThrough),
+        ];
+        assert_eq!(edges, expected);
+    }
+}
@@ -0,0 +1,632 @@
+use super::*;
+use crate::analysis::stackmap::*;
+use crate::block::*;
+use crate::cfg::ControlFlowGraph;
+use crate::instructions::Instruction;
+use crate::module::*;
+
+use std::collections::HashMap;
+
+pub(crate) struct Reader<'a> {
+    pub data: &'a [u8],
+    pub pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos == self.data.len()
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        if self.remaining() < len {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, DecodeError> {
+        let mut buf = [0; 2];
+        buf.copy_from_slice(self.bytes(2)?);
+        Ok(u16::from_le_bytes(buf))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, DecodeError> {
+        let mut buf = [0; 4];
+        buf.copy_from_slice(self.bytes(4)?);
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, DecodeError> {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    pub fn varint(&mut self) -> Result<u64, DecodeError> {
+        let mut value = 0u64;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift == 63 && byte > 1 {
+                return Err(DecodeError::VarIntOverflow);
+            }
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+            shift += 7;
+            if shift > 63 {
+                return Err(DecodeError::VarIntOverflow);
+            }
+        }
+    }
+
+    pub fn u32_operand(&mut self) -> Result<u32, DecodeError> {
+        let value = self.varint()?;
+        if value > u32::MAX as u64 {
+            return Err(DecodeError::VarIntOverflow);
+        }
+        Ok(value as u32)
+    }
+
+    pub fn signed(&mut self) -> Result<i64, DecodeError> {
+        let value = self.varint()?;
+        Ok((value >> 1) as i64 ^ -((value & 1) as i64))
+    }
+
+    /// Reads an element count, rejecting counts that cannot possibly fit in
+    /// the remaining input so corrupt lengths don't cause huge allocations.
+    pub fn count(&mut self) -> Result<usize, DecodeError> {
+        let count = self.varint()?;
+        if count > self.remaining() as u64 {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+        Ok(count as usize)
+    }
+}
+
+pub(crate) fn decode_instruction(
+    input: &mut Reader<'_>,
+    constants: &[Constant],
+) -> Result<Instruction, DecodeError> {
+    use opcode::*;
+    use Instruction::*;
+    let op = input.u8()?;
+    let ins = match op {
+        LD_INT => LdInt(input.signed()?),
+        LD_FLOAT => {
+            let index = input.varint()?;
+            match constants.get(index as usize) {
+                Some(Constant::Float(bits)) => LdFloat(*bits),
+                _ => return Err(DecodeError::InvalidConstant(index)),
+            }
+        }
+        LD_GLOBAL => LdGlobal(input.u32_operand()?),
+        LD_LOCAL => LdLocal(input.u32_operand()?),
+        LD_ENV => LdEnv(input.u32_operand()?),
+        LD_STATIC => LdStatic(input.u32_operand()?),
+        LD_FIELD => LdField,
+        LD_FIELD_AT => LdFieldAt(input.u32_operand()?),
+        ST_LOCAL => StLocal(input.u32_operand()?),
+        ST_ENV => StEnv(input.u32_operand()?),
+        ST_STATIC => StStatic(input.u32_operand()?),
+        ST_FIELD => StField,
+        ST_FIELD_AT => StFieldAt(input.u32_operand()?),
+        NEW_OBJECT => NewObject,
+        TAIL_CALL => TailCall(input.u32_operand()?),
+        CALL => Call(input.u32_operand()?),
+        CALL_CLOSURE => CallClosure(input.u32_operand()?),
+        MK_CLOSURE => MkClosure(input.u32_operand()?),
+        THREAD_YIELD => ThreadYield,
+        JMP => Jmp(input.u32_operand()?),
+        JMP_Z => JmpZ(input.u32_operand()?),
+        JMP_NZ => JmpNz(input.u32_operand()?),
+        SWITCH => Switch,
+        RET => Ret,
+        RET_VOID => RetVoid,
+        TRAP => Trap(input.u32_operand()?),
+        UNREACHABLE => Unreachable,
+        THROW => Throw,
+        ADD => Add,
+        SUB => Sub,
+        DIV => Div,
+        MUL => Mul,
+        MOD => Mod,
+        SHR => Shr,
+        SHL => Shl,
+        EQ => Eq,
+        LT => Lt,
+        LE => Le,
+        GT => Gt,
+        NOT => Not,
+        AND => And,
+        OR => Or,
+        XOR => Xor,
+        NEG => Neg,
+        POP => Pop(input.u32_operand()?),
+        DUP => Dup,
+        INT_TO_FLOAT => IntToFloat,
+        FLOAT_TO_INT => FloatToInt,
+        _ => return Err(DecodeError::InvalidOpcode(op)),
+    };
+    Ok(ins)
+}
+
+fn decode_switch(input: &mut Reader<'_>) -> Result<Option<SwitchTable>, DecodeError> {
+    let tag = input.u8()?;
+    let table = match tag {
+        switch_tag::NONE => return Ok(None),
+        switch_tag::DENSE => {
+            let low = input.signed()?;
+            let len = input.count()?;
+            let mut targets = Vec::with_capacity(len);
+            for _ in 0..len {
+                targets.push(input.u32_operand()?);
+            }
+            SwitchTable::Dense { low, targets }
+        }
+        switch_tag::SPARSE => {
+            let len = input.count()?;
+            let mut cases = Vec::with_capacity(len);
+            for _ in 0..len {
+                let key = input.signed()?;
+                cases.push((key, input.u32_operand()?));
+            }
+            SwitchTable::Sparse(cases)
+        }
+        _ => return Err(DecodeError::InvalidSwitchTag(tag)),
+    };
+    Ok(Some(table))
+}
+
+fn decode_constant(input: &mut Reader<'_>) -> Result<Constant, DecodeError> {
+    let tag = input.u8()?;
+    let constant = match tag {
+        constant_tag::FLOAT => Constant::Float(input.u64()?),
+        constant_tag::STR => {
+            let len = input.count()?;
+            let bytes = input.bytes(len)?;
+            let string = std::str::from_utf8(bytes).map_err(|_| DecodeError::InvalidUtf8)?;
+            Constant::Str(string.to_owned())
+        }
+        _ => return Err(DecodeError::InvalidConstantTag(tag)),
+    };
+    Ok(constant)
+}
+
+fn decode_string(input: &mut Reader<'_>, constants: &[Constant]) -> Result<String, DecodeError> {
+    let index = input.varint()?;
+    match constants.get(index as usize) {
+        Some(Constant::Str(string)) => Ok(string.clone()),
+        _ => Err(DecodeError::InvalidConstant(index)),
+    }
+}
+
+fn decode_literal(input: &mut Reader<'_>) -> Result<Literal, DecodeError> {
+    let tag = input.u8()?;
+    let literal = match tag {
+        literal_tag::NIL => Literal::Nil,
+        literal_tag::INT => Literal::Int(input.signed()?),
+        literal_tag::FLOAT => Literal::Float(input.u64()?),
+        _ => return Err(DecodeError::InvalidLiteralTag(tag)),
+    };
+    Ok(literal)
+}
+
+fn decode_variables(
+    input: &mut Reader<'_>,
+    constants: &[Constant],
+) -> Result<Vec<Variable>, DecodeError> {
+    let count = input.count()?;
+    let mut variables = Vec::with_capacity(count);
+    for _ in 0..count {
+        let name = decode_string(input, constants)?;
+        let init = decode_literal(input)?;
+        variables.push(Variable { name, init });
+    }
+    Ok(variables)
+}
+
+fn block_id(input: &mut Reader<'_>) -> Result<usize, DecodeError> {
+    Ok(input.u32_operand()? as usize)
+}
+
+fn decode_function(
+    input: &mut Reader<'_>,
+    constants: &[Constant],
+) -> Result<Function, DecodeError> {
+    let name = decode_string(input, constants)?;
+    let params = input.u32_operand()?;
+    let locals = input.u32_operand()?;
+    let count = input.count()?;
+    let mut env = Vec::with_capacity(count);
+    for _ in 0..count {
+        env.push(decode_string(input, constants)?);
+    }
+    let entry = block_id(input)?;
+    let exit = block_id(input)?;
+
+    let mut cfg = ControlFlowGraph::default();
+    let mut fallthrough = HashMap::new();
+    let mut ids = std::collections::HashSet::new();
+    let count = input.count()?;
+    for _ in 0..count {
+        let id = block_id(input)?;
+        if !ids.insert(id) {
+            return Err(DecodeError::DuplicateBlock(id));
+        }
+        let next = input.varint()?;
+        if next > 0 {
+            if next - 1 > u32::MAX as u64 {
+                return Err(DecodeError::VarIntOverflow);
+            }
+            fallthrough.insert(id, next as usize - 1);
+        }
+        let handler = match input.varint()? {
+            0 => None,
+            handler if handler - 1 > u32::MAX as u64 => return Err(DecodeError::VarIntOverflow),
+            handler => Some(handler as usize - 1),
+        };
+
+        let len = input.count()?;
+        let mut instructions = Vec::with_capacity(len);
+        for i in 0..len {
+            let ins = decode_instruction(input, constants)?;
+            if ins.is_terminator() && i + 1 != len {
+                return Err(DecodeError::MisplacedTerminator(id));
+            }
+            instructions.push(ins);
+        }
+        let switch = match instructions.last() {
+            Some(Instruction::Switch) => decode_switch(input)?,
+            _ => None,
+        };
+        let block = CodeBlockRef::new(CodeBlock {
+            id,
+            instructions,
+            switch,
+            handler,
+            ..Default::default()
+        });
+        if id == entry {
+            cfg.entry = block.clone();
+        }
+        if id == exit {
+            cfg.exit = block.clone();
+        }
+        cfg.insert_block(block);
+    }
+
+    for id in [entry, exit].iter() {
+        if !ids.contains(id) {
+            return Err(DecodeError::UnknownBlock(*id));
+        }
+    }
+    cfg.link_blocks(&fallthrough)
+        .map_err(DecodeError::UnknownBlock)?;
+    cfg.compute_new_block_id();
+    Ok(Function {
+        name,
+        cfg,
+        params,
+        locals,
+        env,
+    })
+}
+
+/// Validates and loads a module produced by `encode`. The result is
+/// structurally sound; `Module::verify` checks what its code refers to.
+pub fn decode(bytes: &[u8]) -> Result<Module, DecodeError> {
+    let mut input = header(bytes, MAGIC)?;
+    let count = input.count()?;
+    let mut constants = Vec::with_capacity(count);
+    for _ in 0..count {
+        constants.push(decode_constant(&mut input)?);
+    }
+
+    let mut module = Module::new();
+    module.globals = decode_variables(&mut input, &constants)?;
+    module.statics = decode_variables(&mut input, &constants)?;
+
+    let count = input.count()?;
+    for _ in 0..count {
+        let len = input.count()?;
+        let mut body = Reader::new(input.bytes(len)?);
+        let function = decode_function(&mut body, &constants)?;
+        if !body.is_empty() {
+            return Err(DecodeError::LengthMismatch);
+        }
+        module.functions.push(function);
+    }
+    if !input.is_empty() {
+        return Err(DecodeError::TrailingBytes);
+    }
+    Ok(module)
+}
+
+// Checks the header and returns a reader positioned after it.
+fn header(bytes: &[u8], magic: [u8; 4]) -> Result<Reader<'_>, DecodeError> {
+    let mut input = Reader::new(bytes);
+    if input.bytes(4).map_err(|_| DecodeError::BadMagic)? != magic {
+        return Err(DecodeError::BadMagic);
+    }
+    let version = input.u16()?;
+    if version != VERSION {
+        return Err(DecodeError::UnsupportedVersion(version));
+    }
+    let expected = input.u32()?;
+    let actual = checksum(&bytes[HEADER_SIZE..]);
+    if expected != actual {
+        return Err(DecodeError::ChecksumMismatch { expected, actual });
+    }
+    Ok(input)
+}
+
+/// Loads stack maps produced by `encode_stack_maps`. Entries and slots have
+/// to be in increasing order.
+pub fn decode_stack_maps(bytes: &[u8]) -> Result<Vec<StackMaps>, DecodeError> {
+    let mut input = header(bytes, STACK_MAP_MAGIC)?;
+    let count = input.count()?;
+    let mut functions = Vec::with_capacity(count);
+    for _ in 0..count {
+        let mut maps = StackMaps::new();
+        for _ in 0..input.count()? {
+            let key = (block_id(&mut input)?, input.u32_operand()? as usize);
+            if maps.keys().next_back().is_some_and(|last| *last >= key) {
+                return Err(DecodeError::InvalidStackMap);
+            }
+            let locals = decode_slots(&mut input)?;
+            let stack = decode_slots(&mut input)?;
+            maps.insert(key, StackMap { locals, stack });
+        }
+        functions.push(maps);
+    }
+    if !input.is_empty() {
+        return Err(DecodeError::TrailingBytes);
+    }
+    Ok(functions)
+}
+
+fn decode_slots(input: &mut Reader<'_>) -> Result<Vec<u32>, DecodeError> {
+    let len = input.count()?;
+    let mut slots: Vec<u32> = Vec::with_capacity(len);
+    for _ in 0..len {
+        let slot = input.u32_operand()?;
+        if slots.last().is_some_and(|last| *last >= slot) {
+            return Err(DecodeError::InvalidStackMap);
+        }
+        slots.push(slot);
+    }
+    Ok(slots)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::encode;
+    use crate::interpreter::{Interpreter, Value};
+    use EdgeType::*;
+    use Instruction::*;
+
+    // f(a): s0 = 10 / a + g; if a == 0 { s1 = 2.5 }; return s0
+    fn sample() -> Module {
+        let cfg = ControlFlowGraph::from_blocks(
+            vec![
+                vec![
+                    LdInt(10),
+                    LdLocal(0),
+                    Div,
+                    LdGlobal(0),
+                    Add,
+                    StStatic(0),
+                    LdLocal(0),
+                    JmpNz(4),
+                ],
+                vec![LdFloat(2.5f64.to_bits()), StStatic(1)],
+                vec![LdStatic(0)],
+            ],
+            &[
+                (0, 2, FallThrough),
+                (2, 4, Branch),
+                (2, 3, FallThrough),
+                (3, 4, FallThrough),
+                (4, 1, FallThrough),
+            ],
+        );
+        let mut module = Module::new();
+        module.add_function(Function::new("f", 1, cfg));
+        module.add_global("g", Literal::Int(1));
+        module.add_static("s0", Literal::Nil);
+        module.add_static("s1", Literal::Float(0.5f64.to_bits()));
+        module
+    }
+
+    fn edges(cfg: &ControlFlowGraph) -> Vec<(usize, usize, EdgeType)> {
+        let mut edges = cfg
+            .edges
+            .borrow()
+            .iter()
+            .map(|edge| {
+                let edge = edge.borrow();
+                let id = |block: &Option<CodeBlockRef>| block.as_ref().unwrap().borrow().id;
+                (id(&edge.head), id(&edge.tail), edge.ty)
+            })
+            .collect::<Vec<_>>();
+        edges.sort_by_key(|edge| (edge.0, edge.1));
+        edges
+    }
+
+    // Stores the checksum of a modified payload, so decoding gets past the
+    // header.
+    fn reseal(bytes: &mut [u8]) {
+        let checksum = checksum(&bytes[HEADER_SIZE..]).to_le_bytes();
+        bytes[HEADER_SIZE - 4..HEADER_SIZE].copy_from_slice(&checksum);
+    }
+
+    #[test]
+    fn round_trip() {
+        let original = sample();
+        let bytes = encode(&original);
+        let module = decode(&bytes).unwrap();
+        assert_eq!(encode(&module), bytes);
+
+        let (before, after) = (&original.functions[0].cfg, &module.functions[0].cfg);
+        assert_eq!(module.functions[0].name, "f");
+        assert_eq!(edges(after), edges(before));
+        for block in before.blocks.iter() {
+            let block = block.borrow();
+            let decoded = after.find_block(block.id).unwrap();
+            assert_eq!(decoded.borrow().instructions, block.instructions);
+        }
+        assert_eq!(after.entry.borrow().id, 0);
+        assert_eq!(after.exit.borrow().id, 1);
+        assert_eq!(module.functions[0].params, 1);
+        assert_eq!(module.globals, original.globals);
+        assert_eq!(module.statics, original.statics);
+        let result = Interpreter::new(&module).call(0, &[Value::Int(5)]);
+        assert_eq!(result, Ok(Value::Int(3)));
+    }
+
+    #[test]
+    fn jump_to_missing_block_is_rejected() {
+        let module = sample();
+        module.functions[0]
+            .cfg
+            .find_block(3)
+            .unwrap()
+            .borrow_mut()
+            .instructions
+            .push(Jmp(9));
+        let error = Some(DecodeError::UnknownBlock(9));
+        assert_eq!(decode(&encode(&module)).err(), error);
+    }
+
+    #[test]
+    fn truncated_input_is_rejected() {
+        let bytes = encode(&sample());
+        for len in 0..bytes.len() {
+            let mut truncated = bytes[..len].to_vec();
+            assert!(decode(&truncated).is_err(), "{} bytes", len);
+            if len >= HEADER_SIZE {
+                reseal(&mut truncated);
+                assert!(decode(&truncated).is_err(), "{} bytes resealed", len);
+            }
+        }
+    }
+
+    #[test]
+    fn corrupted_header_is_rejected() {
+        let bytes = encode(&sample());
+        let mut corrupted = bytes.clone();
+        corrupted[0] ^= 1;
+        assert_eq!(decode(&corrupted).err(), Some(DecodeError::BadMagic));
+
+        let mut corrupted = bytes.clone();
+        corrupted[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        let error = Some(DecodeError::UnsupportedVersion(VERSION + 1));
+        assert_eq!(decode(&corrupted).err(), error);
+
+        let mut corrupted = bytes;
+        corrupted.push(0);
+        assert!(matches!(
+            decode(&corrupted),
+            Err(DecodeError::ChecksumMismatch { .. })
+        ));
+        reseal(&mut corrupted);
+        assert_eq!(decode(&corrupted).err(), Some(DecodeError::TrailingBytes));
+    }
+
+    #[test]
+    fn corrupted_payload_is_rejected_or_decoded() {
+        let bytes = encode(&sample());
+        for i in HEADER_SIZE..bytes.len() {
+            for mask in [0x01, 0x80, 0xff].iter() {
+                let mut corrupted = bytes.clone();
+                corrupted[i] ^= mask;
+                assert!(matches!(
+                    decode(&corrupted),
+                    Err(DecodeError::ChecksumMismatch { .. })
+                ));
+                // past the checksum, any byte may be wrong without a panic
+                reseal(&mut corrupted);
+                let _ = decode(&corrupted);
+            }
+        }
+    }
+
+    // f(a): a in -1..=0 and a in {10, 20} go to 4 and 5, other keys to 6
+    fn switches() -> Module {
+        let mut cfg = ControlFlowGraph::new();
+        let tables = vec![
+            Some(SwitchTable::Dense {
+                low: -1,
+                targets: vec![4, 5],
+            }),
+            Some(SwitchTable::Sparse(vec![(10, 5), (20, 4)])),
+            None,
+            None,
+            None,
+        ];
+        let code = vec![
+            vec![LdLocal(0), Switch],
+            vec![LdLocal(0), Switch],
+            vec![LdInt(40), Ret],
+            vec![LdInt(50), Ret],
+            vec![LdInt(60), Ret],
+        ];
+        for (instructions, switch) in code.into_iter().zip(tables) {
+            let id = cfg.new_id();
+            cfg.insert_block(CodeBlockRef::new(CodeBlock {
+                id,
+                instructions,
+                switch,
+                ..Default::default()
+            }));
+        }
+        cfg.link_blocks(&HashMap::from([(0, 2), (2, 3), (3, 6)]))
+            .unwrap();
+        let mut module = Module::new();
+        module.add_function(Function::new("f", 1, cfg));
+        module
+    }
+
+    #[test]
+    fn switch_tables_round_trip() {
+        let original = switches();
+        let bytes = encode(&original);
+        let module = decode(&bytes).unwrap();
+        assert_eq!(encode(&module), bytes);
+
+        let (before, after) = (&original.functions[0].cfg, &module.functions[0].cfg);
+        assert_eq!(edges(after), edges(before));
+        for id in 2..=3 {
+            let decoded = after.find_block(id).unwrap().borrow().switch.clone();
+            assert_eq!(decoded, before.find_block(id).unwrap().borrow().switch);
+        }
+        let mut interpreter = Interpreter::new(&module);
+        for (key, result) in [(-1, 40), (0, 50), (1, 60), (10, 50), (20, 40), (-2, 60)].iter() {
+            let value = interpreter.call(0, &[Value::Int(*key)]);
+            assert_eq!(value, Ok(Value::Int(*result)), "{}", key);
+        }
+    }
+
+    #[test]
+    fn unknown_switch_tag_is_rejected() {
+        let error = decode_switch(&mut Reader::new(&[3]));
+        assert_eq!(error, Err(DecodeError::InvalidSwitchTag(3)));
+    }
+}
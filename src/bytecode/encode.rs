@@ -0,0 +1,258 @@
+use super::*;
+use crate::analysis::stackmap::*;
+use crate::block::*;
+use crate::instructions::Instruction;
+use crate::module::*;
+
+#[derive(Default)]
+pub(crate) struct Writer {
+    pub bytes: Vec<u8>,
+}
+
+impl Writer {
+    pub fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Unsigned LEB128.
+    pub fn varint(&mut self, mut value: u64) {
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                self.bytes.push(byte);
+                break;
+            }
+            self.bytes.push(byte | 0x80);
+        }
+    }
+
+    pub fn signed(&mut self, value: i64) {
+        self.varint(((value << 1) ^ (value >> 63)) as u64);
+    }
+}
+
+struct ConstantPool {
+    constants: Vec<Constant>,
+}
+
+impl ConstantPool {
+    fn index(&mut self, constant: Constant) -> u64 {
+        match self.constants.iter().position(|c| *c == constant) {
+            Some(i) => i as u64,
+            None => {
+                self.constants.push(constant);
+                self.constants.len() as u64 - 1
+            }
+        }
+    }
+}
+
+pub(crate) fn encode_instruction(
+    out: &mut Writer,
+    ins: &Instruction,
+    pool: &mut dyn FnMut(u64) -> u64,
+) {
+    use opcode::*;
+    use Instruction::*;
+    let (op, operand) = match ins {
+        LdInt(value) => {
+            out.u8(LD_INT);
+            out.signed(*value);
+            return;
+        }
+        LdFloat(bits) => (LD_FLOAT, Some(pool(*bits))),
+        LdGlobal(n) => (LD_GLOBAL, Some(*n as u64)),
+        LdLocal(n) => (LD_LOCAL, Some(*n as u64)),
+        LdEnv(n) => (LD_ENV, Some(*n as u64)),
+        LdStatic(n) => (LD_STATIC, Some(*n as u64)),
+        LdField => (LD_FIELD, None),
+        LdFieldAt(n) => (LD_FIELD_AT, Some(*n as u64)),
+        StLocal(n) => (ST_LOCAL, Some(*n as u64)),
+        StEnv(n) => (ST_ENV, Some(*n as u64)),
+        StStatic(n) => (ST_STATIC, Some(*n as u64)),
+        StField => (ST_FIELD, None),
+        StFieldAt(n) => (ST_FIELD_AT, Some(*n as u64)),
+        NewObject => (NEW_OBJECT, None),
+        TailCall(n) => (TAIL_CALL, Some(*n as u64)),
+        Call(n) => (CALL, Some(*n as u64)),
+        CallClosure(n) => (CALL_CLOSURE, Some(*n as u64)),
+        MkClosure(n) => (MK_CLOSURE, Some(*n as u64)),
+        ThreadYield => (THREAD_YIELD, None),
+        Jmp(n) => (JMP, Some(*n as u64)),
+        JmpZ(n) => (JMP_Z, Some(*n as u64)),
+        JmpNz(n) => (JMP_NZ, Some(*n as u64)),
+        Switch => (SWITCH, None),
+        Ret => (RET, None),
+        RetVoid => (RET_VOID, None),
+        Trap(code) => (TRAP, Some(*code as u64)),
+        Unreachable => (UNREACHABLE, None),
+        Throw => (THROW, None),
+        Add => (ADD, None),
+        Sub => (SUB, None),
+        Div => (DIV, None),
+        Mul => (MUL, None),
+        Mod => (MOD, None),
+        Shr => (SHR, None),
+        Shl => (SHL, None),
+        Eq => (EQ, None),
+        Lt => (LT, None),
+        Le => (LE, None),
+        Gt => (GT, None),
+        Not => (NOT, None),
+        And => (AND, None),
+        Or => (OR, None),
+        Xor => (XOR, None),
+        Neg => (NEG, None),
+        Pop(n) => (POP, Some(*n as u64)),
+        Dup => (DUP, None),
+        IntToFloat => (INT_TO_FLOAT, None),
+        FloatToInt => (FLOAT_TO_INT, None),
+    };
+    out.u8(op);
+    if let Some(operand) = operand {
+        out.varint(operand);
+    }
+}
+
+fn encode_literal(out: &mut Writer, literal: Literal) {
+    match literal {
+        Literal::Nil => out.u8(literal_tag::NIL),
+        Literal::Int(value) => {
+            out.u8(literal_tag::INT);
+            out.signed(value);
+        }
+        Literal::Float(bits) => {
+            out.u8(literal_tag::FLOAT);
+            out.u64(bits);
+        }
+    }
+}
+
+fn encode_variables(out: &mut Writer, variables: &[Variable], pool: &mut ConstantPool) {
+    out.varint(variables.len() as u64);
+    for variable in variables.iter() {
+        out.varint(pool.index(Constant::Str(variable.name.clone())));
+        encode_literal(out, variable.init);
+    }
+}
+
+fn encode_function(out: &mut Writer, function: &Function, pool: &mut ConstantPool) {
+    let cfg = &function.cfg;
+    out.varint(pool.index(Constant::Str(function.name.clone())));
+    out.varint(function.params as u64);
+    out.varint(function.locals as u64);
+    out.varint(function.env.len() as u64);
+    for name in function.env.iter() {
+        out.varint(pool.index(Constant::Str(name.clone())));
+    }
+    out.varint(cfg.entry.borrow().id as u64);
+    out.varint(cfg.exit.borrow().id as u64);
+    out.varint(cfg.blocks.len() as u64);
+    for block in cfg.blocks.iter() {
+        let block = block.borrow();
+        out.varint(block.id as u64);
+        let fallthrough = block
+            .out_edges
+            .iter()
+            .find(|edge| edge.borrow().ty == EdgeType::FallThrough)
+            .map(|edge| edge.borrow().tail.as_ref().unwrap().borrow().id as u64 + 1)
+            .unwrap_or(0);
+        out.varint(fallthrough);
+        out.varint(block.handler.map_or(0, |handler| handler as u64 + 1));
+        out.varint(block.instructions.len() as u64);
+        for ins in block.instructions.iter() {
+            encode_instruction(out, ins, &mut |bits| pool.index(Constant::Float(bits)));
+        }
+        if let Some(Instruction::Switch) = block.instructions.last() {
+            encode_switch(out, block.switch.as_ref());
+        }
+    }
+}
+
+fn encode_switch(out: &mut Writer, table: Option<&SwitchTable>) {
+    match table {
+        None => out.u8(switch_tag::NONE),
+        Some(SwitchTable::Dense { low, targets }) => {
+            out.u8(switch_tag::DENSE);
+            out.signed(*low);
+            out.varint(targets.len() as u64);
+            for target in targets.iter() {
+                out.varint(*target as u64);
+            }
+        }
+        Some(SwitchTable::Sparse(cases)) => {
+            out.u8(switch_tag::SPARSE);
+            out.varint(cases.len() as u64);
+            for (key, target) in cases.iter() {
+                out.signed(*key);
+                out.varint(*target as u64);
+            }
+        }
+    }
+}
+
+pub fn encode(module: &Module) -> Vec<u8> {
+    let mut pool = ConstantPool { constants: vec![] };
+    let mut tables = Writer::default();
+    encode_variables(&mut tables, &module.globals, &mut pool);
+    encode_variables(&mut tables, &module.statics, &mut pool);
+    tables.varint(module.functions.len() as u64);
+    for function in module.functions.iter() {
+        let mut body = Writer::default();
+        encode_function(&mut body, function, &mut pool);
+        tables.varint(body.bytes.len() as u64);
+        tables.bytes.extend_from_slice(&body.bytes);
+    }
+
+    let mut payload = Writer::default();
+    payload.varint(pool.constants.len() as u64);
+    for constant in pool.constants.iter() {
+        match constant {
+            Constant::Float(bits) => {
+                payload.u8(constant_tag::FLOAT);
+                payload.u64(*bits);
+            }
+            Constant::Str(string) => {
+                payload.u8(constant_tag::STR);
+                payload.varint(string.len() as u64);
+                payload.bytes.extend_from_slice(string.as_bytes());
+            }
+        }
+    }
+    payload.bytes.extend_from_slice(&tables.bytes);
+    with_header(MAGIC, &payload.bytes)
+}
+
+fn with_header(magic: [u8; 4], payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(HEADER_SIZE + payload.len());
+    out.extend_from_slice(&magic);
+    out.extend_from_slice(&VERSION.to_le_bytes());
+    out.extend_from_slice(&checksum(payload).to_le_bytes());
+    out.extend_from_slice(payload);
+    out
+}
+
+/// Encodes the stack maps of a module's functions, in function order.
+pub fn encode_stack_maps(maps: &[StackMaps]) -> Vec<u8> {
+    let mut payload = Writer::default();
+    payload.varint(maps.len() as u64);
+    for function in maps.iter() {
+        payload.varint(function.len() as u64);
+        for ((block, index), map) in function.iter() {
+            payload.varint(*block as u64);
+            payload.varint(*index as u64);
+            for slots in [&map.locals, &map.stack].iter() {
+                payload.varint(slots.len() as u64);
+                for slot in slots.iter() {
+                    payload.varint(*slot as u64);
+                }
+            }
+        }
+    }
+    with_header(STACK_MAP_MAGIC, &payload.bytes)
+}
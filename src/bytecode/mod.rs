@@ -0,0 +1,155 @@
+pub mod decode;
+pub mod encode;
+
+pub use decode::{decode, decode_stack_maps};
+pub use encode::{encode, encode_stack_maps};
+
+pub const MAGIC: [u8; 4] = *b"RTBC";
+/// Magic of the stack map section stored next to a module, which shares its
+/// version and header layout.
+pub const STACK_MAP_MAGIC: [u8; 4] = *b"RTSM";
+pub const VERSION: u16 = 9;
+/// Magic, version and checksum.
+pub const HEADER_SIZE: usize = 10;
+
+/// Entry of the constant pool shared by all functions of an encoded module.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Constant {
+    Float(u64),
+    Str(String),
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum DecodeError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    UnexpectedEnd,
+    ChecksumMismatch { expected: u32, actual: u32 },
+    VarIntOverflow,
+    InvalidOpcode(u8),
+    InvalidConstantTag(u8),
+    InvalidLiteralTag(u8),
+    InvalidSwitchTag(u8),
+    InvalidConstant(u64),
+    InvalidUtf8,
+    DuplicateBlock(usize),
+    UnknownBlock(usize),
+    MisplacedTerminator(usize),
+    InvalidStackMap,
+    LengthMismatch,
+    TrailingBytes,
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use DecodeError::*;
+        match self {
+            BadMagic => write!(f, "not a bytecode module"),
+            UnsupportedVersion(v) => write!(f, "unsupported bytecode version {}", v),
+            UnexpectedEnd => write!(f, "unexpected end of input"),
+            ChecksumMismatch { expected, actual } => write!(
+                f,
+                "checksum mismatch: expected {:08x}, found {:08x}",
+                expected, actual
+            ),
+            VarIntOverflow => write!(f, "variable length integer out of range"),
+            InvalidOpcode(op) => write!(f, "invalid opcode {:#04x}", op),
+            InvalidConstantTag(tag) => write!(f, "invalid constant tag {}", tag),
+            InvalidLiteralTag(tag) => write!(f, "invalid literal tag {}", tag),
+            InvalidSwitchTag(tag) => write!(f, "invalid switch table tag {}", tag),
+            InvalidConstant(n) => write!(f, "invalid constant pool index {}", n),
+            InvalidUtf8 => write!(f, "string constant is not valid UTF-8"),
+            DuplicateBlock(id) => write!(f, "block {} defined twice", id),
+            UnknownBlock(id) => write!(f, "reference to undefined block {}", id),
+            MisplacedTerminator(id) => write!(f, "terminator before the end of block {}", id),
+            InvalidStackMap => write!(f, "stack map entries are not in order"),
+            LengthMismatch => write!(f, "function body length does not match its contents"),
+            TrailingBytes => write!(f, "trailing bytes after the last function"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+pub(crate) mod opcode {
+    pub const LD_INT: u8 = 0x01;
+    pub const LD_FLOAT: u8 = 0x02;
+    pub const LD_GLOBAL: u8 = 0x03;
+    pub const LD_LOCAL: u8 = 0x04;
+    pub const LD_ENV: u8 = 0x05;
+    pub const LD_STATIC: u8 = 0x06;
+    pub const LD_FIELD: u8 = 0x07;
+    pub const ST_LOCAL: u8 = 0x08;
+    pub const ST_ENV: u8 = 0x09;
+    pub const ST_STATIC: u8 = 0x0a;
+    pub const ST_FIELD: u8 = 0x0b;
+    pub const LD_FIELD_AT: u8 = 0x0c;
+    pub const ST_FIELD_AT: u8 = 0x0d;
+    pub const NEW_OBJECT: u8 = 0x0e;
+    pub const TAIL_CALL: u8 = 0x10;
+    pub const CALL: u8 = 0x11;
+    pub const THREAD_YIELD: u8 = 0x12;
+    pub const CALL_CLOSURE: u8 = 0x13;
+    pub const MK_CLOSURE: u8 = 0x14;
+    pub const THROW: u8 = 0x17;
+    pub const JMP: u8 = 0x18;
+    pub const JMP_Z: u8 = 0x19;
+    pub const JMP_NZ: u8 = 0x1a;
+    pub const SWITCH: u8 = 0x1f;
+    pub const RET: u8 = 0x1b;
+    pub const RET_VOID: u8 = 0x1c;
+    pub const TRAP: u8 = 0x1d;
+    pub const UNREACHABLE: u8 = 0x1e;
+    pub const ADD: u8 = 0x20;
+    pub const SUB: u8 = 0x21;
+    pub const DIV: u8 = 0x22;
+    pub const MUL: u8 = 0x23;
+    pub const MOD: u8 = 0x24;
+    pub const SHR: u8 = 0x25;
+    pub const SHL: u8 = 0x26;
+    pub const EQ: u8 = 0x27;
+    pub const LT: u8 = 0x28;
+    pub const LE: u8 = 0x29;
+    pub const GT: u8 = 0x2a;
+    pub const NOT: u8 = 0x2b;
+    pub const AND: u8 = 0x2c;
+    pub const OR: u8 = 0x2d;
+    pub const XOR: u8 = 0x2e;
+    pub const NEG: u8 = 0x2f;
+    pub const POP: u8 = 0x30;
+    pub const DUP: u8 = 0x31;
+    pub const INT_TO_FLOAT: u8 = 0x38;
+    pub const FLOAT_TO_INT: u8 = 0x39;
+}
+
+/// Tag of the table written after the instructions of a block ending in
+/// `Switch`.
+pub(crate) mod switch_tag {
+    pub const NONE: u8 = 0;
+    pub const DENSE: u8 = 1;
+    pub const SPARSE: u8 = 2;
+}
+
+pub(crate) mod constant_tag {
+    pub const FLOAT: u8 = 1;
+    pub const STR: u8 = 2;
+}
+
+pub(crate) mod literal_tag {
+    pub const NIL: u8 = 0;
+    pub const INT: u8 = 1;
+    pub const FLOAT: u8 = 2;
+}
+
+/// CRC-32 (IEEE) of `data`.
+pub fn checksum(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
@@ -1,4 +1,5 @@
 use crate::block::*;
+use crate::instructions::Instruction;
 
 use std::cell::*;
 use std::rc::Rc;
@@ -25,7 +26,7 @@ impl ControlFlowGraph {
         this.blocks.push(entry.clone());
         this.entry = entry;
         let exit = CodeBlockRef::new(CodeBlock {
-            id: 0,
+            id: 1,
             ..Default::default()
         });
         this.blocks.push(exit.clone());
@@ -60,6 +61,19 @@ impl ControlFlowGraph {
         count
     }
 
+    /// One past the highest local slot referenced by any block.
+    pub fn first_free_local(&self) -> u32 {
+        let mut next = 0;
+        for block in self.blocks.iter() {
+            for ins in block.borrow().instructions.iter() {
+                if let Instruction::LdLocal(n) | Instruction::StLocal(n) = ins {
+                    next = std::cmp::max(next, *n + 1);
+                }
+            }
+        }
+        next
+    }
+
     pub fn is_empty(&self) -> bool {
         self.blocks.is_empty()
     }
@@ -69,7 +83,7 @@ impl ControlFlowGraph {
         block
     }
 
-    pub fn clone_block(&mut self, block: CodeBlockRef) {
+    pub fn clone_block(&mut self, block: CodeBlockRef) -> CodeBlockRef {
         let id = self.new_id();
         /*let new_block = CodeBlock {
             instructions: block.borrow().instructions.clone(),
@@ -78,109 +92,143 @@ impl ControlFlowGraph {
         };*/
         let mut new_block = CodeBlock::default();
         new_block.instructions = block.borrow().instructions.clone();
+        new_block.switch = block.borrow().switch.clone();
+        new_block.handler = block.borrow().handler;
         new_block.id = id;
-        self.insert_block(CodeBlockRef::new(new_block));
+        self.insert_block(CodeBlockRef::new(new_block))
     }
 
-    pub fn remove_edge(&mut self, edge: Rc<RefCell<Edge>>) {
-        let edge_: &Edge = &edge.borrow();
-        let out = {
-            let mut value_: Option<usize> = None;
-            for (i, value) in edge_
-                .head
-                .as_ref()
-                .unwrap()
-                .borrow()
-                .out_edges
-                .iter()
-                .enumerate()
-            {
-                if *value.borrow() == *edge.borrow() {
-                    value_ = Some(i);
-                    break;
-                }
+    /// Clones `blocks`, which may belong to another graph, into this graph
+    /// together with the edges between them. Branches between cloned blocks
+    /// are retargeted to the copies; edges leaving the set are not copied.
+    /// Returns the copies keyed by the id of their original.
+    pub fn clone_subgraph(
+        &mut self,
+        blocks: &[CodeBlockRef],
+    ) -> std::collections::HashMap<usize, CodeBlockRef> {
+        let copies = blocks
+            .iter()
+            .map(|block| (block.borrow().id, self.clone_block(block.clone())))
+            .collect::<std::collections::HashMap<_, _>>();
+        let ids = copies
+            .iter()
+            .map(|(id, copy)| (*id, copy.borrow().id))
+            .collect::<std::collections::HashMap<_, _>>();
+        for block in blocks.iter() {
+            let mut head = copies[&block.borrow().id].clone();
+            head.borrow_mut().retarget_branches(&ids);
+            let handler = head.borrow().handler.and_then(|handler| ids.get(&handler).copied());
+            if handler.is_some() {
+                head.borrow_mut().handler = handler;
             }
-            value_
-        };
-        assert!(out.is_some());
+            for edge in block.borrow().out_edges.iter() {
+                let edge = edge.borrow();
+                let target = edge.tail.as_ref().unwrap().borrow().id;
+                let tail = match copies.get(&target) {
+                    Some(tail) => tail.clone(),
+                    None => continue,
+                };
+                self.insert_edge(Rc::new(RefCell::new(Edge {
+                    head: Some(head.clone()),
+                    tail: Some(tail),
+                    ty: edge.ty,
+                })));
+            }
+        }
+        copies
+    }
 
-        let mut head = edge_.head.as_ref().unwrap().clone();
+    pub fn remove_edge(&mut self, edge: Rc<RefCell<Edge>>) {
+        let mut head = edge.borrow().head.as_ref().unwrap().clone();
+        let mut tail = edge.borrow().tail.as_ref().unwrap().clone();
+
+        let out = head
+            .borrow()
+            .out_edges
+            .iter()
+            .position(|value| Rc::ptr_eq(value, &edge));
+        assert!(out.is_some());
         head.borrow_mut().out_edges.remove(out.unwrap());
-        let in_ = {
-            let mut value_: Option<usize> = None;
-            for (i, value) in edge_
-                .tail
-                .as_ref()
-                .unwrap()
-                .borrow()
-                .in_edges
-                .iter()
-                .enumerate()
-            {
-                if *value.borrow() == *edge.borrow() {
-                    value_ = Some(i);
-                    break;
-                }
-            }
-            value_
-        };
-        assert!(in_.is_some());
-        drop(head);
 
-        let mut tail = edge_.tail.as_ref().unwrap().clone();
+        let in_ = tail
+            .borrow()
+            .in_edges
+            .iter()
+            .position(|value| Rc::ptr_eq(value, &edge));
+        assert!(in_.is_some());
         tail.borrow_mut().in_edges.remove(in_.unwrap());
 
-        let heads = {
-            let mut value_: Option<usize> = None;
-            for (i, value) in edge_
-                .head
-                .as_ref()
-                .unwrap()
-                .borrow()
-                .successors
-                .iter()
-                .enumerate()
-            {
-                if *value == *edge.borrow().tail.as_ref().unwrap() {
-                    value_ = Some(i);
-                    break;
-                }
-            }
-            value_
+        let successor = head
+            .borrow()
+            .successors
+            .iter()
+            .position(|value| value.ptr_eq(&tail))
+            .unwrap();
+        head.borrow_mut().successors.remove(successor);
+        let predecessor = tail
+            .borrow()
+            .predecessors
+            .iter()
+            .position(|value| value.ptr_eq(&head))
+            .unwrap();
+        tail.borrow_mut().predecessors.remove(predecessor);
+
+        let i = self
+            .edges
+            .borrow()
+            .iter()
+            .position(|value| Rc::ptr_eq(value, &edge))
+            .unwrap();
+        self.edges.borrow_mut().remove(i);
+    }
+
+    /// Removes the exception edges of blocks that can no longer throw, e.g.
+    /// after a rewrite folded their only throwing instruction. Returns whether
+    /// any edge was removed.
+    pub fn remove_dead_exception_edges(&mut self) -> bool {
+        let dead = self
+            .edges
+            .borrow()
+            .iter()
+            .filter(|edge| {
+                let edge = edge.borrow();
+                edge.ty == EdgeType::Exception && !edge.head.as_ref().unwrap().borrow().may_throw()
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        let changed = !dead.is_empty();
+        for edge in dead {
+            self.remove_edge(edge);
+        }
+        changed
+    }
+
+    pub fn remove_block(&mut self, block: &CodeBlockRef) {
+        let edges = {
+            let block = block.borrow();
+            let mut edges = block.in_edges.clone();
+            edges.extend(block.out_edges.iter().cloned());
+            edges
         };
-        let mut head = edge_.head.as_ref().unwrap().clone();
-        head.borrow_mut().successors.remove(heads.unwrap());
-        let heads = {
-            let mut value_: Option<usize> = None;
-            for (i, value) in edge_
-                .head
-                .as_ref()
-                .unwrap()
+        for edge in edges {
+            // a self loop shows up in both lists
+            let removed = !self
+                .edges
                 .borrow()
-                .predecessors
                 .iter()
-                .enumerate()
-            {
-                if *value == *edge.borrow().head.as_ref().unwrap() {
-                    value_ = Some(i);
-                    break;
-                }
+                .any(|value| Rc::ptr_eq(value, &edge));
+            if !removed {
+                self.remove_edge(edge);
             }
-            value_
-        };
-        let mut head = edge_.head.as_ref().unwrap().clone();
-        head.borrow_mut().predecessors.remove(heads.unwrap());
-
-        let i = {
-            let mut x = None;
-            for (i, val) in self.edges.borrow().iter().enumerate() {
-                if *val.borrow() == *edge.borrow() {
-                    x = Some(i);
-                }
-            }
-            x.unwrap()
-        };
-        self.edges.borrow_mut().remove(i);
+        }
+        self.blocks.retain(|value| !value.ptr_eq(block));
+    }
+
+    pub fn find_block(&self, id: usize) -> Option<CodeBlockRef> {
+        self.blocks
+            .iter()
+            .find(|block| block.borrow().id == id)
+            .cloned()
     }
 
     pub fn insert_edge(&mut self, edge: Rc<RefCell<Edge>>) -> Rc<RefCell<Edge>> {
@@ -243,6 +291,158 @@ impl ControlFlowGraph {
         (first_edge, second_edge)
     }
 
+    /// Splits `edge` with a new empty block and keeps the branch instructions
+    /// of the edge's head pointing at the right block.
+    pub fn insert_block_on_edge(&mut self, edge: Rc<RefCell<Edge>>) -> CodeBlockRef {
+        let mut head = edge.borrow().head.as_ref().unwrap().clone();
+        let tail = edge.borrow().tail.as_ref().unwrap().borrow().id;
+        let ty = edge.borrow().ty;
+
+        let mut block = CodeBlock {
+            id: self.new_id(),
+            ..Default::default()
+        };
+        if ty == EdgeType::Branch {
+            block.instructions.push(Instruction::Jmp(tail as u32));
+            head.borrow_mut().retarget_branch(tail, block.id);
+        }
+        if ty == EdgeType::Exception {
+            head.borrow_mut().handler = Some(block.id);
+        }
+        let block = CodeBlockRef::new(block);
+        let (_, out) = self.split_edge(edge, block.clone());
+        if ty == EdgeType::Exception {
+            // the new block cannot throw, it runs the handler next
+            out.borrow_mut().ty = EdgeType::FallThrough;
+        }
+        block
+    }
+
+    /// Moves the tail of `edge` to `tail`, retargeting the branch of the
+    /// edge's head.
+    pub fn redirect_edge(
+        &mut self,
+        edge: Rc<RefCell<Edge>>,
+        tail: CodeBlockRef,
+    ) -> Rc<RefCell<Edge>> {
+        let mut head = edge.borrow().head.as_ref().unwrap().clone();
+        let old = edge.borrow().tail.as_ref().unwrap().borrow().id;
+        let ty = edge.borrow().ty;
+        self.remove_edge(edge);
+        if ty == EdgeType::Branch {
+            let id = tail.borrow().id;
+            head.borrow_mut().retarget_branch(old, id);
+        }
+        if ty == EdgeType::Exception {
+            head.borrow_mut().handler = Some(tail.borrow().id);
+        }
+        self.insert_edge(Rc::new(RefCell::new(Edge {
+            head: Some(head),
+            tail: Some(tail),
+            ty,
+        })))
+    }
+
+    /// Creates the edges of every block: a branch edge to each target of its
+    /// final jump or switch, a fall through edge to the block given in
+    /// `fallthrough`, an exception edge to the handler of blocks that may
+    /// throw and a return edge to the exit from blocks that leave the
+    /// function.
+    /// Returns the first target that is not a block of this graph.
+    pub fn link_blocks(
+        &mut self,
+        fallthrough: &std::collections::HashMap<usize, usize>,
+    ) -> Result<(), usize> {
+        let ids = self
+            .blocks
+            .iter()
+            .map(|block| (block.borrow().id, block.clone()))
+            .collect::<std::collections::HashMap<_, _>>();
+        for block in self.blocks.clone() {
+            let id = block.borrow().id;
+            let targets = match block.borrow().instructions.last() {
+                Some(Instruction::Jmp(target))
+                | Some(Instruction::JmpZ(target))
+                | Some(Instruction::JmpNz(target)) => vec![*target],
+                Some(Instruction::Switch) => block
+                    .borrow()
+                    .switch
+                    .as_ref()
+                    .map(|table| table.targets())
+                    .unwrap_or_default(),
+                _ => vec![],
+            };
+            let falls_through = block
+                .borrow()
+                .instructions
+                .last()
+                .is_none_or(|ins| ins.falls_through());
+
+            let mut edges = vec![];
+            for target in targets {
+                edges.push((target as usize, EdgeType::Branch));
+            }
+            let handler = block.borrow().handler;
+            if let Some(handler) = handler {
+                ids.get(&handler).ok_or(handler)?;
+                if block.borrow().may_throw() {
+                    edges.push((handler, EdgeType::Exception));
+                }
+            }
+            if let Some(next) = fallthrough.get(&id) {
+                if falls_through {
+                    edges.push((*next, EdgeType::FallThrough));
+                }
+            }
+            for (target, ty) in edges {
+                let tail = ids.get(&target).ok_or(target)?.clone();
+                self.insert_edge(Rc::new(RefCell::new(Edge {
+                    head: Some(block.clone()),
+                    tail: Some(tail),
+                    ty,
+                })));
+            }
+        }
+        self.connect_returns();
+        Ok(())
+    }
+
+    /// Adds the missing return edges from blocks ending in a return, tail
+    /// call, trap or unhandled throw to the exit, so every block that
+    /// finishes the function reaches the exit.
+    pub fn connect_returns(&mut self) {
+        for block in self.blocks.clone() {
+            let returns = match block.borrow().instructions.last() {
+                Some(Instruction::Throw) => block.borrow().handler.is_none(),
+                Some(ins) => ins.is_return(),
+                None => false,
+            };
+            let connected = block
+                .borrow()
+                .out_edges
+                .iter()
+                .any(|edge| edge.borrow().ty == EdgeType::Return);
+            if returns && !connected {
+                self.insert_edge(Rc::new(RefCell::new(Edge {
+                    head: Some(block),
+                    tail: Some(self.exit.clone()),
+                    ty: EdgeType::Return,
+                })));
+            }
+        }
+    }
+
+    /// Depth-first post order of the blocks reachable from the entry.
+    pub fn post_order(&self) -> Vec<CodeBlockRef> {
+        post_order_from(self.get_entry_block(), |block| &block.successors)
+    }
+
+    /// Depth-first post order of the blocks that reach the exit, walking
+    /// edges backwards.
+    pub fn post_order_to_exit(&self) -> Vec<CodeBlockRef> {
+        post_order_from(self.exit.clone(), |block| &block.predecessors)
+    }
+
     pub fn topological_sequence(&self) -> Vec<CodeBlockRef> {
         let mut visited: BlockSet = BlockSet::new();
         let mut sequence: Vec<CodeBlockRef> = vec![];
@@ -349,3 +549,65 @@ impl ControlFlowGraph {
         sequence
     }
 }
+
+fn post_order_from(
+    start: CodeBlockRef,
+    next: fn(&CodeBlock) -> &Vec<CodeBlockRef>,
+) -> Vec<CodeBlockRef> {
+    let mut visited = std::collections::HashSet::new();
+    let mut order = vec![];
+    visited.insert(start.borrow().id);
+    let mut stack = vec![(start, 0)];
+    while let Some((block, i)) = stack.pop() {
+        let succ = next(&block.borrow()).get(i).cloned();
+        match succ {
+            Some(succ) => {
+                stack.push((block, i + 1));
+                if visited.insert(succ.borrow().id) {
+                    stack.push((succ, 0));
+                }
+            }
+            None => order.push(block),
+        }
+    }
+    order
+}
+
+#[cfg(test)]
+impl ControlFlowGraph {
+    /// Builds a graph from `blocks`, numbered from 2 on, and `edges` given as
+    /// `(head, tail, type)` triples of block ids. The tail of an exception
+    /// edge becomes the handler of its head.
+    pub(crate) fn from_blocks(
+        blocks: Vec<Vec<crate::instructions::Instruction>>,
+        edges: &[(usize, usize, EdgeType)],
+    ) -> Self {
+        let mut cfg = Self::new();
+        for instructions in blocks {
+            let id = cfg.new_id();
+            cfg.insert_block(CodeBlockRef::new(CodeBlock {
+                id,
+                instructions,
+                ..Default::default()
+            }));
+        }
+        for &(head, tail, ty) in edges {
+            let block = |id: usize| {
+                cfg.blocks
+                    .iter()
+                    .find(|block| block.borrow().id == id)
+                    .cloned()
+            };
+            if ty == EdgeType::Exception {
+                block(head).unwrap().borrow_mut().handler = Some(tail);
+            }
+            let edge = Edge {
+                head: block(head),
+                tail: block(tail),
+                ty,
+            };
+            cfg.insert_edge(Rc::new(RefCell::new(edge)));
+        }
+        cfg
+    }
+}
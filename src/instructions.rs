@@ -6,20 +6,51 @@ pub enum Instruction {
     LdLocal(u32),
     LdEnv(u32),
     LdStatic(u32),
+    /// Pops a key and an object and pushes the object's field under that
+    /// key, nil if it has none. Keys are integers.
     LdField,
+    /// `LdField` with the key given as operand.
+    LdFieldAt(u32),
     StLocal(u32),
     StEnv(u32),
     StStatic(u32),
+    /// Pops a value, a key and an object and stores the value in the
+    /// object's field under that key.
     StField,
+    /// `StField` with the key given as operand.
+    StFieldAt(u32),
+    /// Pushes a new object without fields.
+    NewObject,
 
     TailCall(u32),
     Call(u32),
+    /// Pops `n` arguments and a closure and calls the closure's function,
+    /// which runs with the environment the closure captured.
+    CallClosure(u32),
+    /// Pushes a closure of function `n` that shares the environment of the
+    /// current frame.
+    MkClosure(u32),
 
     ThreadYield,
 
     Jmp(u32),
     JmpZ(u32),
     JmpNz(u32),
+    /// Pops an integer key and jumps to its target in the block's
+    /// `SwitchTable`, falling through for keys without one.
+    Switch,
+
+    /// Returns the value on top of the stack.
+    Ret,
+    /// Returns nil.
+    RetVoid,
+    /// Aborts execution with the given code.
+    Trap(u32),
+    /// Marks code that can never execute; executing it traps.
+    Unreachable,
+    /// Throws the value on top of the stack to the block's handler, or out
+    /// of the function if it has none.
+    Throw,
 
     Add,
     Sub,
@@ -28,6 +59,24 @@ pub enum Instruction {
     Mod,
     Shr,
     Shl,
+
+    /// Comparisons push 1 when they hold and 0 otherwise.
+    Eq,
+    Lt,
+    Le,
+    Gt,
+    /// Pushes 1 for 0 and 0 for anything else.
+    Not,
+    /// Bitwise, which is the logical operation on 0 and 1.
+    And,
+    Or,
+    Xor,
+    Neg,
+
+    IntToFloat,
+    /// Rounds towards zero, saturating at the bounds of `i64`; NaN gives 0.
+    FloatToInt,
+
     Pop(u32),
     Dup,
 }
@@ -36,9 +85,194 @@ impl Instruction {
     pub fn can_observe_side_effects(&self) -> bool {
         use Instruction::*;
         match self {
-            StEnv(_) | StField | StLocal(_) | StStatic(_) | LdEnv(_) | LdField | LdGlobal(_)
-            | LdLocal(_) | LdStatic(_) => true,
+            StEnv(_) | StField | StFieldAt(_) | StLocal(_) | StStatic(_) | LdEnv(_) | LdField
+            | LdFieldAt(_) | LdGlobal(_) | LdLocal(_) | LdStatic(_) => true,
             _ => false,
         }
     }
+
+    /// Number of values popped and pushed, or `None` when it depends on the
+    /// callee.
+    pub fn stack_effect(&self) -> Option<(u32, u32)> {
+        use Instruction::*;
+        let effect = match self {
+            LdInt(_) | LdFloat(_) | LdGlobal(_) | LdLocal(_) | LdEnv(_) | LdStatic(_) => (0, 1),
+            LdField => (2, 1),
+            LdFieldAt(_) => (1, 1),
+            StLocal(_) | StEnv(_) | StStatic(_) => (1, 0),
+            StField => (3, 0),
+            StFieldAt(_) => (2, 0),
+            NewObject | MkClosure(_) => (0, 1),
+            CallClosure(n) => (*n + 1, 1),
+            TailCall(_) | Call(_) => return None,
+            ThreadYield | Jmp(_) => (0, 0),
+            Ret | Throw => (1, 0),
+            RetVoid | Trap(_) | Unreachable => (0, 0),
+            JmpZ(_) | JmpNz(_) | Switch => (1, 0),
+            Add | Sub | Div | Mul | Mod | Shr | Shl => (2, 1),
+            Eq | Lt | Le | Gt | And | Or | Xor => (2, 1),
+            Not | Neg | IntToFloat | FloatToInt => (1, 1),
+            Pop(n) => (*n, 0),
+            Dup => (1, 2),
+        };
+        Some(effect)
+    }
+
+    /// Instructions that neither trap nor touch anything outside the frame and
+    /// can therefore be removed when their results are unused. Arithmetic and
+    /// comparisons other than `Eq` trap on operands of the wrong type.
+    pub fn is_pure(&self) -> bool {
+        use Instruction::*;
+        matches!(self, LdInt(_) | LdFloat(_) | LdLocal(_) | Eq | Dup)
+    }
+
+    /// Evaluates an integer binary operation. Returns `None` for other
+    /// instructions and for a division or remainder by zero, which traps.
+    pub fn fold_int(&self, lhs: i64, rhs: i64) -> Option<i64> {
+        use Instruction::*;
+        let value = match self {
+            Add => lhs.wrapping_add(rhs),
+            Sub => lhs.wrapping_sub(rhs),
+            Mul => lhs.wrapping_mul(rhs),
+            Div if rhs != 0 => lhs.wrapping_div(rhs),
+            Mod if rhs != 0 => lhs.wrapping_rem(rhs),
+            Shl => lhs.wrapping_shl((rhs & 63) as u32),
+            Shr => lhs.wrapping_shr((rhs & 63) as u32),
+            Eq => (lhs == rhs) as i64,
+            Lt => (lhs < rhs) as i64,
+            Le => (lhs <= rhs) as i64,
+            Gt => (lhs > rhs) as i64,
+            And => lhs & rhs,
+            Or => lhs | rhs,
+            Xor => lhs ^ rhs,
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /// Evaluates an integer unary operation, `None` for other instructions.
+    pub fn fold_unary(&self, value: i64) -> Option<i64> {
+        match self {
+            Instruction::Not => Some((value == 0) as i64),
+            Instruction::Neg => Some(value.wrapping_neg()),
+            _ => None,
+        }
+    }
+
+    /// Binary operations whose operands can be swapped.
+    pub fn is_commutative(&self) -> bool {
+        use Instruction::*;
+        matches!(self, Add | Mul | Eq | And | Or | Xor)
+    }
+
+    pub fn is_branch(&self) -> bool {
+        matches!(
+            self,
+            Instruction::Jmp(_)
+                | Instruction::JmpZ(_)
+                | Instruction::JmpNz(_)
+                | Instruction::Switch
+        )
+    }
+
+    /// Instructions that leave the function, normally or not.
+    pub fn is_return(&self) -> bool {
+        use Instruction::*;
+        matches!(self, Ret | RetVoid | TailCall(_) | Trap(_) | Unreachable)
+    }
+
+    /// Instructions that may only appear last in a block.
+    pub fn is_terminator(&self) -> bool {
+        self.is_branch() || self.is_return() || *self == Instruction::Throw
+    }
+
+    /// Whether control can continue with the next block in line after this
+    /// instruction.
+    pub fn falls_through(&self) -> bool {
+        !matches!(self, Instruction::Jmp(_) | Instruction::Throw) && !self.is_return()
+    }
+
+    /// Instructions that may transfer control to the handler of their block.
+    /// A tail call is not among them, as it leaves the frame first.
+    pub fn may_throw(&self) -> bool {
+        use Instruction::*;
+        matches!(
+            self,
+            Throw
+                | Call(_)
+                | CallClosure(_)
+                | Div
+                | Mod
+                | LdField
+                | LdFieldAt(_)
+                | StField
+                | StFieldAt(_)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::EdgeType::FallThrough;
+    use crate::bytecode::decode::{decode_instruction, Reader};
+    use crate::bytecode::encode::{encode_instruction, Writer};
+    use crate::cfg::ControlFlowGraph;
+    use crate::interpreter::{Interpreter, Value};
+    use crate::module::{Function, Module};
+    use Instruction::{And, Eq, Gt, Le, Lt, Neg, Not, Or, Xor};
+
+    #[test]
+    fn folds_comparisons_and_logic() {
+        let fold = |ins: Instruction| {
+            [(2, 3), (3, 3), (4, 3)]
+                .iter()
+                .map(|(lhs, rhs)| ins.fold_int(*lhs, *rhs).unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(fold(Eq), vec![0, 1, 0]);
+        assert_eq!(fold(Lt), vec![1, 0, 0]);
+        assert_eq!(fold(Le), vec![1, 1, 0]);
+        assert_eq!(fold(Gt), vec![0, 0, 1]);
+        assert_eq!(fold(And), vec![2, 3, 0]);
+        assert_eq!(fold(Or), vec![3, 3, 7]);
+        assert_eq!(fold(Xor), vec![1, 0, 7]);
+
+        assert_eq!(Not.fold_unary(0), Some(1));
+        assert_eq!(Not.fold_unary(-5), Some(0));
+        assert_eq!(Neg.fold_unary(5), Some(-5));
+        assert_eq!(Neg.fold_unary(i64::MIN), Some(i64::MIN));
+    }
+
+    #[test]
+    fn comparison_of_different_kinds() {
+        let eval = |ins: Instruction, lhs: Value, rhs: Value| {
+            let cfg = ControlFlowGraph::from_blocks(
+                vec![vec![Instruction::LdLocal(0), Instruction::LdLocal(1), ins]],
+                &[(0, 2, FallThrough), (2, 1, FallThrough)],
+            );
+            let mut module = Module::new();
+            module.add_function(Function::new("f", 2, cfg));
+            let result = Interpreter::new(&module).call(0, &[lhs, rhs]);
+            result
+        };
+        let (one, nil) = (Value::Int(1), Value::Nil);
+        assert_eq!(eval(Eq, one, one), Ok(Value::Int(1)));
+        assert_eq!(eval(Eq, one, nil), Ok(Value::Int(0)));
+        assert_eq!(eval(Eq, nil, nil), Ok(Value::Int(1)));
+        assert_eq!(eval(Lt, one, Value::Int(2)), Ok(Value::Int(1)));
+        let error = crate::interpreter::Trap::TypeError(Lt);
+        assert_eq!(eval(Lt, one, nil), Err(error));
+    }
+
+    #[test]
+    fn encodes_comparisons_and_logic() {
+        for ins in [Eq, Lt, Le, Gt, Not, And, Or, Xor, Neg].iter() {
+            let mut out = Writer::default();
+            encode_instruction(&mut out, ins, &mut |_| unreachable!());
+            assert_eq!(out.bytes.len(), 1);
+            let decoded = decode_instruction(&mut Reader::new(&out.bytes), &[]);
+            assert_eq!(decoded, Ok(*ins));
+        }
+    }
 }
@@ -0,0 +1,534 @@
+use super::{Trap, Value};
+
+use std::collections::HashMap;
+
+/// Hidden class of an object: the keys of its fields in slot order.
+///
+/// Objects that received the same keys in the same order share a shape.
+/// Adding a field moves an object along a transition to the shape with that
+/// key appended, so the slot of a key never changes once assigned.
+#[derive(Default, Debug)]
+pub struct Shape {
+    pub keys: Vec<i64>,
+    slots: HashMap<i64, u32>,
+    transitions: HashMap<i64, u32>,
+}
+
+impl Shape {
+    pub fn slot(&self, key: i64) -> Option<u32> {
+        self.slots.get(&key).copied()
+    }
+}
+
+#[derive(Clone, PartialEq, Default, Debug)]
+pub struct Object {
+    pub shape: u32,
+    pub slots: Vec<Value>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Cell {
+    Object(Object),
+    /// The environment slots of a frame, addressed by `LdEnv`/`StEnv`.
+    Environment(Vec<Value>),
+    /// A function with the environment it captured.
+    Closure {
+        function: u32,
+        env: Option<u32>,
+    },
+    /// A cell freed by a collection, linking to the next free one.
+    Free(Option<u32>),
+}
+
+/// A shape seen by a field access and where the field was found for it.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct CacheEntry {
+    pub shape: u32,
+    pub key: i64,
+    pub slot: u32,
+    /// Shape of the object after the access, which differs from `shape` for
+    /// stores that add the field.
+    pub to: u32,
+}
+
+/// Inline cache of a single field access instruction.
+///
+/// It holds one entry while the access is monomorphic and up to `MAX_ENTRIES`
+/// while polymorphic. Past that the access is megamorphic and always goes
+/// through the shape's own lookup.
+#[derive(Clone, Default, Debug)]
+pub struct InlineCache {
+    pub entries: Vec<CacheEntry>,
+    pub megamorphic: bool,
+}
+
+impl InlineCache {
+    pub const MAX_ENTRIES: usize = 4;
+
+    pub fn lookup(&self, shape: u32, key: i64) -> Option<CacheEntry> {
+        self.entries
+            .iter()
+            .find(|entry| entry.shape == shape && entry.key == key)
+            .copied()
+    }
+
+    pub fn insert(&mut self, entry: CacheEntry) {
+        if self.megamorphic {
+            return;
+        }
+        if self.entries.len() == Self::MAX_ENTRIES {
+            self.entries.clear();
+            self.megamorphic = true;
+            return;
+        }
+        self.entries.push(entry);
+    }
+
+    pub fn is_monomorphic(&self) -> bool {
+        self.entries.len() == 1 && !self.megamorphic
+    }
+}
+
+/// The cells allocated by an interpreter and the shapes of its objects.
+/// `Value::Object` holds the index of a cell. Shape 0 has no fields.
+///
+/// Memory is reclaimed by `collect`, a mark-and-sweep pass from roots the
+/// caller supplies. Freed cells are reused by later allocations, so the
+/// index of an unreachable object may come back as a different object.
+#[derive(Debug)]
+pub struct Heap {
+    pub cells: Vec<Cell>,
+    pub shapes: Vec<Shape>,
+    free: Option<u32>,
+    /// Number of cells that are not free.
+    pub live: usize,
+    /// `should_collect` holds once `live` reaches this.
+    pub threshold: usize,
+    pub collections: usize,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self {
+            cells: vec![],
+            shapes: vec![Shape::default()],
+            free: None,
+            live: 0,
+            threshold: Self::MIN_THRESHOLD,
+            collections: 0,
+            cache_hits: 0,
+            cache_misses: 0,
+        }
+    }
+}
+
+impl Heap {
+    pub const MIN_THRESHOLD: usize = 1024;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn alloc_cell(&mut self, cell: Cell) -> u32 {
+        self.live += 1;
+        match self.free {
+            Some(n) => {
+                if let Cell::Free(next) = self.cells[n as usize] {
+                    self.free = next;
+                }
+                self.cells[n as usize] = cell;
+                n
+            }
+            None => {
+                self.cells.push(cell);
+                self.cells.len() as u32 - 1
+            }
+        }
+    }
+
+    pub fn alloc(&mut self) -> Value {
+        Value::Object(self.alloc_cell(Cell::Object(Object::default())))
+    }
+
+    /// Allocates an environment of `len` nil slots.
+    pub fn alloc_env(&mut self, len: usize) -> u32 {
+        self.alloc_cell(Cell::Environment(vec![Value::Nil; len]))
+    }
+
+    /// Allocates an environment of `len` slots holding the values of the
+    /// first slots of `env`, nil where it has fewer.
+    pub fn copy_env(&mut self, env: Option<u32>, len: usize) -> u32 {
+        let mut slots = env
+            .and_then(|env| self.env(env))
+            .unwrap_or_default()
+            .to_vec();
+        slots.resize(len, Value::Nil);
+        self.alloc_cell(Cell::Environment(slots))
+    }
+
+    pub fn alloc_closure(&mut self, function: u32, env: Option<u32>) -> Value {
+        Value::Closure(self.alloc_cell(Cell::Closure { function, env }))
+    }
+
+    /// The function and environment of a closure.
+    pub fn closure(&self, closure: Value) -> Option<(u32, Option<u32>)> {
+        match closure {
+            Value::Closure(n) => match self.cells.get(n as usize) {
+                Some(Cell::Closure { function, env }) => Some((*function, *env)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn env(&self, env: u32) -> Option<&[Value]> {
+        match self.cells.get(env as usize) {
+            Some(Cell::Environment(slots)) => Some(slots),
+            _ => None,
+        }
+    }
+
+    pub fn env_mut(&mut self, env: u32) -> Option<&mut [Value]> {
+        match self.cells.get_mut(env as usize) {
+            Some(Cell::Environment(slots)) => Some(slots),
+            _ => None,
+        }
+    }
+
+    pub fn get(&self, object: Value) -> Result<&Object, Trap> {
+        match object {
+            Value::Object(n) => match self.cells.get(n as usize) {
+                Some(Cell::Object(object)) => Ok(object),
+                _ => Err(Trap::NotAnObject),
+            },
+            _ => Err(Trap::NotAnObject),
+        }
+    }
+
+    pub fn get_mut(&mut self, object: Value) -> Result<&mut Object, Trap> {
+        match object {
+            Value::Object(n) => match self.cells.get_mut(n as usize) {
+                Some(Cell::Object(object)) => Ok(object),
+                _ => Err(Trap::NotAnObject),
+            },
+            _ => Err(Trap::NotAnObject),
+        }
+    }
+
+    /// The cell a value refers to.
+    pub fn reference(value: &Value) -> Option<u32> {
+        match value {
+            Value::Object(n) | Value::Closure(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn should_collect(&self) -> bool {
+        self.live >= self.threshold
+    }
+
+    /// Frees every cell not reachable from `roots` and returns how many were
+    /// freed. The next collection is due once the heap has doubled.
+    pub fn collect(&mut self, roots: impl IntoIterator<Item = u32>) -> usize {
+        let mut marked = vec![false; self.cells.len()];
+        let mut stack = roots.into_iter().collect::<Vec<_>>();
+        while let Some(n) = stack.pop() {
+            match marked.get_mut(n as usize) {
+                Some(mark) if !*mark => *mark = true,
+                _ => continue,
+            }
+            let values = match &self.cells[n as usize] {
+                Cell::Object(object) => &object.slots[..],
+                Cell::Environment(slots) => &slots[..],
+                Cell::Closure { env, .. } => {
+                    stack.extend(env);
+                    continue;
+                }
+                Cell::Free(_) => continue,
+            };
+            stack.extend(values.iter().filter_map(Self::reference));
+        }
+
+        let mut freed = 0;
+        for (n, cell) in self.cells.iter_mut().enumerate() {
+            if marked[n] || matches!(cell, Cell::Free(_)) {
+                continue;
+            }
+            *cell = Cell::Free(self.free);
+            self.free = Some(n as u32);
+            freed += 1;
+        }
+        self.live -= freed;
+        self.threshold = std::cmp::max(Self::MIN_THRESHOLD, 2 * self.live);
+        self.collections += 1;
+        freed
+    }
+
+    /// The fields of `object` with their keys, in the order they were added.
+    pub fn fields(&self, object: Value) -> Result<Vec<(i64, Value)>, Trap> {
+        let object = self.get(object)?;
+        let keys = self.shapes[object.shape as usize].keys.iter().copied();
+        Ok(keys.zip(object.slots.iter().copied()).collect())
+    }
+
+    /// The shape reached from `shape` by adding `key`, created on first use.
+    pub fn transition(&mut self, shape: u32, key: i64) -> u32 {
+        if let Some(to) = self.shapes[shape as usize].transitions.get(&key) {
+            return *to;
+        }
+        let mut keys = self.shapes[shape as usize].keys.clone();
+        keys.push(key);
+        let slots = keys
+            .iter()
+            .enumerate()
+            .map(|(slot, key)| (*key, slot as u32))
+            .collect();
+        let to = self.shapes.len() as u32;
+        self.shapes.push(Shape {
+            keys,
+            slots,
+            transitions: HashMap::new(),
+        });
+        self.shapes[shape as usize].transitions.insert(key, to);
+        to
+    }
+
+    /// The field `key` of `object`, nil if it was never stored.
+    pub fn load(
+        &mut self,
+        object: Value,
+        key: i64,
+        cache: &mut InlineCache,
+    ) -> Result<Value, Trap> {
+        let shape = self.get(object)?.shape;
+        let slot = match cache.lookup(shape, key) {
+            Some(entry) => {
+                self.cache_hits += 1;
+                Some(entry.slot)
+            }
+            None => {
+                self.cache_misses += 1;
+                let slot = self.shapes[shape as usize].slot(key);
+                if let Some(slot) = slot {
+                    cache.insert(CacheEntry {
+                        shape,
+                        key,
+                        slot,
+                        to: shape,
+                    });
+                }
+                slot
+            }
+        };
+        let object = self.get(object)?;
+        Ok(slot.map_or(Value::Nil, |slot| object.slots[slot as usize]))
+    }
+
+    pub fn store(
+        &mut self,
+        object: Value,
+        key: i64,
+        value: Value,
+        cache: &mut InlineCache,
+    ) -> Result<(), Trap> {
+        let shape = self.get(object)?.shape;
+        let entry = match cache.lookup(shape, key) {
+            Some(entry) => {
+                self.cache_hits += 1;
+                entry
+            }
+            None => {
+                self.cache_misses += 1;
+                let entry = match self.shapes[shape as usize].slot(key) {
+                    Some(slot) => CacheEntry {
+                        shape,
+                        key,
+                        slot,
+                        to: shape,
+                    },
+                    None => CacheEntry {
+                        shape,
+                        key,
+                        slot: self.shapes[shape as usize].keys.len() as u32,
+                        to: self.transition(shape, key),
+                    },
+                };
+                cache.insert(entry);
+                entry
+            }
+        };
+        let object = self.get_mut(object)?;
+        object.shape = entry.to;
+        if entry.slot as usize == object.slots.len() {
+            object.slots.push(value);
+        } else {
+            object.slots[entry.slot as usize] = value;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::EdgeType::FallThrough;
+    use crate::cfg::ControlFlowGraph;
+    use crate::instructions::Instruction::{
+        self, Dup, LdField, LdFieldAt, LdFloat, LdInt, LdLocal, NewObject, Pop, StEnv, StField,
+        StFieldAt, StLocal, StStatic, ThreadYield,
+    };
+    use crate::interpreter::Interpreter;
+    use crate::module::{Function, Literal, Module};
+
+    fn run(instructions: Vec<Instruction>) -> Result<Value, Trap> {
+        let cfg = ControlFlowGraph::from_blocks(
+            vec![instructions],
+            &[(0, 2, FallThrough), (2, 1, FallThrough)],
+        );
+        let mut module = Module::new();
+        module.add_function(Function::new("f", 0, cfg));
+        Interpreter::new(&module).call(0, &[])
+    }
+
+    #[test]
+    fn fields_default_to_nil() {
+        let (mut heap, cache) = (Heap::new(), &mut InlineCache::default());
+        let object = heap.alloc();
+        let store = &mut InlineCache::default();
+        heap.store(object, 3, Value::Int(5), store).unwrap();
+        assert_eq!(heap.load(object, 3, cache), Ok(Value::Int(5)));
+        assert_eq!(heap.load(object, 4, cache), Ok(Value::Nil));
+        let other = heap.alloc();
+        assert_eq!(heap.load(other, 3, cache), Ok(Value::Nil));
+    }
+
+    #[test]
+    fn fields_of_non_objects_are_rejected() {
+        let (mut heap, cache) = (Heap::new(), &mut InlineCache::default());
+        assert_eq!(heap.load(Value::Int(0), 3, cache), Err(Trap::NotAnObject));
+        let error = heap.store(Value::Object(0), 3, Value::Nil, cache);
+        assert_eq!(error, Err(Trap::NotAnObject));
+
+        let code = vec![NewObject, Dup, LdInt(1), LdInt(5), StField, LdFieldAt(1)];
+        assert_eq!(run(code), Ok(Value::Int(5)));
+        assert_eq!(run(vec![LdInt(1), LdFieldAt(0)]), Err(Trap::NotAnObject));
+        let code = vec![NewObject, LdFloat(0), LdField];
+        assert_eq!(run(code), Err(Trap::TypeError(LdField)));
+    }
+
+    #[test]
+    fn objects_with_same_keys_share_shape() {
+        let (mut heap, cache) = (Heap::new(), &mut InlineCache::default());
+        let objects = [heap.alloc(), heap.alloc(), heap.alloc()];
+        for (object, keys) in objects.iter().zip([[1, 2], [1, 2], [2, 1]].iter()) {
+            for key in keys.iter() {
+                heap.store(*object, *key, Value::Int(*key * 10), cache)
+                    .unwrap();
+            }
+        }
+        let shape = |heap: &Heap, object| heap.get(object).unwrap().shape;
+        assert_eq!(shape(&heap, objects[0]), shape(&heap, objects[1]));
+        assert_ne!(shape(&heap, objects[0]), shape(&heap, objects[2]));
+        // the empty shape, {1}, {1, 2}, {2} and {2, 1}
+        assert_eq!(heap.shapes.len(), 5);
+        assert_eq!(
+            heap.shapes[shape(&heap, objects[2]) as usize].keys,
+            vec![2, 1]
+        );
+
+        heap.store(objects[0], 1, Value::Nil, cache).unwrap();
+        assert_eq!(shape(&heap, objects[0]), shape(&heap, objects[1]));
+        let fields = vec![(1, Value::Nil), (2, Value::Int(20))];
+        assert_eq!(heap.fields(objects[0]), Ok(fields));
+        let fields = vec![(2, Value::Int(20)), (1, Value::Int(10))];
+        assert_eq!(heap.fields(objects[2]), Ok(fields));
+    }
+
+    #[test]
+    fn cache_turns_megamorphic_past_four_shapes() {
+        let mut heap = Heap::new();
+        let objects = (0..5)
+            .map(|key| {
+                let object = heap.alloc();
+                let cache = &mut InlineCache::default();
+                heap.store(object, key, Value::Nil, cache).unwrap();
+                heap.store(object, 9, Value::Int(key), cache).unwrap();
+                object
+            })
+            .collect::<Vec<_>>();
+
+        let mut cache = InlineCache::default();
+        assert_eq!(heap.load(objects[0], 9, &mut cache), Ok(Value::Int(0)));
+        assert!(cache.is_monomorphic());
+        let hits = heap.cache_hits;
+        assert_eq!(heap.load(objects[0], 9, &mut cache), Ok(Value::Int(0)));
+        assert_eq!(heap.cache_hits, hits + 1);
+
+        for (n, object) in objects.iter().enumerate().skip(1) {
+            assert_eq!(heap.load(*object, 9, &mut cache), Ok(Value::Int(n as i64)));
+            assert!(!cache.is_monomorphic());
+            let polymorphic = n < InlineCache::MAX_ENTRIES;
+            assert_eq!(cache.megamorphic, !polymorphic, "{}", n);
+            let entries = if polymorphic { n + 1 } else { 0 };
+            assert_eq!(cache.entries.len(), entries);
+        }
+        let misses = heap.cache_misses;
+        assert_eq!(heap.load(objects[0], 9, &mut cache), Ok(Value::Int(0)));
+        assert_eq!(heap.cache_misses, misses + 1);
+        assert!(cache.entries.is_empty());
+    }
+
+    #[test]
+    fn collection_keeps_objects_reachable_from_roots() {
+        // cells: 0 in a global, 1 the environment, 2 in a local, 3 in the
+        // environment, 4 in a static, 5 dropped, 6 in a field of 2, 7 on the
+        // stack
+        let code = vec![
+            NewObject,
+            StLocal(0),
+            NewObject,
+            StEnv(0),
+            NewObject,
+            StStatic(0),
+            NewObject,
+            Pop(1),
+            LdLocal(0),
+            NewObject,
+            StFieldAt(0),
+            NewObject,
+            ThreadYield,
+        ];
+        let cfg =
+            ControlFlowGraph::from_blocks(vec![code], &[(0, 2, FallThrough), (2, 1, FallThrough)]);
+        let mut function = Function::new("f", 0, cfg);
+        function.locals = 1;
+        function.env = vec!["e".to_string()];
+        let mut module = Module::new();
+        module.add_function(function);
+        module.add_global("g", Literal::Nil);
+        module.add_static("s", Literal::Nil);
+        let mut interpreter = Interpreter::new(&module);
+        interpreter.globals[0] = interpreter.heap.alloc();
+        interpreter.heap.threshold = 0;
+
+        let free = |heap: &Heap| {
+            let cells = heap.cells.iter().enumerate();
+            cells
+                .filter(|(_, cell)| matches!(cell, Cell::Free(_)))
+                .map(|(n, _)| n)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(interpreter.call(0, &[]), Ok(Value::Object(7)));
+        assert_eq!(interpreter.heap.collections, 1);
+        assert_eq!(free(&interpreter.heap), vec![5]);
+
+        // without frames only the global and the static are left as roots
+        assert_eq!(interpreter.collect_garbage(), 5);
+        assert_eq!(free(&interpreter.heap), vec![1, 2, 3, 5, 6, 7]);
+        assert_eq!(interpreter.heap.live, 2);
+        interpreter.heap.alloc();
+        assert_eq!(interpreter.heap.cells.len(), 8);
+    }
+}
@@ -0,0 +1,874 @@
+pub mod heap;
+pub mod scheduler;
+pub mod value;
+
+pub use heap::{CacheEntry, Cell, Heap, InlineCache, Object, Shape};
+pub use scheduler::{Policy, Scheduler, Thread, ThreadId};
+pub use value::Value;
+
+use crate::analysis::cycleanalysis::CycleAnalysis;
+use crate::analysis::saferegion::get_blocks_with_backward_branches;
+use crate::block::*;
+use crate::instructions::Instruction;
+use crate::module::*;
+
+use std::collections::HashMap;
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Trap {
+    UnknownFunction(u32),
+    UnknownThread(ThreadId),
+    ArityMismatch { expected: u32, found: u32 },
+    UnknownBlock(usize),
+    /// Control reached the end of a block that is neither the exit nor has a
+    /// fall through successor.
+    FellOffBlock(usize),
+    InvalidSlot(Instruction),
+    StackUnderflow,
+    StackOverflow,
+    DivisionByZero,
+    /// An operand had the wrong kind of value.
+    TypeError(Instruction),
+    NotAnObject,
+    /// Raised by `Instruction::Trap`.
+    Aborted(u32),
+    Unreachable,
+    /// A thrown value no handler caught.
+    Thrown(Value),
+    /// The fuel budget was used up. Handlers cannot catch this.
+    OutOfFuel,
+}
+
+impl std::fmt::Display for Trap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use Trap::*;
+        match self {
+            UnknownFunction(n) => write!(f, "call to unknown function {}", n),
+            UnknownThread(n) => write!(f, "join of unknown thread {}", n),
+            ArityMismatch { expected, found } => write!(
+                f,
+                "function takes {} arguments but {} were given",
+                expected, found
+            ),
+            UnknownBlock(id) => write!(f, "jump to unknown block {}", id),
+            FellOffBlock(id) => write!(f, "control fell off the end of block {}", id),
+            InvalidSlot(ins) => write!(f, "{:?} refers to a slot that does not exist", ins),
+            StackUnderflow => write!(f, "operand stack underflow"),
+            StackOverflow => write!(f, "call stack overflow"),
+            DivisionByZero => write!(f, "division by zero"),
+            TypeError(ins) => write!(f, "{:?} applied to operands of the wrong type", ins),
+            NotAnObject => write!(f, "field access on a value that is not an object"),
+            Aborted(code) => write!(f, "aborted with code {}", code),
+            Unreachable => write!(f, "reached code marked unreachable"),
+            Thrown(value) => write!(f, "uncaught exception {:?}", value),
+            OutOfFuel => write!(f, "ran out of fuel"),
+        }
+    }
+}
+
+impl std::error::Error for Trap {}
+
+impl Trap {
+    /// Value handed to a handler for `DivisionByZero`.
+    pub const DIVISION_BY_ZERO: i64 = 1;
+    /// Value handed to a handler for `NotAnObject`, raised by field accesses
+    /// on values that are not objects.
+    pub const NOT_AN_OBJECT: i64 = 2;
+
+    /// The value a handler receives for this trap, for the traps that can be
+    /// caught.
+    pub fn exception(&self) -> Option<Value> {
+        match self {
+            Trap::Thrown(value) => Some(*value),
+            Trap::DivisionByZero => Some(Value::Int(Self::DIVISION_BY_ZERO)),
+            Trap::NotAnObject => Some(Value::Int(Self::NOT_AN_OBJECT)),
+            _ => None,
+        }
+    }
+}
+
+struct Block {
+    instructions: Vec<Instruction>,
+    switch: Option<SwitchTable>,
+    handler: Option<usize>,
+    fallthrough: Option<usize>,
+    /// Whether the block ends in a back edge, where fuel is checked.
+    back_edge: bool,
+    /// One per instruction, used by the field accesses.
+    caches: Vec<InlineCache>,
+}
+
+/// A function's blocks keyed by id, copied out of its graph so execution
+/// does not have to go through `RefCell`s.
+struct Code {
+    blocks: HashMap<usize, Block>,
+    entry: usize,
+    exit: usize,
+}
+
+impl Code {
+    fn new(function: &Function) -> Self {
+        let cfg = &function.cfg;
+        let mut ca = CycleAnalysis::new();
+        ca.analyze(cfg);
+        let mut back_edges = get_blocks_with_backward_branches(&ca)
+            .iter()
+            .map(|block| block.borrow().id)
+            .collect::<std::collections::HashSet<_>>();
+        // a loop can also be closed by falling through to its header
+        for edge in ca.all_back_edges() {
+            let edge = edge.borrow();
+            if edge.ty == EdgeType::FallThrough {
+                back_edges.insert(edge.head.as_ref().unwrap().borrow().id);
+            }
+        }
+        let blocks = cfg
+            .blocks
+            .iter()
+            .map(|block| {
+                let block = block.borrow();
+                let fallthrough = block
+                    .out_edges
+                    .iter()
+                    .find(|edge| edge.borrow().ty == EdgeType::FallThrough)
+                    .map(|edge| edge.borrow().tail.as_ref().unwrap().borrow().id);
+                let code = Block {
+                    instructions: block.instructions.clone(),
+                    switch: block.switch.clone(),
+                    handler: block.handler,
+                    fallthrough,
+                    back_edge: back_edges.contains(&block.id),
+                    caches: vec![InlineCache::default(); block.instructions.len()],
+                };
+                (block.id, code)
+            })
+            .collect();
+        Self {
+            blocks,
+            entry: cfg.entry.borrow().id,
+            exit: cfg.exit.borrow().id,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Frame {
+    pub function: u32,
+    pub block: usize,
+    pub pc: usize,
+    pub locals: Vec<Value>,
+    /// Heap cell holding the environment slots, if the function has any.
+    pub env: Option<u32>,
+    pub stack: Vec<Value>,
+}
+
+/// Executes the functions of a module with an explicit frame stack.
+///
+/// A function returns at `Ret`/`RetVoid` or when control runs off the end of
+/// its exit block; in the latter case the result is the value on top of the
+/// operand stack, or `Nil` if it is empty.
+///
+/// Thrown values and the traps in `Trap::exception` unwind to the handler of
+/// the current block, popping frames until one is found.
+///
+/// Every field access instruction has its own `InlineCache` mapping the
+/// shapes it has seen to slots, kept for the lifetime of the interpreter.
+///
+/// A closure gets a copy of the environment of the frame that created it,
+/// which every frame running the closure shares. Other calls start with a
+/// fresh environment.
+///
+/// Threads created with `spawn` each have their own frames and only run
+/// inside `run_threads` or `join`. There `ThreadYield` hands control to the
+/// thread the `scheduler` picks next; in a plain `call` it does not switch.
+///
+/// Execution can be limited with `fuel`, which every instruction uses one unit
+/// of, and threads can be preempted after running for the scheduler's
+/// `quantum`. Both are only acted upon at back edges, calls and handler
+/// entries, so code between those points always runs to completion. A thread
+/// carries its own fuel, an exhausted budget ends it with `Trap::OutOfFuel`.
+///
+/// Objects, closures and environments live on the garbage collected `heap`.
+/// A collection only happens at safe points, `ThreadYield` and calls, with the
+/// globals, statics, the results of finished threads and the locals,
+/// environments and operand stacks of the frames of all threads as roots.
+/// Values kept outside of the interpreter are not roots.
+pub struct Interpreter<'a> {
+    pub module: &'a Module,
+    pub globals: Vec<Value>,
+    pub statics: Vec<Value>,
+    pub heap: Heap,
+    /// Frames of the running thread.
+    pub frames: Vec<Frame>,
+    pub max_frames: usize,
+    /// Instructions the running code may still execute, None for no limit.
+    pub fuel: Option<u64>,
+    pub scheduler: Scheduler,
+    code: Vec<Code>,
+    yielded: bool,
+    /// What is left of the running thread's quantum.
+    slice: Option<u64>,
+}
+
+impl<'a> Interpreter<'a> {
+    pub fn new(module: &'a Module) -> Self {
+        Self {
+            module,
+            globals: module.globals.iter().map(|v| v.init.into()).collect(),
+            statics: module.statics.iter().map(|v| v.init.into()).collect(),
+            heap: Heap::new(),
+            frames: vec![],
+            max_frames: 10_000,
+            fuel: None,
+            scheduler: Scheduler::default(),
+            code: module.functions.iter().map(Code::new).collect(),
+            yielded: false,
+            slice: None,
+        }
+    }
+
+    pub fn call(&mut self, function: u32, args: &[Value]) -> Result<Value, Trap> {
+        let base = self.frames.len();
+        let result = self
+            .push_frame(function, args.to_vec(), None)
+            .and_then(|_| self.run(base));
+        if result.is_err() {
+            self.frames.truncate(base);
+        }
+        // only threads yield
+        result.map(Option::unwrap_or_default)
+    }
+
+    /// Creates a thread that will call `function` with `args`.
+    pub fn spawn(&mut self, function: u32, args: &[Value]) -> Result<ThreadId, Trap> {
+        let frames = std::mem::take(&mut self.frames);
+        let result = self.push_frame(function, args.to_vec(), None);
+        let thread = std::mem::replace(&mut self.frames, frames);
+        result?;
+        Ok(self.scheduler.spawn(thread))
+    }
+
+    /// Runs threads until every one of them has finished.
+    pub fn run_threads(&mut self) {
+        while self.run_next() {}
+    }
+
+    /// Runs threads until `thread` has finished and returns its result.
+    pub fn join(&mut self, thread: ThreadId) -> Result<Value, Trap> {
+        if thread >= self.scheduler.threads.len() {
+            return Err(Trap::UnknownThread(thread));
+        }
+        while !self.scheduler.is_finished(thread) && self.run_next() {}
+        self.scheduler.threads[thread].result.clone().unwrap()
+    }
+
+    /// Runs the thread the scheduler picks until it yields or finishes.
+    /// Returns false if there was none left to run.
+    fn run_next(&mut self) -> bool {
+        let id = match self.scheduler.pick() {
+            Some(id) => id,
+            None => return false,
+        };
+        self.scheduler.current = Some(id);
+        self.slice = self.scheduler.quantum;
+        let thread = &mut self.scheduler.threads[id];
+        std::mem::swap(&mut self.frames, &mut thread.frames);
+        std::mem::swap(&mut self.fuel, &mut thread.fuel);
+        let result = self.run(0);
+        if result.is_err() {
+            self.frames.clear();
+        }
+        let thread = &mut self.scheduler.threads[id];
+        std::mem::swap(&mut self.frames, &mut thread.frames);
+        std::mem::swap(&mut self.fuel, &mut thread.fuel);
+        self.scheduler.current = None;
+        self.slice = None;
+        self.scheduler.threads[id].result = result.transpose();
+        true
+    }
+
+    /// Executes frames above `base` until the one at `base` returns. Returns
+    /// None if a thread yielded first.
+    fn run(&mut self, base: usize) -> Result<Option<Value>, Trap> {
+        loop {
+            let result = match self.step() {
+                Ok(result) => result,
+                Err(trap) => {
+                    self.unwind(base, trap)?;
+                    None
+                }
+            };
+            if let Some(value) = result {
+                if self.frames.len() == base {
+                    return Ok(Some(value));
+                }
+                self.frames.last_mut().unwrap().stack.push(value);
+            } else if std::mem::take(&mut self.yielded) {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Continues at the handler of the innermost block above `base` that has
+    /// one, with the exception as the only value on its stack. Frames without
+    /// a handler are popped; the trap is returned if nothing catches it.
+    /// Entering a handler is a preemption point, since a handler can loop
+    /// back into the code it protects.
+    fn unwind(&mut self, base: usize, trap: Trap) -> Result<(), Trap> {
+        let value = match trap.exception() {
+            Some(value) => value,
+            None => return Err(trap),
+        };
+        while self.frames.len() > base {
+            let frame = self.frames.last_mut().unwrap();
+            let handler = self.code[frame.function as usize]
+                .blocks
+                .get(&frame.block)
+                .and_then(|block| block.handler);
+            if let Some(handler) = handler {
+                frame.block = handler;
+                frame.pc = 0;
+                frame.stack.clear();
+                frame.stack.push(value);
+                return self.preemption_point();
+            }
+            self.frames.pop();
+        }
+        Err(trap)
+    }
+
+    /// Enters function `n` with the environment `env`, or a new one if none is
+    /// given.
+    fn push_frame(&mut self, n: u32, args: Vec<Value>, env: Option<u32>) -> Result<(), Trap> {
+        let function = self.module.resolve(n).ok_or(Trap::UnknownFunction(n))?;
+        if args.len() != function.params as usize {
+            return Err(Trap::ArityMismatch {
+                expected: function.params,
+                found: args.len() as u32,
+            });
+        }
+        if self.frames.len() >= self.max_frames {
+            return Err(Trap::StackOverflow);
+        }
+        let mut locals = args;
+        locals.resize(function.locals as usize, Value::Nil);
+        let env = match (env, function.env.len()) {
+            (Some(env), _) => Some(env),
+            (None, 0) => None,
+            (None, len) => Some(self.heap.alloc_env(len)),
+        };
+        self.frames.push(Frame {
+            function: n,
+            block: self.code[n as usize].entry,
+            pc: 0,
+            locals,
+            env,
+            stack: vec![],
+        });
+        Ok(())
+    }
+
+    /// The heap cells referenced from outside the heap.
+    pub fn roots(&self) -> Vec<u32> {
+        let mut roots = vec![];
+        let values = self.globals.iter().chain(self.statics.iter());
+        roots.extend(values.filter_map(Heap::reference));
+        let threads = self.scheduler.threads.iter();
+        for frame in self.frames.iter().chain(threads.flat_map(|thread| thread.frames.iter())) {
+            let values = frame.locals.iter().chain(frame.stack.iter());
+            roots.extend(values.filter_map(Heap::reference));
+            roots.extend(frame.env);
+        }
+        for thread in self.scheduler.threads.iter() {
+            if let Some(Ok(value)) = thread.result {
+                roots.extend(Heap::reference(&value));
+            }
+        }
+        roots
+    }
+
+    pub fn collect_garbage(&mut self) -> usize {
+        let roots = self.roots();
+        self.heap.collect(roots)
+    }
+
+    fn safe_point(&mut self) {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+    }
+
+    /// Reached at back edges, calls and handler entries, the only places where
+    /// running out of fuel or quantum takes effect.
+    fn preemption_point(&mut self) -> Result<(), Trap> {
+        if self.fuel == Some(0) {
+            return Err(Trap::OutOfFuel);
+        }
+        if self.slice == Some(0) && self.scheduler.current.is_some() {
+            self.yielded = true;
+        }
+        Ok(())
+    }
+
+    /// Executes one instruction of the innermost frame, or moves it to the
+    /// next block. Returns the result when the frame returns.
+    fn step(&mut self) -> Result<Option<Value>, Trap> {
+        use crate::interpreter::Trap;
+        use Instruction::*;
+        let frame = self.frames.last_mut().unwrap();
+        let code = &mut self.code[frame.function as usize];
+        let block = code
+            .blocks
+            .get_mut(&frame.block)
+            .ok_or(Trap::UnknownBlock(frame.block))?;
+        let ins = match block.instructions.get(frame.pc) {
+            Some(ins) => *ins,
+            None => {
+                if let Some(next) = block.fallthrough {
+                    let back_edge = block.back_edge;
+                    frame.block = next;
+                    frame.pc = 0;
+                    if back_edge {
+                        self.preemption_point()?;
+                    }
+                    return Ok(None);
+                }
+                if frame.block != code.exit {
+                    return Err(Trap::FellOffBlock(frame.block));
+                }
+                let frame = self.frames.pop().unwrap();
+                return Ok(Some(frame.stack.last().copied().unwrap_or_default()));
+            }
+        };
+        let back_edge = block.back_edge && frame.pc + 1 == block.instructions.len();
+        let cache = &mut block.caches[frame.pc];
+        frame.pc += 1;
+        for left in self.fuel.iter_mut().chain(self.slice.iter_mut()) {
+            *left = left.saturating_sub(1);
+        }
+
+        let stack = &mut frame.stack;
+        match ins {
+            LdInt(value) => stack.push(Value::Int(value)),
+            LdFloat(bits) => stack.push(Value::Float(f64::from_bits(bits))),
+            LdGlobal(n) => stack.push(*self.globals.get(n as usize).ok_or(Trap::InvalidSlot(ins))?),
+            LdLocal(n) => stack.push(*frame.locals.get(n as usize).ok_or(Trap::InvalidSlot(ins))?),
+            LdEnv(n) => {
+                let heap = &self.heap;
+                let slots = frame.env.and_then(|env| heap.env(env)).unwrap_or_default();
+                stack.push(*slots.get(n as usize).ok_or(Trap::InvalidSlot(ins))?);
+            }
+            LdStatic(n) => stack.push(*self.statics.get(n as usize).ok_or(Trap::InvalidSlot(ins))?),
+            StLocal(n) => {
+                let value = pop(stack)?;
+                *frame.locals.get_mut(n as usize).ok_or(Trap::InvalidSlot(ins))? = value;
+            }
+            StEnv(n) => {
+                let value = pop(stack)?;
+                let heap = &mut self.heap;
+                let slots = frame.env.and_then(|env| heap.env_mut(env)).unwrap_or_default();
+                *slots.get_mut(n as usize).ok_or(Trap::InvalidSlot(ins))? = value;
+            }
+            StStatic(n) => {
+                let value = pop(stack)?;
+                *self.statics.get_mut(n as usize).ok_or(Trap::InvalidSlot(ins))? = value;
+            }
+            LdField => {
+                let key = pop_key(stack, ins)?;
+                let object = pop(stack)?;
+                stack.push(self.heap.load(object, key, cache)?);
+            }
+            LdFieldAt(key) => {
+                let object = pop(stack)?;
+                stack.push(self.heap.load(object, key as i64, cache)?);
+            }
+            StField => {
+                let value = pop(stack)?;
+                let key = pop_key(stack, ins)?;
+                let object = pop(stack)?;
+                self.heap.store(object, key, value, cache)?;
+            }
+            StFieldAt(key) => {
+                let value = pop(stack)?;
+                let object = pop(stack)?;
+                self.heap.store(object, key as i64, value, cache)?;
+            }
+            NewObject => stack.push(self.heap.alloc()),
+            Call(n) | TailCall(n) => {
+                let function = self.module.resolve(n).ok_or(Trap::UnknownFunction(n))?;
+                let args = pop_n(stack, function.params as usize)?;
+                if let TailCall(_) = ins {
+                    self.frames.pop();
+                }
+                self.push_frame(n, args, None)?;
+                self.safe_point();
+                self.preemption_point()?;
+            }
+            CallClosure(argc) => {
+                let args = pop_n(stack, argc as usize)?;
+                let closure = pop(stack)?;
+                let (function, env) = self.heap.closure(closure).ok_or(Trap::TypeError(ins))?;
+                self.push_frame(function, args, env)?;
+                self.safe_point();
+                self.preemption_point()?;
+            }
+            MkClosure(n) => {
+                let closure = self.module.resolve(n).ok_or(Trap::UnknownFunction(n))?;
+                let env = match closure.env.len() {
+                    0 => None,
+                    len => Some(self.heap.copy_env(frame.env, len)),
+                };
+                stack.push(self.heap.alloc_closure(n, env));
+            }
+            ThreadYield => {
+                self.yielded = self.scheduler.current.is_some();
+                self.safe_point();
+            }
+            Jmp(target) => {
+                frame.block = target as usize;
+                frame.pc = 0;
+            }
+            JmpZ(target) | JmpNz(target) => {
+                let value = match pop(stack)? {
+                    Value::Int(value) => value,
+                    _ => return Err(Trap::TypeError(ins)),
+                };
+                if (value == 0) == matches!(ins, JmpZ(_)) {
+                    frame.block = target as usize;
+                    frame.pc = 0;
+                }
+            }
+            Switch => {
+                let key = match pop(stack)? {
+                    Value::Int(key) => key,
+                    _ => return Err(Trap::TypeError(ins)),
+                };
+                if let Some(target) = block.switch.as_ref().and_then(|table| table.target(key)) {
+                    frame.block = target as usize;
+                    frame.pc = 0;
+                }
+            }
+            Add | Sub | Div | Mul | Mod | Shr | Shl | Eq | Lt | Le | Gt | And | Or | Xor => {
+                let rhs = pop(stack)?;
+                let lhs = pop(stack)?;
+                stack.push(Value::binary(ins, lhs, rhs)?);
+            }
+            Not | Neg | IntToFloat | FloatToInt => {
+                let value = pop(stack)?;
+                stack.push(Value::unary(ins, value)?);
+            }
+            Ret => {
+                let value = pop(stack)?;
+                self.frames.pop();
+                return Ok(Some(value));
+            }
+            RetVoid => {
+                self.frames.pop();
+                return Ok(Some(Value::Nil));
+            }
+            Instruction::Trap(code) => return Err(Trap::Aborted(code)),
+            Unreachable => return Err(Trap::Unreachable),
+            Throw => return Err(Trap::Thrown(pop(stack)?)),
+            Pop(n) => {
+                pop_n(stack, n as usize)?;
+            }
+            Dup => {
+                let value = *stack.last().ok_or(Trap::StackUnderflow)?;
+                stack.push(value);
+            }
+        }
+        if back_edge && matches!(ins, Jmp(_) | JmpZ(_) | JmpNz(_) | Switch) {
+            self.preemption_point()?;
+        }
+        Ok(None)
+    }
+}
+
+fn pop(stack: &mut Vec<Value>) -> Result<Value, Trap> {
+    stack.pop().ok_or(Trap::StackUnderflow)
+}
+
+fn pop_key(stack: &mut Vec<Value>, ins: Instruction) -> Result<i64, Trap> {
+    match pop(stack)? {
+        Value::Int(key) => Ok(key),
+        _ => Err(Trap::TypeError(ins)),
+    }
+}
+
+fn pop_n(stack: &mut Vec<Value>, n: usize) -> Result<Vec<Value>, Trap> {
+    if stack.len() < n {
+        return Err(Trap::StackUnderflow);
+    }
+    Ok(stack.split_off(stack.len() - n))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cfg::ControlFlowGraph;
+    use EdgeType::*;
+    use Instruction::{
+        Add, Call, CallClosure, Div, Dup, Jmp, JmpZ, LdEnv, LdInt, LdLocal, MkClosure, Mul, Pop,
+        Ret, StEnv, StLocal, Sub, Throw,
+    };
+
+    // sum(n): acc = 0; while n != 0 { acc += n; n -= 1 }; return acc
+    fn sum() -> Function {
+        let cfg = ControlFlowGraph::from_blocks(
+            vec![
+                vec![LdInt(0), StLocal(1)],
+                vec![LdLocal(0), JmpZ(5)],
+                vec![
+                    LdLocal(1),
+                    LdLocal(0),
+                    Add,
+                    StLocal(1),
+                    LdLocal(0),
+                    LdInt(1),
+                    Sub,
+                    StLocal(0),
+                    Jmp(3),
+                ],
+                vec![LdLocal(1)],
+            ],
+            &[
+                (0, 2, FallThrough),
+                (2, 3, FallThrough),
+                (3, 5, Branch),
+                (3, 4, FallThrough),
+                (4, 3, Branch),
+                (5, 1, FallThrough),
+            ],
+        );
+        Function::new("sum", 1, cfg)
+    }
+
+    fn straight_line(params: u32, instructions: Vec<Instruction>) -> Function {
+        let cfg = ControlFlowGraph::from_blocks(
+            vec![instructions],
+            &[(0, 2, FallThrough), (2, 1, FallThrough)],
+        );
+        Function::new("f", params, cfg)
+    }
+
+    #[test]
+    fn runs_loops_and_calls() {
+        let mut module = Module::new();
+        module.add_function(sum());
+        module.add_function(straight_line(0, vec![LdInt(4), Call(0), LdInt(2), Mul]));
+        let mut interpreter = Interpreter::new(&module);
+
+        assert_eq!(interpreter.call(0, &[Value::Int(3)]), Ok(Value::Int(6)));
+        assert_eq!(interpreter.call(1, &[]), Ok(Value::Int(20)));
+        assert!(interpreter.frames.is_empty());
+    }
+
+    #[test]
+    fn traps_on_bad_operands() {
+        let mut module = Module::new();
+        module.add_function(straight_line(2, vec![LdLocal(0), LdLocal(1), Div]));
+        let mut interpreter = Interpreter::new(&module);
+
+        let result = interpreter.call(0, &[Value::Int(1), Value::Int(0)]);
+        assert_eq!(result, Err(Trap::DivisionByZero));
+        let result = interpreter.call(0, &[Value::Float(1.0), Value::Int(1)]);
+        assert_eq!(result, Err(Trap::TypeError(Div)));
+        let result = interpreter.call(0, &[Value::Int(1)]);
+        let arity = Trap::ArityMismatch {
+            expected: 2,
+            found: 1,
+        };
+        assert_eq!(result, Err(arity));
+        assert!(interpreter.frames.is_empty());
+    }
+
+    #[test]
+    fn closures_copy_the_environment() {
+        // f: a = 1; c = || { a += 10; a }; a = 2; return c() + c() * 100 + a * 10000
+        let f = vec![
+            LdInt(1),
+            StEnv(0),
+            MkClosure(1),
+            StLocal(0),
+            LdInt(2),
+            StEnv(0),
+            LdLocal(0),
+            CallClosure(0),
+            LdLocal(0),
+            CallClosure(0),
+            LdInt(100),
+            Mul,
+            Add,
+            LdEnv(0),
+            LdInt(10000),
+            Mul,
+            Add,
+        ];
+        let closure = vec![LdEnv(0), LdInt(10), Add, Dup, StEnv(0)];
+        let mut module = Module::new();
+        module.add_function(straight_line(0, f));
+        module.add_function(straight_line(0, closure));
+        for function in module.functions.iter_mut() {
+            function.env = vec!["a".to_string()];
+        }
+        module.functions[0].locals = 1;
+        assert_eq!(module.verify(), Ok(()));
+
+        let mut interpreter = Interpreter::new(&module);
+        assert_eq!(interpreter.call(0, &[]), Ok(Value::Int(22111)));
+    }
+
+    #[test]
+    fn unbounded_recursion_overflows() {
+        let mut module = Module::new();
+        module.add_function(straight_line(0, vec![Call(0)]));
+        let mut interpreter = Interpreter::new(&module);
+        interpreter.max_frames = 100;
+
+        assert_eq!(interpreter.call(0, &[]), Err(Trap::StackOverflow));
+        assert!(interpreter.frames.is_empty());
+    }
+
+    #[test]
+    fn returns_and_traps_leave_function() {
+        // f(a): if a == 0 { trap 7 } return 1
+        let cfg = ControlFlowGraph::from_blocks(
+            vec![
+                vec![LdLocal(0), JmpZ(4)],
+                vec![LdInt(1), Ret],
+                vec![LdLocal(0), Instruction::Trap(7)],
+            ],
+            &[
+                (0, 2, FallThrough),
+                (2, 4, Branch),
+                (2, 3, FallThrough),
+                (3, 1, Return),
+                (4, 1, Return),
+            ],
+        );
+        let mut module = Module::new();
+        module.add_function(Function::new("f", 1, cfg));
+        assert_eq!(module.verify(), Ok(()));
+        let mut interpreter = Interpreter::new(&module);
+
+        assert_eq!(interpreter.call(0, &[Value::Int(5)]), Ok(Value::Int(1)));
+        assert_eq!(interpreter.call(0, &[Value::Int(0)]), Err(Trap::Aborted(7)));
+        assert!(interpreter.frames.is_empty());
+    }
+
+    #[test]
+    fn handler_catches_what_callee_throws() {
+        // f(a): try { return g(a) } catch (e) { return e + 100 }
+        let cfg = ControlFlowGraph::from_blocks(
+            vec![vec![LdLocal(0), Call(1), Ret], vec![LdInt(100), Add, Ret]],
+            &[
+                (0, 2, FallThrough),
+                (2, 3, Exception),
+                (2, 1, Return),
+                (3, 1, Return),
+            ],
+        );
+        let mut module = Module::new();
+        module.add_function(Function::new("f", 1, cfg));
+        // g(a): if a == 0 { throw 7 } return 10 / (a - 1)
+        let cfg = ControlFlowGraph::from_blocks(
+            vec![
+                vec![LdLocal(0), JmpZ(3)],
+                vec![LdInt(7), Throw],
+                vec![LdInt(10), LdLocal(0), LdInt(1), Sub, Div, Ret],
+            ],
+            &[
+                (0, 2, FallThrough),
+                (2, 3, Branch),
+                (2, 4, FallThrough),
+                (3, 1, Return),
+                (4, 1, Return),
+            ],
+        );
+        module.add_function(Function::new("g", 1, cfg));
+        assert_eq!(module.verify(), Ok(()));
+        let mut interpreter = Interpreter::new(&module);
+
+        assert_eq!(interpreter.call(0, &[Value::Int(3)]), Ok(Value::Int(5)));
+        assert_eq!(interpreter.call(0, &[Value::Int(0)]), Ok(Value::Int(107)));
+        let caught = Trap::DIVISION_BY_ZERO + 100;
+        assert_eq!(
+            interpreter.call(0, &[Value::Int(1)]),
+            Ok(Value::Int(caught))
+        );
+        let thrown = Err(Trap::Thrown(Value::Int(7)));
+        assert_eq!(interpreter.call(1, &[Value::Int(0)]), thrown);
+        assert!(interpreter.frames.is_empty());
+    }
+
+    #[test]
+    fn fuel_is_checked_at_back_edges_and_calls() {
+        let mut module = Module::new();
+        module.add_function(sum());
+        module.add_function(straight_line(
+            0,
+            vec![LdInt(1), LdInt(2), Add, LdInt(3), Mul],
+        ));
+        let mut interpreter = Interpreter::new(&module);
+
+        // without back edges or calls the code runs to completion
+        interpreter.fuel = Some(2);
+        assert_eq!(interpreter.call(1, &[]), Ok(Value::Int(9)));
+        interpreter.fuel = Some(100);
+        let result = interpreter.call(0, &[Value::Int(1000)]);
+        assert_eq!(result, Err(Trap::OutOfFuel));
+        assert!(interpreter.frames.is_empty());
+        interpreter.fuel = None;
+        let result = interpreter.call(0, &[Value::Int(1000)]);
+        assert_eq!(result, Ok(Value::Int(500500)));
+    }
+
+    #[test]
+    fn quantum_preempts_threads_in_loops() {
+        let mut module = Module::new();
+        module.add_function(sum());
+        let mut interpreter = Interpreter::new(&module);
+        interpreter.scheduler.quantum = Some(20);
+        for n in [100, 200].iter() {
+            interpreter.spawn(0, &[Value::Int(*n)]).unwrap();
+        }
+
+        assert_eq!(interpreter.join(0), Ok(Value::Int(5050)));
+        assert_eq!(interpreter.join(1), Ok(Value::Int(20100)));
+        assert!(interpreter.scheduler.switches > 10);
+    }
+
+    // f: throw 0, caught by 3, which throws 1 and catches it itself
+    fn spin() -> Module {
+        let cfg = ControlFlowGraph::from_blocks(
+            vec![vec![LdInt(0), Throw], vec![Pop(1), LdInt(1), Throw]],
+            &[(0, 2, FallThrough), (2, 3, Exception), (3, 3, Exception)],
+        );
+        let mut module = Module::new();
+        module.add_function(Function::new("f", 0, cfg));
+        module
+    }
+
+    #[test]
+    fn handler_loop_runs_out_of_fuel() {
+        let module = spin();
+        assert_eq!(module.verify(), Ok(()));
+        let mut interpreter = Interpreter::new(&module);
+        interpreter.fuel = Some(1000);
+        assert_eq!(interpreter.call(0, &[]), Err(Trap::OutOfFuel));
+        assert!(interpreter.frames.is_empty());
+    }
+
+    #[test]
+    fn handler_loop_is_preempted() {
+        let module = spin();
+        let mut interpreter = Interpreter::new(&module);
+        interpreter.scheduler.quantum = Some(10);
+        for _ in 0..2 {
+            let thread = interpreter.spawn(0, &[]).unwrap();
+            interpreter.scheduler.threads[thread].fuel = Some(1000);
+        }
+        assert_eq!(interpreter.join(0), Err(Trap::OutOfFuel));
+        assert!(interpreter.scheduler.switches > 2);
+    }
+}
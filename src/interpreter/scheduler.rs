@@ -0,0 +1,210 @@
+use super::{Frame, Trap, Value};
+
+pub type ThreadId = usize;
+
+/// How the scheduler picks the thread to run next.
+#[derive(Copy, Clone, PartialEq, Eq, Default, Debug)]
+pub enum Policy {
+    #[default]
+    /// Runnable threads take turns in the order they were spawned.
+    RoundRobin,
+    /// A runnable thread with the highest priority runs, threads of equal
+    /// priority take turns.
+    Priority,
+    /// Any runnable thread may run next. The choice comes from a generator
+    /// seeded with `seed`, so a seed always produces the same interleaving.
+    Random { seed: u64 },
+}
+
+/// A lightweight thread: a call stack of its own, run by the interpreter
+/// until it executes `ThreadYield` or finishes.
+#[derive(Debug)]
+pub struct Thread {
+    pub priority: i32,
+    pub frames: Vec<Frame>,
+    /// Instructions the thread may still execute, None for no limit.
+    pub fuel: Option<u64>,
+    /// What the thread's function returned, once it has finished.
+    pub result: Option<Result<Value, Trap>>,
+}
+
+impl Thread {
+    pub fn is_finished(&self) -> bool {
+        self.result.is_some()
+    }
+}
+
+/// The threads of an interpreter and the policy choosing between them.
+#[derive(Default, Debug)]
+pub struct Scheduler {
+    pub threads: Vec<Thread>,
+    /// The running thread. Its frames are the interpreter's while it runs.
+    pub current: Option<ThreadId>,
+    /// Number of times a different thread than the last one was picked.
+    pub switches: usize,
+    /// Instructions a thread runs before it is preempted at the next back
+    /// edge, call or handler entry. None lets a thread run until it yields.
+    pub quantum: Option<u64>,
+    policy: Policy,
+    last: Option<ThreadId>,
+    rng: u64,
+}
+
+impl Scheduler {
+    pub fn new(policy: Policy) -> Self {
+        let rng = match policy {
+            Policy::Random { seed } => seed,
+            _ => 0,
+        };
+        Self {
+            policy,
+            rng,
+            ..Default::default()
+        }
+    }
+
+    pub fn policy(&self) -> Policy {
+        self.policy
+    }
+
+    pub fn spawn(&mut self, frames: Vec<Frame>) -> ThreadId {
+        self.threads.push(Thread {
+            priority: 0,
+            frames,
+            fuel: None,
+            result: None,
+        });
+        self.threads.len() - 1
+    }
+
+    pub fn is_finished(&self, thread: ThreadId) -> bool {
+        self.threads
+            .get(thread)
+            .is_some_and(|thread| thread.is_finished())
+    }
+
+    /// Picks the thread to run next, None once every thread has finished.
+    pub fn pick(&mut self) -> Option<ThreadId> {
+        let n = self.threads.len();
+        let start = self.last.map_or(0, |last| last + 1);
+        // runnable threads, starting after the one that ran last
+        let mut runnable = (0..n)
+            .map(|i| (start + i) % n)
+            .filter(|id| !self.threads[*id].is_finished());
+        let next = match self.policy {
+            Policy::RoundRobin => runnable.next(),
+            Policy::Priority => runnable.fold(None, |best: Option<ThreadId>, id| match best {
+                Some(best) if self.threads[best].priority >= self.threads[id].priority => {
+                    Some(best)
+                }
+                _ => Some(id),
+            }),
+            Policy::Random { .. } => {
+                let runnable = runnable.collect::<Vec<_>>();
+                if runnable.is_empty() {
+                    None
+                } else {
+                    let pick = self.random() % runnable.len() as u64;
+                    Some(runnable[pick as usize])
+                }
+            }
+        }?;
+        if self.last != Some(next) {
+            self.switches += 1;
+        }
+        self.last = Some(next);
+        Some(next)
+    }
+
+    // splitmix64
+    fn random(&mut self) -> u64 {
+        self.rng = self.rng.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::EdgeType::{FallThrough, Return};
+    use crate::cfg::ControlFlowGraph;
+    use crate::instructions::Instruction::{
+        Add, LdInt, LdLocal, LdStatic, Mul, Ret, StStatic, ThreadYield,
+    };
+    use crate::interpreter::Interpreter;
+    use crate::module::{Function, Literal, Module};
+
+    // Runs threads 1, 2 and 3, which each append their number to a static
+    // three times and yield after each, and returns the digits in order.
+    fn interleaving(policy: Policy, priorities: [i32; 3]) -> i64 {
+        let step = [
+            LdStatic(0),
+            LdInt(10),
+            Mul,
+            LdLocal(0),
+            Add,
+            StStatic(0),
+            ThreadYield,
+        ];
+        let body = [&step[..], &step[..], &step[..], &[LdLocal(0), Ret]].concat();
+        let cfg = ControlFlowGraph::from_blocks(vec![body], &[(0, 2, FallThrough), (2, 1, Return)]);
+        let mut module = Module::new();
+        module.add_function(Function::new("f", 1, cfg));
+        module.add_static("log", Literal::Int(0));
+        let mut interpreter = Interpreter::new(&module);
+        interpreter.scheduler = Scheduler::new(policy);
+        for (n, priority) in (1..=3).zip(priorities.iter()) {
+            let thread = interpreter.spawn(0, &[Value::Int(n)]).unwrap();
+            interpreter.scheduler.threads[thread].priority = *priority;
+        }
+        interpreter.run_threads();
+        for (thread, n) in (0..3).zip(1..) {
+            assert_eq!(interpreter.join(thread), Ok(Value::Int(n)));
+        }
+        match interpreter.statics[0] {
+            Value::Int(log) => log,
+            value => panic!("log is {:?}", value),
+        }
+    }
+
+    #[test]
+    fn round_robin_takes_turns() {
+        assert_eq!(interleaving(Policy::RoundRobin, [0; 3]), 123_123_123);
+    }
+
+    #[test]
+    fn priority_runs_highest_first_and_rotates_ties() {
+        assert_eq!(interleaving(Policy::Priority, [0, 5, 5]), 232_323_111);
+    }
+
+    #[test]
+    fn random_is_reproducible_from_seed() {
+        let logs = (0..8)
+            .map(|seed| interleaving(Policy::Random { seed }, [0; 3]))
+            .collect::<Vec<_>>();
+        for (seed, log) in (0..8).zip(logs.iter()) {
+            assert_eq!(interleaving(Policy::Random { seed }, [0; 3]), *log);
+            let mut digits = log.to_string().chars().collect::<Vec<_>>();
+            digits.sort_unstable();
+            assert_eq!(digits.into_iter().collect::<String>(), "111222333");
+        }
+        assert!(logs.iter().any(|log| *log != logs[0]));
+    }
+
+    #[test]
+    fn pick_skips_finished_threads() {
+        let mut scheduler = Scheduler::new(Policy::RoundRobin);
+        for _ in 0..3 {
+            scheduler.spawn(vec![]);
+        }
+        scheduler.threads[1].result = Some(Ok(Value::Nil));
+        let picks = (0..4).map(|_| scheduler.pick()).collect::<Vec<_>>();
+        assert_eq!(picks, vec![Some(0), Some(2), Some(0), Some(2)]);
+        scheduler.threads[0].result = Some(Ok(Value::Nil));
+        scheduler.threads[2].result = Some(Ok(Value::Nil));
+        assert_eq!(scheduler.pick(), None);
+    }
+}
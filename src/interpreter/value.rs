@@ -0,0 +1,186 @@
+use super::Trap;
+use crate::instructions::Instruction;
+use crate::module::Literal;
+
+use std::convert::TryFrom;
+
+#[derive(Copy, Clone, PartialEq, Default, Debug)]
+pub enum Value {
+    #[default]
+    Nil,
+    Int(i64),
+    Float(f64),
+    /// Index of an object on the interpreter's heap. Objects are equal only
+    /// to themselves.
+    Object(u32),
+    /// Index of a closure on the interpreter's heap, compared like objects.
+    Closure(u32),
+}
+
+impl From<Literal> for Value {
+    fn from(literal: Literal) -> Self {
+        match literal {
+            Literal::Nil => Value::Nil,
+            Literal::Int(value) => Value::Int(value),
+            Literal::Float(bits) => Value::Float(f64::from_bits(bits)),
+        }
+    }
+}
+
+/// Objects and closures only exist at run time and have no literal.
+impl TryFrom<Value> for Literal {
+    type Error = Value;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::Nil => Ok(Literal::Nil),
+            Value::Int(value) => Ok(Literal::Int(value)),
+            Value::Float(value) => Ok(Literal::Float(value.to_bits())),
+            Value::Object(_) | Value::Closure(_) => Err(value),
+        }
+    }
+}
+
+impl Value {
+    /// Applies a binary operation. Both operands must be of the same kind,
+    /// except for `Eq`, under which values of different kinds are unequal.
+    ///
+    /// Float arithmetic follows IEEE 754: division by zero gives an infinity
+    /// and comparisons involving NaN are false. Constant folding goes through
+    /// here as well, so folded results match execution to the bit.
+    pub fn binary(ins: Instruction, lhs: Value, rhs: Value) -> Result<Value, Trap> {
+        use Instruction::{Add, Div, Eq, Gt, Le, Lt, Mod, Mul, Sub};
+        let value = match (ins, lhs, rhs) {
+            (Eq, lhs, rhs) => Value::Int((lhs == rhs) as i64),
+            (_, Value::Int(lhs), Value::Int(rhs)) => match ins.fold_int(lhs, rhs) {
+                Some(value) => Value::Int(value),
+                None if matches!(ins, Div | Mod) => return Err(Trap::DivisionByZero),
+                None => return Err(Trap::TypeError(ins)),
+            },
+            (_, Value::Float(lhs), Value::Float(rhs)) => match ins {
+                Add => Value::Float(lhs + rhs),
+                Sub => Value::Float(lhs - rhs),
+                Mul => Value::Float(lhs * rhs),
+                Div => Value::Float(lhs / rhs),
+                Mod => Value::Float(lhs % rhs),
+                Lt => Value::Int((lhs < rhs) as i64),
+                Le => Value::Int((lhs <= rhs) as i64),
+                Gt => Value::Int((lhs > rhs) as i64),
+                _ => return Err(Trap::TypeError(ins)),
+            },
+            _ => return Err(Trap::TypeError(ins)),
+        };
+        Ok(value)
+    }
+
+    /// Applies a unary operation. `FloatToInt` rounds towards zero and
+    /// saturates at the bounds of `i64`, with NaN converting to 0.
+    pub fn unary(ins: Instruction, value: Value) -> Result<Value, Trap> {
+        use Instruction::{FloatToInt, IntToFloat, Neg};
+        let value = match (ins, value) {
+            (IntToFloat, Value::Int(value)) => Value::Float(value as f64),
+            (FloatToInt, Value::Float(value)) => Value::Int(value as i64),
+            (Neg, Value::Float(value)) => Value::Float(-value),
+            (_, Value::Int(value)) => {
+                Value::Int(ins.fold_unary(value).ok_or(Trap::TypeError(ins))?)
+            }
+            _ => return Err(Trap::TypeError(ins)),
+        };
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::EdgeType::FallThrough;
+    use crate::cfg::ControlFlowGraph;
+    use crate::interpreter::Interpreter;
+    use crate::module::{Function, Module};
+    use crate::opt::constprop::ConstantPropagation;
+    use Instruction::{
+        Add, Div, Eq, FloatToInt, Gt, IntToFloat, LdFloat, LdInt, Le, Lt, Neg, Shl, StStatic,
+    };
+
+    #[test]
+    fn comparisons_with_nan_are_false() {
+        let nan = Value::Float(f64::NAN);
+        let one = Value::Float(1.0);
+        for ins in [Eq, Lt, Le, Gt].iter() {
+            assert_eq!(Value::binary(*ins, nan, one), Ok(Value::Int(0)));
+            assert_eq!(Value::binary(*ins, one, nan), Ok(Value::Int(0)));
+        }
+        assert_eq!(Value::binary(Eq, nan, nan), Ok(Value::Int(0)));
+        assert_eq!(Value::binary(Le, one, one), Ok(Value::Int(1)));
+        assert_eq!(Value::binary(Eq, one, Value::Int(1)), Ok(Value::Int(0)));
+    }
+
+    #[test]
+    fn float_arithmetic_follows_ieee() {
+        let div = Value::binary(Div, Value::Float(1.0), Value::Float(-0.0));
+        assert_eq!(div, Ok(Value::Float(f64::NEG_INFINITY)));
+        let add = Value::binary(Add, Value::Float(0.1), Value::Float(0.2));
+        assert_eq!(add, Ok(Value::Float(0.30000000000000004)));
+        let mixed = Value::binary(Add, Value::Float(1.0), Value::Int(1));
+        assert_eq!(mixed, Err(Trap::TypeError(Add)));
+        let shift = Value::binary(Shl, Value::Float(1.0), Value::Float(1.0));
+        assert_eq!(shift, Err(Trap::TypeError(Shl)));
+    }
+
+    #[test]
+    fn float_to_int_saturates() {
+        let convert = |value: f64| Value::unary(FloatToInt, Value::Float(value));
+        assert_eq!(convert(-2.7), Ok(Value::Int(-2)));
+        assert_eq!(convert(1e300), Ok(Value::Int(i64::MAX)));
+        assert_eq!(convert(f64::NEG_INFINITY), Ok(Value::Int(i64::MIN)));
+        assert_eq!(convert(f64::NAN), Ok(Value::Int(0)));
+        let back = Value::unary(IntToFloat, Value::Int(i64::MAX));
+        assert_eq!(back, Ok(Value::Float(9223372036854775807.0)));
+    }
+
+    // Runs `code` storing its result into static 0 before and after
+    // constant propagation and compares the stored bits.
+    fn folds_like_interpreter(code: Vec<Instruction>) {
+        let mut module = Module::new();
+        let cfg =
+            ControlFlowGraph::from_blocks(vec![code], &[(0, 2, FallThrough), (2, 1, FallThrough)]);
+        module.add_function(Function::new("f", 0, cfg));
+        module.add_static("s", Literal::Nil);
+
+        let mut interpreter = Interpreter::new(&module);
+        interpreter.call(0, &[]).unwrap();
+        let expected = match interpreter.statics[0] {
+            Value::Int(value) => Literal::Int(value),
+            Value::Float(value) => Literal::Float(value.to_bits()),
+            value => panic!("stored {:?}", value),
+        };
+
+        let mut constprop = ConstantPropagation::new();
+        assert!(constprop.run(&mut module.functions[0].cfg));
+        let folded = match module.functions[0]
+            .cfg
+            .find_block(2)
+            .unwrap()
+            .borrow()
+            .instructions[..]
+        {
+            [LdInt(value), StStatic(0)] => Literal::Int(value),
+            [LdFloat(bits), StStatic(0)] => Literal::Float(bits),
+            ref code => panic!("not folded: {:?}", code),
+        };
+        assert_eq!(folded, expected);
+    }
+
+    #[test]
+    fn folding_is_bit_exact() {
+        let float = |value: f64| LdFloat(value.to_bits());
+        for value in [-2.7, 1e300, f64::NEG_INFINITY, f64::NAN, -0.0].iter() {
+            folds_like_interpreter(vec![float(*value), FloatToInt, StStatic(0)]);
+        }
+        folds_like_interpreter(vec![float(0.1), float(0.2), Add, StStatic(0)]);
+        folds_like_interpreter(vec![float(0.0), float(0.0), Div, StStatic(0)]);
+        folds_like_interpreter(vec![float(-0.0), Neg, StStatic(0)]);
+        folds_like_interpreter(vec![LdInt(i64::MAX), IntToFloat, StStatic(0)]);
+        folds_like_interpreter(vec![float(f64::NAN), float(f64::NAN), Lt, StStatic(0)]);
+    }
+}
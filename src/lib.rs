@@ -2,5 +2,10 @@
 pub mod macros;
 pub mod analysis;
 pub mod block;
+pub mod bytecode;
 pub mod cfg;
 pub mod instructions;
+pub mod interpreter;
+pub mod module;
+pub mod opt;
+pub mod verifier;
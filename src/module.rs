@@ -0,0 +1,107 @@
+use crate::cfg::ControlFlowGraph;
+use crate::instructions::Instruction;
+
+/// Initial value of a global or static slot.
+#[derive(Copy, Clone, PartialEq, Eq, Default, Debug)]
+pub enum Literal {
+    #[default]
+    Nil,
+    Int(i64),
+    Float(u64 /* f64::to_bits() */),
+}
+
+#[derive(Clone, PartialEq, Default, Debug)]
+pub struct Variable {
+    pub name: String,
+    pub init: Literal,
+}
+
+/// A function body together with the frame layout it needs.
+///
+/// Arguments are passed in the first `params` local slots, so `locals` is
+/// never smaller than `params`. `env` names the environment slots addressed
+/// by `LdEnv`/`StEnv`.
+#[derive(Default, Debug)]
+pub struct Function {
+    pub name: String,
+    pub cfg: ControlFlowGraph,
+    pub params: u32,
+    pub locals: u32,
+    pub env: Vec<String>,
+}
+
+impl Function {
+    pub fn new(name: &str, params: u32, cfg: ControlFlowGraph) -> Self {
+        let locals = std::cmp::max(params, cfg.first_free_local());
+        Self {
+            name: name.to_owned(),
+            cfg,
+            params,
+            locals,
+            env: vec![],
+        }
+    }
+}
+
+/// Functions, globals and statics referenced by index from `Call(n)`,
+/// `TailCall(n)`, `LdGlobal(n)` and `LdStatic(n)`/`StStatic(n)`.
+#[derive(Default, Debug)]
+pub struct Module {
+    pub functions: Vec<Function>,
+    pub globals: Vec<Variable>,
+    pub statics: Vec<Variable>,
+}
+
+impl Module {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_function(&mut self, function: Function) -> u32 {
+        self.functions.push(function);
+        self.functions.len() as u32 - 1
+    }
+
+    pub fn add_global(&mut self, name: &str, init: Literal) -> u32 {
+        self.globals.push(Variable {
+            name: name.to_owned(),
+            init,
+        });
+        self.globals.len() as u32 - 1
+    }
+
+    pub fn add_static(&mut self, name: &str, init: Literal) -> u32 {
+        self.statics.push(Variable {
+            name: name.to_owned(),
+            init,
+        });
+        self.statics.len() as u32 - 1
+    }
+
+    /// The function a `Call(n)` or `TailCall(n)` refers to.
+    pub fn resolve(&self, n: u32) -> Option<&Function> {
+        self.functions.get(n as usize)
+    }
+
+    pub fn function_index(&self, name: &str) -> Option<u32> {
+        self.functions
+            .iter()
+            .position(|function| function.name == name)
+            .map(|n| n as u32)
+    }
+
+    /// Like `Instruction::stack_effect`, with calls resolved against this
+    /// module. A call pops its arguments and pushes the callee's result; a
+    /// tail call pushes nothing since it leaves the function.
+    pub fn stack_effect(&self, ins: &Instruction) -> Option<(u32, u32)> {
+        match ins {
+            Instruction::Call(n) => Some((self.resolve(*n)?.params, 1)),
+            Instruction::TailCall(n) => Some((self.resolve(*n)?.params, 0)),
+            _ => ins.stack_effect(),
+        }
+    }
+
+    pub fn verify(&self) -> Result<(), crate::verifier::VerifyError> {
+        crate::verifier::verify(self)
+    }
+}
@@ -0,0 +1,497 @@
+use crate::block::*;
+use crate::cfg::*;
+use crate::instructions::Instruction;
+use crate::interpreter::Value;
+use crate::module::Literal;
+
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+
+/// Abstract state at a program point. `None` on the stack means the value is
+/// not a known constant; locals missing from the map are not constant either.
+#[derive(Clone, PartialEq, Default, Debug)]
+struct State {
+    stack: Vec<Option<Literal>>,
+    locals: HashMap<u32, Literal>,
+}
+
+impl State {
+    fn pop(&mut self) -> Option<Literal> {
+        self.stack.pop().flatten()
+    }
+
+    fn meet(&self, other: &State) -> State {
+        let len = std::cmp::min(self.stack.len(), other.stack.len());
+        let stack = self.stack[self.stack.len() - len..]
+            .iter()
+            .zip(other.stack[other.stack.len() - len..].iter())
+            .map(|(a, b)| if a == b { *a } else { None })
+            .collect();
+        let locals = self
+            .locals
+            .iter()
+            .filter(|(slot, value)| other.locals.get(slot) == Some(value))
+            .map(|(slot, value)| (*slot, *value))
+            .collect();
+        State { stack, locals }
+    }
+
+    fn transfer(&mut self, ins: &Instruction) {
+        use Instruction::*;
+        match ins {
+            LdInt(value) => self.stack.push(Some(Literal::Int(*value))),
+            LdFloat(bits) => self.stack.push(Some(Literal::Float(*bits))),
+            LdLocal(n) => self.stack.push(self.locals.get(n).copied()),
+            StLocal(n) => match self.pop() {
+                Some(value) => {
+                    self.locals.insert(*n, value);
+                }
+                None => {
+                    self.locals.remove(n);
+                }
+            },
+            Dup => {
+                let value = self.pop();
+                self.stack.push(value);
+                self.stack.push(value);
+            }
+            // operations that would trap are left for execution
+            Add | Sub | Mul | Div | Mod | Shl | Shr | Eq | Lt | Le | Gt | And | Or | Xor => {
+                let rhs = self.pop();
+                let lhs = self.pop();
+                let value = match (lhs, rhs) {
+                    (Some(lhs), Some(rhs)) => Value::binary(*ins, lhs.into(), rhs.into()).ok(),
+                    _ => None,
+                };
+                self.stack
+                    .push(value.and_then(|value| Literal::try_from(value).ok()));
+            }
+            Not | Neg | IntToFloat | FloatToInt => {
+                let value = self
+                    .pop()
+                    .and_then(|value| Value::unary(*ins, value.into()).ok());
+                self.stack
+                    .push(value.and_then(|value| Literal::try_from(value).ok()));
+            }
+            // the arity of the callee is unknown, so nothing on the stack can
+            // be trusted afterwards
+            Call(_) | TailCall(_) | CallClosure(_) => self.stack.clear(),
+            _ => {
+                let (pops, pushes) = ins.stack_effect().unwrap();
+                for _ in 0..pops {
+                    self.pop();
+                }
+                for _ in 0..pushes {
+                    self.stack.push(None);
+                }
+            }
+        }
+    }
+}
+
+/// Sparse conditional constant propagation over the operand stack and locals.
+///
+/// Arithmetic on known constants is folded into `LdInt` or `LdFloat`, loads
+/// of locals holding a known constant are replaced by the constant, and
+/// conditional jumps and switches on a known value become unconditional, with
+/// the edges that can no longer be taken removed. Blocks left unreachable are
+/// cleaned up by `DeadCodeElimination`.
+#[derive(Default)]
+pub struct ConstantPropagation {
+    pub folded: usize,
+    pub resolved_branches: usize,
+    states: HashMap<usize, State>,
+}
+
+impl ConstantPropagation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn run(&mut self, cfg: &mut ControlFlowGraph) -> bool {
+        self.states.clear();
+        self.propagate(cfg);
+
+        let mut changed = false;
+        for block in cfg.blocks.clone() {
+            let id = block.borrow().id;
+            if let Some(state) = self.states.get(&id).cloned() {
+                changed |= self.rewrite(cfg, block, state);
+            }
+        }
+        if changed {
+            cfg.remove_dead_exception_edges();
+        }
+        changed
+    }
+
+    fn propagate(&mut self, cfg: &ControlFlowGraph) {
+        let entry = cfg.get_entry_block();
+        self.states.insert(entry.borrow().id, State::default());
+        let mut worklist = VecDeque::new();
+        worklist.push_back(entry);
+
+        while let Some(block) = worklist.pop_front() {
+            let mut state = self.states[&block.borrow().id].clone();
+            let mut condition = None;
+            // what the handler sees: the locals at any instruction that may
+            // throw and the exception on the stack
+            let mut thrown: Option<State> = None;
+            let last = block.borrow().instructions.len().wrapping_sub(1);
+            for (i, ins) in block.borrow().instructions.iter().enumerate() {
+                if ins.may_throw() {
+                    let at = State {
+                        stack: vec![None],
+                        locals: state.locals.clone(),
+                    };
+                    thrown = Some(match thrown {
+                        Some(thrown) => thrown.meet(&at),
+                        None => at,
+                    });
+                }
+                if i == last {
+                    if let Instruction::JmpZ(_) | Instruction::JmpNz(_) | Instruction::Switch = ins
+                    {
+                        condition = int_condition(&state);
+                    }
+                }
+                state.transfer(ins);
+            }
+
+            for edge in executable_edges(&block, condition) {
+                let state = match edge.borrow().ty {
+                    EdgeType::Exception => match thrown.as_ref() {
+                        Some(thrown) => thrown,
+                        None => continue,
+                    },
+                    _ => &state,
+                };
+                let succ = edge.borrow().tail.as_ref().unwrap().clone();
+                let id = succ.borrow().id;
+                let merged = match self.states.get(&id) {
+                    Some(old) => old.meet(state),
+                    None => state.clone(),
+                };
+                if self.states.get(&id) != Some(&merged) {
+                    self.states.insert(id, merged);
+                    worklist.push_back(succ);
+                }
+            }
+        }
+    }
+
+    fn rewrite(
+        &mut self,
+        cfg: &mut ControlFlowGraph,
+        mut block: CodeBlockRef,
+        mut state: State,
+    ) -> bool {
+        let old = block.borrow().instructions.clone();
+        let mut new: Vec<Instruction> = Vec::with_capacity(old.len());
+        let mut resolved = None;
+
+        for (i, ins) in old.iter().enumerate() {
+            let condition = int_condition(&state);
+            state.transfer(ins);
+            match ins {
+                Instruction::LdLocal(_) => {
+                    if let Some(value) = state.stack.last().copied().flatten() {
+                        new.push(load(value));
+                        continue;
+                    }
+                }
+                Instruction::Add
+                | Instruction::Sub
+                | Instruction::Mul
+                | Instruction::Div
+                | Instruction::Mod
+                | Instruction::Shl
+                | Instruction::Shr
+                | Instruction::Eq
+                | Instruction::Lt
+                | Instruction::Le
+                | Instruction::Gt
+                | Instruction::Not
+                | Instruction::And
+                | Instruction::Or
+                | Instruction::Xor
+                | Instruction::Neg
+                | Instruction::IntToFloat
+                | Instruction::FloatToInt => {
+                    if let Some(value) = state.stack.last().copied().flatten() {
+                        let (pops, _) = ins.stack_effect().unwrap();
+                        let operands = pops as usize;
+                        let len = new.len();
+                        if len >= operands
+                            && new[len - operands..].iter().all(|ins| {
+                                matches!(ins, Instruction::LdInt(_) | Instruction::LdFloat(_))
+                            })
+                        {
+                            new.truncate(len - operands);
+                        } else {
+                            new.push(Instruction::Pop(pops));
+                        }
+                        new.push(load(value));
+                        self.folded += 1;
+                        continue;
+                    }
+                }
+                Instruction::JmpZ(target) | Instruction::JmpNz(target) if i == old.len() - 1 => {
+                    if let Some(value) = condition {
+                        let taken = match ins {
+                            Instruction::JmpZ(_) => value == 0,
+                            _ => value != 0,
+                        };
+                        let target = if taken { Some(*target) } else { None };
+                        drop_condition(&mut new);
+                        if let Some(target) = target {
+                            new.push(Instruction::Jmp(target));
+                        }
+                        resolved = Some(target);
+                        continue;
+                    }
+                }
+                Instruction::Switch if i == old.len() - 1 => {
+                    if let Some(key) = condition {
+                        let target = switch_target(&block.borrow(), key);
+                        drop_condition(&mut new);
+                        if let Some(target) = target {
+                            new.push(Instruction::Jmp(target));
+                        }
+                        resolved = Some(target);
+                        continue;
+                    }
+                }
+                _ => (),
+            }
+            new.push(*ins);
+        }
+
+        if new == old {
+            return false;
+        }
+        block.borrow_mut().instructions = new;
+
+        if let Some(target) = resolved {
+            block.borrow_mut().switch = None;
+            let edges = block
+                .borrow()
+                .out_edges
+                .iter()
+                .filter(|edge| {
+                    let edge = edge.borrow();
+                    let tail = edge.tail.as_ref().unwrap().borrow().id;
+                    match target {
+                        _ if edge.ty == EdgeType::Exception => false,
+                        Some(target) => edge.ty != EdgeType::Branch || tail != target as usize,
+                        None => edge.ty != EdgeType::FallThrough,
+                    }
+                })
+                .cloned()
+                .collect::<Vec<_>>();
+            for edge in edges {
+                cfg.remove_edge(edge);
+            }
+            self.resolved_branches += 1;
+        }
+        true
+    }
+}
+
+// The known value of a branch condition or switch key. Only integers are
+// tested by `JmpZ`/`JmpNz`/`Switch`; anything else traps.
+fn int_condition(state: &State) -> Option<i64> {
+    match state.stack.last() {
+        Some(Some(Literal::Int(value))) => Some(*value),
+        _ => None,
+    }
+}
+
+// Removes the condition of a resolved branch from the rewritten code.
+fn drop_condition(new: &mut Vec<Instruction>) {
+    if let Some(Instruction::LdInt(_) | Instruction::LdFloat(_)) = new.last() {
+        new.pop();
+    } else {
+        new.push(Instruction::Pop(1));
+    }
+}
+
+fn load(value: Literal) -> Instruction {
+    match value {
+        Literal::Float(bits) => Instruction::LdFloat(bits),
+        Literal::Int(value) => Instruction::LdInt(value),
+        Literal::Nil => unreachable!("no instruction produces a nil constant"),
+    }
+}
+
+fn executable_edges(
+    block: &CodeBlockRef,
+    condition: Option<i64>,
+) -> Vec<std::rc::Rc<std::cell::RefCell<Edge>>> {
+    let block = block.borrow();
+    let live = match (block.instructions.last(), condition) {
+        (Some(Instruction::Jmp(_)), _) => Some(EdgeType::Branch),
+        (Some(Instruction::JmpZ(_)), Some(0)) => Some(EdgeType::Branch),
+        (Some(Instruction::JmpNz(_)), Some(0)) => Some(EdgeType::FallThrough),
+        (Some(Instruction::JmpZ(_)), Some(_)) => Some(EdgeType::FallThrough),
+        (Some(Instruction::JmpNz(_)), Some(_)) => Some(EdgeType::Branch),
+        (Some(Instruction::Switch), Some(key)) => match switch_target(&block, key) {
+            Some(_) => Some(EdgeType::Branch),
+            None => Some(EdgeType::FallThrough),
+        },
+        _ => None,
+    };
+    let target = match (block.instructions.last(), condition) {
+        (Some(Instruction::Switch), Some(key)) => switch_target(&block, key),
+        _ => None,
+    };
+    // returns leave the function, nothing flows along them into the exit
+    block
+        .out_edges
+        .iter()
+        .filter(|edge| edge.borrow().ty != EdgeType::Return)
+        .filter(|edge| {
+            let ty = edge.borrow().ty;
+            ty == EdgeType::Exception || live.map(|live| ty == live).unwrap_or(true)
+        })
+        .filter(|edge| {
+            let edge = edge.borrow();
+            let tail = edge.tail.as_ref().unwrap().borrow().id;
+            edge.ty != EdgeType::Branch
+                || target.map(|target| tail == target as usize).unwrap_or(true)
+        })
+        .cloned()
+        .collect()
+}
+
+fn switch_target(block: &CodeBlock, key: i64) -> Option<u32> {
+    block.switch.as_ref().and_then(|table| table.target(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use EdgeType::*;
+    use Instruction::*;
+
+    fn instructions(cfg: &ControlFlowGraph, id: usize) -> Vec<Instruction> {
+        cfg.find_block(id).unwrap().borrow().instructions.clone()
+    }
+
+    #[test]
+    fn folds_arithmetic_through_locals() {
+        let mut cfg = ControlFlowGraph::from_blocks(
+            vec![vec![
+                LdInt(2),
+                LdInt(3),
+                Mul,
+                StLocal(0),
+                LdLocal(0),
+                LdInt(1),
+                Add,
+                StStatic(0),
+            ]],
+            &[(0, 2, FallThrough), (2, 1, FallThrough)],
+        );
+        let mut constprop = ConstantPropagation::new();
+        assert!(constprop.run(&mut cfg));
+
+        assert_eq!(
+            instructions(&cfg, 2),
+            vec![LdInt(6), StLocal(0), LdInt(7), StStatic(0)]
+        );
+        assert_eq!(constprop.folded, 2);
+    }
+
+    #[test]
+    fn resolves_branch_on_known_condition() {
+        let mut cfg = ControlFlowGraph::from_blocks(
+            vec![
+                vec![LdInt(0), StLocal(0), LdLocal(0), JmpZ(4)],
+                vec![LdInt(1), StStatic(0)],
+                vec![],
+            ],
+            &[
+                (0, 2, FallThrough),
+                (2, 4, Branch),
+                (2, 3, FallThrough),
+                (3, 4, FallThrough),
+                (4, 1, FallThrough),
+            ],
+        );
+        let mut constprop = ConstantPropagation::new();
+        assert!(constprop.run(&mut cfg));
+
+        let block = cfg.find_block(2).unwrap();
+        assert_eq!(
+            block.borrow().instructions,
+            vec![LdInt(0), StLocal(0), Jmp(4)]
+        );
+        let edges = block
+            .borrow()
+            .out_edges
+            .iter()
+            .map(|edge| {
+                (
+                    edge.borrow().tail.as_ref().unwrap().borrow().id,
+                    edge.borrow().ty,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(edges, vec![(4, Branch)]);
+        assert_eq!(constprop.resolved_branches, 1);
+    }
+
+    #[test]
+    fn keeps_local_that_differs_between_paths() {
+        let mut cfg = ControlFlowGraph::from_blocks(
+            vec![
+                vec![LdLocal(1), JmpZ(4)],
+                vec![LdInt(1), StLocal(0), LdInt(2), StLocal(3), Jmp(5)],
+                vec![LdInt(1), StLocal(0), LdInt(3), StLocal(3)],
+                vec![LdLocal(0), LdLocal(3), Add, StStatic(0)],
+            ],
+            &[
+                (0, 2, FallThrough),
+                (2, 4, Branch),
+                (2, 3, FallThrough),
+                (3, 5, Branch),
+                (4, 5, FallThrough),
+                (5, 1, FallThrough),
+            ],
+        );
+        assert!(ConstantPropagation::new().run(&mut cfg));
+
+        assert_eq!(
+            instructions(&cfg, 5),
+            vec![LdInt(1), LdLocal(3), Add, StStatic(0)]
+        );
+    }
+
+    #[test]
+    fn folded_division_drops_exception_edge() {
+        // try { return 6 / 2 } catch { return 100 }
+        let mut cfg = ControlFlowGraph::from_blocks(
+            vec![
+                vec![LdInt(6), LdInt(2), Div, Ret],
+                vec![Pop(1), LdInt(100), Ret],
+            ],
+            &[
+                (0, 2, FallThrough),
+                (2, 3, Exception),
+                (2, 1, Return),
+                (3, 1, Return),
+            ],
+        );
+        assert!(ConstantPropagation::new().run(&mut cfg));
+        assert_eq!(instructions(&cfg, 2), vec![LdInt(3), Ret]);
+        let block = cfg.find_block(2).unwrap();
+        let types = block
+            .borrow()
+            .out_edges
+            .iter()
+            .map(|edge| edge.borrow().ty)
+            .collect::<Vec<_>>();
+        assert_eq!(types, vec![Return]);
+    }
+}
@@ -0,0 +1,510 @@
+use super::tailcall::loop_header;
+use crate::analysis::liveness::*;
+use crate::block::*;
+use crate::cfg::*;
+use crate::instructions::Instruction;
+use crate::module::*;
+use crate::verifier;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// What a yield point has to save: its operand stack height and the locals
+/// live across it, numbered as in the transformed function.
+struct Site {
+    state: i64,
+    height: u32,
+    locals: Vec<u32>,
+}
+
+/// Turns a function containing `ThreadYield`s into a resumable state machine
+/// that keeps its state in a frame object instead of a call stack.
+///
+/// The transformed function takes the frame object as its only argument.
+/// Field `STATE` holds where to resume: 0 starts the function with the
+/// arguments taken from fields `1..=params`, `k` continues after the `k`-th
+/// yield point and `DONE` marks a function that has returned, which traps
+/// when resumed. Field `n` otherwise holds local `n`, local `n - 1` of the
+/// original function, and the fields after the locals the operand stack of
+/// the last yield point.
+///
+/// A yield point stores the locals live across it and its operand stack into
+/// the frame object, sets the state and returns nil. The entry block
+/// dispatches on the state to the start of the function or to a block that
+/// restores what the yield point stored. Functions with an environment,
+/// which every resume would create anew, and functions referring to
+/// themselves are left alone.
+#[derive(Default)]
+pub struct CoroutineTransform {
+    pub yields: usize,
+}
+
+impl CoroutineTransform {
+    pub const STATE: u32 = 0;
+    pub const DONE: i64 = -1;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Transforms function `n`. Returns false if it was left alone.
+    pub fn run(&mut self, module: &mut Module, n: u32) -> bool {
+        let heights = match verifier::stack_heights(module, n) {
+            Ok(heights) => heights,
+            Err(_) => return false,
+        };
+        let function = &module.functions[n as usize];
+        if !function.env.is_empty() || refers_to(function, n) {
+            return false;
+        }
+        let cfg = &function.cfg;
+        let mut liveness = LivenessAnalysis::new();
+        liveness.analyze(cfg);
+
+        let mut sites: HashMap<usize, HashMap<usize, Site>> = HashMap::new();
+        let mut state = 0;
+        for block in cfg.blocks.iter() {
+            let block = block.borrow();
+            let mut height = match heights.get(&block.id) {
+                Some(height) => *height,
+                None => continue,
+            };
+            for (i, ins) in block.instructions.iter().enumerate() {
+                if *ins == Instruction::ThreadYield {
+                    state += 1;
+                    let live = liveness.live_before(block.id, i).unwrap();
+                    let locals = live.iter().filter_map(|slot| match slot {
+                        Slot::Local(n) => Some(n + 1),
+                        _ => None,
+                    });
+                    let site = Site {
+                        state,
+                        height,
+                        locals: locals.collect(),
+                    };
+                    sites.entry(block.id).or_default().insert(i, site);
+                }
+                let (pops, pushes) = module.stack_effect(ins).unwrap();
+                height = height - pops + pushes;
+            }
+        }
+        if sites.is_empty() {
+            return false;
+        }
+
+        let function = &mut module.functions[n as usize];
+        let cfg = &mut function.cfg;
+        // the entry block becomes the dispatcher, its code moves to the header
+        let entry = cfg.entry.borrow().id;
+        let header = loop_header(cfg);
+        if let Some(moved) = sites.remove(&entry) {
+            sites.insert(header.borrow().id, moved);
+        }
+
+        // local 0 holds the frame object, the original locals move up by one
+        let locals = function.locals;
+        let mapping = (0..locals).map(|n| (n, n + 1)).collect();
+        remap_locals(cfg, &mapping);
+        let temp = locals + 1;
+
+        // resume blocks with the state they continue from
+        let mut resumes = vec![];
+        for mut block in cfg.blocks.clone() {
+            let id = block.borrow().id;
+            let sites = sites.remove(&id).unwrap_or_default();
+            let mut segments = vec![vec![]];
+            let mut states = vec![];
+            let instructions = std::mem::take(&mut block.borrow_mut().instructions);
+            for (i, ins) in instructions.into_iter().enumerate() {
+                let segment = segments.last_mut().unwrap();
+                match sites.get(&i) {
+                    Some(site) => {
+                        save(segment, site, temp);
+                        segments.push(restore(site, temp));
+                        states.push(site.state);
+                    }
+                    None => {
+                        if let Instruction::Ret | Instruction::RetVoid | Instruction::TailCall(_) =
+                            ins
+                        {
+                            set_state(segment, Self::DONE);
+                        }
+                        segment.push(ins);
+                    }
+                }
+            }
+            let mut segments = segments.into_iter();
+            block.borrow_mut().instructions = segments.next().unwrap();
+            let rest = segments
+                .map(|instructions| {
+                    let id = cfg.new_id();
+                    let handler = block.borrow().handler;
+                    cfg.insert_block(CodeBlockRef::new(CodeBlock {
+                        id,
+                        instructions,
+                        handler,
+                        ..Default::default()
+                    }))
+                })
+                .collect::<Vec<_>>();
+            let mut last = match rest.last() {
+                Some(last) => last.clone(),
+                None => continue,
+            };
+            resumes.extend(states.into_iter().zip(rest));
+
+            // the code after the last yield point ends the way the block did
+            let switch = block.borrow_mut().switch.take();
+            last.borrow_mut().switch = switch;
+            if cfg.exit.ptr_eq(&block) {
+                cfg.exit = last.clone();
+            }
+            let out_edges = block.borrow().out_edges.clone();
+            for edge in out_edges {
+                let tail = edge.borrow().tail.as_ref().unwrap().clone();
+                let ty = edge.borrow().ty;
+                cfg.remove_edge(edge);
+                // exception edges are recreated below for the blocks that may throw
+                if ty != EdgeType::Exception {
+                    link(cfg, &last, &tail, ty);
+                }
+            }
+        }
+        self.yields += resumes.len();
+        resumes.sort_by_key(|(state, _)| *state);
+        let resumes = resumes
+            .into_iter()
+            .map(|(_, resume)| resume)
+            .collect::<Vec<_>>();
+
+        // control can fall off the end of the exit block to return
+        let mut exit = cfg.exit.clone();
+        let falls_off = exit
+            .borrow()
+            .in_edges
+            .iter()
+            .any(|edge| edge.borrow().ty != EdgeType::Return)
+            && exit
+                .borrow()
+                .instructions
+                .last()
+                .is_none_or(|ins| !ins.is_terminator());
+        if falls_off {
+            set_state(&mut exit.borrow_mut().instructions, Self::DONE);
+        }
+
+        let mut start = CodeBlock {
+            id: cfg.new_id(),
+            ..Default::default()
+        };
+        for n in 1..=function.params {
+            start.instructions.extend_from_slice(&[
+                Instruction::LdLocal(0),
+                Instruction::LdFieldAt(n),
+                Instruction::StLocal(n),
+            ]);
+        }
+        let start = cfg.insert_block(CodeBlockRef::new(start));
+        let id = cfg.new_id();
+        let finished = cfg.insert_block(CodeBlockRef::new(CodeBlock {
+            id,
+            instructions: vec![Instruction::Unreachable],
+            ..Default::default()
+        }));
+        let entry = cfg.get_entry_block();
+        let out_edges = entry.borrow().out_edges.clone();
+        for edge in out_edges {
+            cfg.remove_edge(edge);
+        }
+        let mut targets = vec![start.borrow().id as u32];
+        targets.extend(resumes.iter().map(|resume| resume.borrow().id as u32));
+        {
+            let mut entry = entry.clone();
+            let mut entry = entry.borrow_mut();
+            entry.instructions = vec![
+                Instruction::LdLocal(0),
+                Instruction::LdFieldAt(Self::STATE),
+                Instruction::Switch,
+            ];
+            entry.switch = Some(SwitchTable::Dense { low: 0, targets });
+        }
+        link(cfg, &entry, &start, EdgeType::Branch);
+        for resume in resumes.iter() {
+            link(cfg, &entry, resume, EdgeType::Branch);
+        }
+        link(cfg, &entry, &finished, EdgeType::FallThrough);
+        link(cfg, &start, &header, EdgeType::FallThrough);
+
+        // the frame object accesses may throw where nothing did before
+        for block in cfg.blocks.clone() {
+            let handler = block
+                .borrow()
+                .handler
+                .and_then(|handler| cfg.find_block(handler));
+            let linked = block
+                .borrow()
+                .out_edges
+                .iter()
+                .any(|edge| edge.borrow().ty == EdgeType::Exception);
+            if let Some(handler) = handler {
+                if !linked && block.borrow().may_throw() {
+                    link(cfg, &block, &handler, EdgeType::Exception);
+                }
+            }
+        }
+        cfg.connect_returns();
+
+        function.params = 1;
+        function.locals = locals + 2;
+        true
+    }
+}
+
+fn refers_to(function: &Function, n: u32) -> bool {
+    function.cfg.blocks.iter().any(|block| {
+        block.borrow().instructions.iter().any(|ins| {
+            matches!(ins, Instruction::Call(m) | Instruction::TailCall(m) | Instruction::MkClosure(m) if *m == n)
+        })
+    })
+}
+
+fn set_state(instructions: &mut Vec<Instruction>, state: i64) {
+    instructions.extend_from_slice(&[
+        Instruction::LdLocal(0),
+        Instruction::LdInt(state),
+        Instruction::StFieldAt(CoroutineTransform::STATE),
+    ]);
+}
+
+// Stores the operand stack and the live locals into the frame object and
+// returns. The stack goes to the fields after the locals, starting at `temp`.
+fn save(instructions: &mut Vec<Instruction>, site: &Site, temp: u32) {
+    for n in (0..site.height).rev() {
+        instructions.extend_from_slice(&[
+            Instruction::StLocal(temp),
+            Instruction::LdLocal(0),
+            Instruction::LdLocal(temp),
+            Instruction::StFieldAt(temp + n),
+        ]);
+    }
+    for local in site.locals.iter() {
+        instructions.extend_from_slice(&[
+            Instruction::LdLocal(0),
+            Instruction::LdLocal(*local),
+            Instruction::StFieldAt(*local),
+        ]);
+    }
+    set_state(instructions, site.state);
+    instructions.push(Instruction::RetVoid);
+}
+
+fn restore(site: &Site, temp: u32) -> Vec<Instruction> {
+    let mut instructions = vec![];
+    for local in site.locals.iter() {
+        instructions.extend_from_slice(&[
+            Instruction::LdLocal(0),
+            Instruction::LdFieldAt(*local),
+            Instruction::StLocal(*local),
+        ]);
+    }
+    for n in 0..site.height {
+        instructions
+            .extend_from_slice(&[Instruction::LdLocal(0), Instruction::LdFieldAt(temp + n)]);
+    }
+    instructions
+}
+
+fn link(cfg: &mut ControlFlowGraph, head: &CodeBlockRef, tail: &CodeBlockRef, ty: EdgeType) {
+    cfg.insert_edge(Rc::new(RefCell::new(Edge {
+        head: Some(head.clone()),
+        tail: Some(tail.clone()),
+        ty,
+    })));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::{InlineCache, Interpreter, Trap, Value};
+    use EdgeType::*;
+    use Instruction::{
+        Add, Div, Jmp, JmpZ, LdFieldAt, LdInt, LdLocal, Lt, Mul, Ret, RetVoid, StFieldAt, StLocal,
+        Switch, ThreadYield,
+    };
+
+    fn instructions(cfg: &ControlFlowGraph, id: usize) -> Vec<Instruction> {
+        let block = cfg.find_block(id).unwrap();
+        let instructions = block.borrow().instructions.clone();
+        instructions
+    }
+
+    // Resumes function `n` with a new frame object holding `args` until it
+    // has finished, returning its result and how often it yielded.
+    fn resume_until_done(module: &Module, n: u32, args: &[Value]) -> (Result<Value, Trap>, usize) {
+        let mut interpreter = Interpreter::new(module);
+        let frame = interpreter.heap.alloc();
+        let mut cache = InlineCache::default();
+        let state = CoroutineTransform::STATE as i64;
+        let heap = &mut interpreter.heap;
+        heap.store(frame, state, Value::Int(0), &mut cache).unwrap();
+        for (key, arg) in (1..).zip(args.iter()) {
+            heap.store(frame, key, *arg, &mut cache).unwrap();
+        }
+        for yields in 0..100 {
+            let result = interpreter.call(n, &[frame]);
+            let state = interpreter.heap.load(frame, state, &mut cache).unwrap();
+            if result.is_err() || state == Value::Int(CoroutineTransform::DONE) {
+                return (result, yields);
+            }
+        }
+        panic!("function {} did not finish", n);
+    }
+
+    #[test]
+    fn yield_saves_stack_and_live_locals() {
+        // f(a): return yield(a + 1) * a
+        let cfg = ControlFlowGraph::from_blocks(
+            vec![vec![
+                LdLocal(0),
+                LdInt(1),
+                Add,
+                ThreadYield,
+                LdLocal(0),
+                Mul,
+                Ret,
+            ]],
+            &[(0, 2, FallThrough), (2, 1, Return)],
+        );
+        let mut module = Module::new();
+        module.add_function(Function::new("f", 1, cfg));
+        let mut transform = CoroutineTransform::new();
+        assert!(transform.run(&mut module, 0));
+        assert_eq!(transform.yields, 1);
+
+        let function = &module.functions[0];
+        assert_eq!((function.params, function.locals), (1, 3));
+        let cfg = &function.cfg;
+        let entry = vec![LdLocal(0), LdFieldAt(0), Switch];
+        assert_eq!(instructions(cfg, 0), entry);
+        let table = SwitchTable::Dense {
+            low: 0,
+            targets: vec![4, 3],
+        };
+        assert_eq!(cfg.entry.borrow().switch, Some(table));
+        let start = vec![LdLocal(0), LdFieldAt(1), StLocal(1)];
+        assert_eq!(instructions(cfg, 4), start);
+        let save = vec![
+            LdLocal(1),
+            LdInt(1),
+            Add,
+            StLocal(2),
+            LdLocal(0),
+            LdLocal(2),
+            StFieldAt(2),
+            LdLocal(0),
+            LdLocal(1),
+            StFieldAt(1),
+            LdLocal(0),
+            LdInt(1),
+            StFieldAt(0),
+            RetVoid,
+        ];
+        assert_eq!(instructions(cfg, 2), save);
+        let restore = vec![
+            LdLocal(0),
+            LdFieldAt(1),
+            StLocal(1),
+            LdLocal(0),
+            LdFieldAt(2),
+            LdLocal(1),
+            Mul,
+            LdLocal(0),
+            LdInt(CoroutineTransform::DONE),
+            StFieldAt(0),
+            Ret,
+        ];
+        assert_eq!(instructions(cfg, 3), restore);
+
+        assert_eq!(module.verify(), Ok(()));
+        let result = resume_until_done(&module, 0, &[Value::Int(4)]);
+        assert_eq!(result, (Ok(Value::Int(20)), 1));
+    }
+
+    #[test]
+    fn loop_keeps_locals_and_stack_across_yields() {
+        // f(a, n): i = 0; s = 0; while i < n { s = s + yield(i * a); i = i + 1; yield }
+        let body = vec![
+            LdLocal(3),
+            LdLocal(2),
+            LdLocal(0),
+            Mul,
+            ThreadYield,
+            Add,
+            StLocal(3),
+            LdLocal(2),
+            LdInt(1),
+            Add,
+            StLocal(2),
+            ThreadYield,
+            Jmp(3),
+        ];
+        let cfg = ControlFlowGraph::from_blocks(
+            vec![
+                vec![LdInt(0), StLocal(2), LdInt(0), StLocal(3)],
+                vec![LdLocal(2), LdLocal(1), Lt, JmpZ(5)],
+                body,
+                vec![LdLocal(3), Ret],
+            ],
+            &[
+                (0, 2, FallThrough),
+                (2, 3, FallThrough),
+                (3, 5, Branch),
+                (3, 4, FallThrough),
+                (4, 3, Branch),
+                (5, 1, Return),
+            ],
+        );
+        let mut function = Function::new("f", 2, cfg);
+        function.locals = 4;
+        let mut module = Module::new();
+        module.add_function(function);
+        let mut transform = CoroutineTransform::new();
+        assert!(transform.run(&mut module, 0));
+        assert_eq!(transform.yields, 2);
+        assert_eq!(module.verify(), Ok(()));
+
+        let args = [Value::Int(4), Value::Int(3)];
+        let result = resume_until_done(&module, 0, &args);
+        assert_eq!(result, (Ok(Value::Int(12)), 6));
+    }
+
+    #[test]
+    fn yields_resume_in_state_order() {
+        // f(a): yield; try { yield; return 10 / a } catch e { yield; return e - 5 }
+        let cfg = ControlFlowGraph::from_blocks(
+            vec![
+                vec![ThreadYield, LdInt(10), LdLocal(0), Div, Ret],
+                vec![ThreadYield, LdInt(-5), Add, Ret],
+            ],
+            &[
+                (0, 2, FallThrough),
+                (2, 3, Exception),
+                (2, 1, Return),
+                (3, 1, Return),
+            ],
+        );
+        let mut entry = cfg.entry.clone();
+        entry.borrow_mut().instructions = vec![ThreadYield];
+        let mut module = Module::new();
+        module.add_function(Function::new("f", 1, cfg));
+        assert!(CoroutineTransform::new().run(&mut module, 0));
+        assert_eq!(module.verify(), Ok(()));
+
+        let result = resume_until_done(&module, 0, &[Value::Int(2)]);
+        assert_eq!(result, (Ok(Value::Int(5)), 2));
+        let caught = Trap::DIVISION_BY_ZERO - 5;
+        let result = resume_until_done(&module, 0, &[Value::Int(0)]);
+        assert_eq!(result, (Ok(Value::Int(caught)), 3));
+    }
+}
@@ -0,0 +1,354 @@
+use crate::analysis::liveness::*;
+use crate::block::*;
+use crate::cfg::*;
+use crate::instructions::Instruction;
+use crate::interpreter::Value;
+use crate::module::Literal;
+
+use std::collections::HashSet;
+
+/// Removes code that cannot execute or whose results are never used:
+/// instructions after an unconditional jump, return or trap, blocks
+/// unreachable from the entry, stores to locals that are never read again and
+/// pure computations whose results are immediately popped. Arithmetic counts
+/// as pure when its operands are constants it cannot trap on.
+#[derive(Default)]
+pub struct DeadCodeElimination {
+    pub removed_instructions: usize,
+    pub removed_blocks: usize,
+}
+
+impl DeadCodeElimination {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn run(&mut self, cfg: &mut ControlFlowGraph) -> bool {
+        let mut changed = false;
+        loop {
+            let mut round = false;
+            round |= self.remove_code_after_terminators(cfg);
+            round |= self.remove_unreachable_blocks(cfg);
+            round |= self.remove_dead_stores(cfg);
+            round |= self.remove_unused_values(cfg);
+            round |= cfg.remove_dead_exception_edges();
+            if !round {
+                break;
+            }
+            changed = true;
+        }
+        changed
+    }
+
+    // Everything after a jump, return, throw or trap is dead, and so are the
+    // out edges the terminator does not take.
+    fn remove_code_after_terminators(&mut self, cfg: &mut ControlFlowGraph) -> bool {
+        let mut changed = false;
+        for block in cfg.blocks.clone() {
+            let mut block = block;
+            let end = block
+                .borrow()
+                .instructions
+                .iter()
+                .position(|ins| !ins.falls_through());
+            let end = match end {
+                Some(end) => end,
+                None => continue,
+            };
+            let len = block.borrow().instructions.len();
+            if end + 1 < len {
+                let mut block = block.borrow_mut();
+                block.instructions.truncate(end + 1);
+                block.switch = None;
+                self.removed_instructions += len - end - 1;
+                changed = true;
+            }
+
+            let last = block.borrow().instructions[end];
+            let dead_edges = block
+                .borrow()
+                .out_edges
+                .iter()
+                .filter(|edge| {
+                    let edge = edge.borrow();
+                    let tail = edge.tail.as_ref().unwrap().borrow().id;
+                    match (last, edge.ty) {
+                        (_, EdgeType::Exception) => false,
+                        (Instruction::Jmp(target), ty) => {
+                            ty != EdgeType::Branch || tail != target as usize
+                        }
+                        (_, ty) => ty != EdgeType::Return,
+                    }
+                })
+                .cloned()
+                .collect::<Vec<_>>();
+            for edge in dead_edges {
+                cfg.remove_edge(edge);
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    fn remove_unreachable_blocks(&mut self, cfg: &mut ControlFlowGraph) -> bool {
+        let mut reachable = HashSet::new();
+        let mut stack = vec![cfg.get_entry_block()];
+        while let Some(block) = stack.pop() {
+            if !reachable.insert(block.borrow().id) {
+                continue;
+            }
+            for succ in block.borrow().successors.iter() {
+                stack.push(succ.clone());
+            }
+        }
+
+        let exit = cfg.exit.borrow().id;
+        let dead = cfg
+            .blocks
+            .iter()
+            .filter(|block| {
+                let id = block.borrow().id;
+                id != exit && !reachable.contains(&id)
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        for block in dead.iter() {
+            self.removed_instructions += block.borrow().instructions.len();
+            self.removed_blocks += 1;
+            cfg.remove_block(block);
+        }
+        !dead.is_empty()
+    }
+
+    fn remove_dead_stores(&mut self, cfg: &mut ControlFlowGraph) -> bool {
+        let mut liveness = LivenessAnalysis::new();
+        liveness.analyze(cfg);
+
+        let mut changed = false;
+        for block in cfg.blocks.iter_mut() {
+            let id = block.borrow().id;
+            for (i, ins) in block.borrow_mut().instructions.iter_mut().enumerate() {
+                if let Instruction::StLocal(n) = *ins {
+                    if !liveness.is_live_after(id, i, Slot::Local(n)) {
+                        *ins = Instruction::Pop(1);
+                        changed = true;
+                    }
+                }
+            }
+        }
+        changed
+    }
+
+    // Walks each block backwards keeping track of how many of the values
+    // produced so far are discarded by a later `Pop`. A pure instruction that
+    // produces one of those values is dropped and its operands are discarded
+    // instead.
+    fn remove_unused_values(&mut self, cfg: &mut ControlFlowGraph) -> bool {
+        let mut changed = false;
+        for block in cfg.blocks.iter_mut() {
+            let old = block.borrow().instructions.clone();
+            let mut new = Vec::with_capacity(old.len());
+            let mut pending = 0;
+            for (i, ins) in old.iter().enumerate().rev() {
+                match ins {
+                    Instruction::Pop(n) => {
+                        pending += n;
+                        continue;
+                    }
+                    Instruction::Dup if pending > 0 => {
+                        pending -= 1;
+                        continue;
+                    }
+                    ins if pending > 0 && (ins.is_pure() || cannot_trap(&old[..i], ins)) => {
+                        let (pops, pushes) = ins.stack_effect().unwrap();
+                        if pushes == 1 {
+                            pending = pending - 1 + pops;
+                            continue;
+                        }
+                    }
+                    _ => (),
+                }
+                if pending > 0 {
+                    new.push(Instruction::Pop(pending));
+                    pending = 0;
+                }
+                new.push(*ins);
+            }
+            if pending > 0 {
+                new.push(Instruction::Pop(pending));
+            }
+            new.reverse();
+
+            if new != old {
+                self.removed_instructions += old.len().saturating_sub(new.len());
+                block.borrow_mut().instructions = new;
+                changed = true;
+            }
+        }
+        changed
+    }
+}
+
+// Whether `ins` is an operation on the constants loaded right before it that
+// evaluates without trapping.
+fn cannot_trap(before: &[Instruction], ins: &Instruction) -> bool {
+    let constant = |ins: &Instruction| match ins {
+        Instruction::LdInt(value) => Some(Value::from(Literal::Int(*value))),
+        Instruction::LdFloat(bits) => Some(Value::from(Literal::Float(*bits))),
+        _ => None,
+    };
+    let (lhs, rhs) = match before {
+        [.., lhs, rhs] => (constant(lhs), constant(rhs)),
+        [rhs] => (None, constant(rhs)),
+        [] => (None, None),
+    };
+    match (ins.stack_effect(), lhs, rhs) {
+        (Some((2, 1)), Some(lhs), Some(rhs)) => Value::binary(*ins, lhs, rhs).is_ok(),
+        (Some((1, 1)), _, Some(value)) => Value::unary(*ins, value).is_ok(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use EdgeType::*;
+    use Instruction::*;
+
+    fn instructions(cfg: &ControlFlowGraph, id: usize) -> Vec<Instruction> {
+        cfg.find_block(id).unwrap().borrow().instructions.clone()
+    }
+
+    #[test]
+    fn removes_code_after_jump_and_unreachable_blocks() {
+        let mut cfg = ControlFlowGraph::from_blocks(
+            vec![
+                vec![Jmp(4), LdInt(1), StStatic(0)],
+                vec![LdInt(2), StStatic(0)],
+                vec![LdInt(3), StStatic(0)],
+            ],
+            &[
+                (0, 2, FallThrough),
+                (2, 4, Branch),
+                (2, 3, FallThrough),
+                (3, 4, FallThrough),
+                (4, 1, FallThrough),
+            ],
+        );
+        let mut dce = DeadCodeElimination::new();
+        assert!(dce.run(&mut cfg));
+
+        assert_eq!(instructions(&cfg, 2), vec![Jmp(4)]);
+        assert!(cfg.find_block(3).is_none());
+        assert_eq!(instructions(&cfg, 4), vec![LdInt(3), StStatic(0)]);
+        assert_eq!(dce.removed_blocks, 1);
+        assert_eq!(dce.removed_instructions, 4);
+    }
+
+    #[test]
+    fn removes_overwritten_store() {
+        let mut cfg = ControlFlowGraph::from_blocks(
+            vec![vec![
+                LdInt(1),
+                StLocal(0),
+                LdInt(2),
+                StLocal(0),
+                LdLocal(0),
+                StStatic(0),
+            ]],
+            &[(0, 2, FallThrough), (2, 1, FallThrough)],
+        );
+        assert!(DeadCodeElimination::new().run(&mut cfg));
+
+        assert_eq!(
+            instructions(&cfg, 2),
+            vec![LdInt(2), StLocal(0), LdLocal(0), StStatic(0)]
+        );
+    }
+
+    #[test]
+    fn keeps_popped_arithmetic_that_may_trap() {
+        let mut cfg = ControlFlowGraph::from_blocks(
+            vec![vec![
+                LdLocal(0),
+                LdInt(1),
+                Add,
+                Pop(1),
+                LdLocal(0),
+                LdInt(1),
+                Eq,
+                Pop(1),
+                LdStatic(0),
+                Pop(1),
+            ]],
+            &[(0, 2, FallThrough), (2, 1, FallThrough)],
+        );
+        assert!(DeadCodeElimination::new().run(&mut cfg));
+
+        assert_eq!(
+            instructions(&cfg, 2),
+            vec![LdLocal(0), LdInt(1), Add, Pop(1), LdStatic(0), Pop(1)]
+        );
+    }
+
+    #[test]
+    fn removes_popped_arithmetic_on_constants() {
+        let one = LdFloat(1f64.to_bits());
+        let mut cfg = ControlFlowGraph::from_blocks(
+            vec![vec![
+                LdInt(2),
+                LdInt(3),
+                Add,
+                Pop(1),
+                one,
+                Neg,
+                Pop(1),
+                LdInt(1),
+                LdInt(0),
+                Div,
+                Pop(1),
+                LdInt(1),
+                one,
+                Add,
+                Pop(1),
+            ]],
+            &[(0, 2, FallThrough), (2, 1, FallThrough)],
+        );
+        assert!(DeadCodeElimination::new().run(&mut cfg));
+
+        assert_eq!(
+            instructions(&cfg, 2),
+            vec![LdInt(1), LdInt(0), Div, Pop(1), LdInt(1), one, Add, Pop(1)]
+        );
+    }
+
+    #[test]
+    fn removes_code_after_every_terminator() {
+        let terminators = vec![
+            vec![LdInt(1), Ret],
+            vec![RetVoid],
+            vec![LdInt(1), Throw],
+            vec![Unreachable],
+            vec![TailCall(0)],
+        ];
+        for terminator in terminators {
+            let mut code = terminator.clone();
+            code.extend(vec![LdInt(2), StStatic(0)]);
+            let mut cfg = ControlFlowGraph::from_blocks(
+                vec![code, vec![LdInt(3), StStatic(0)]],
+                &[
+                    (0, 2, FallThrough),
+                    (2, 3, FallThrough),
+                    (2, 1, Return),
+                    (3, 1, FallThrough),
+                ],
+            );
+            let mut dce = DeadCodeElimination::new();
+            assert!(dce.run(&mut cfg));
+
+            assert_eq!(instructions(&cfg, 2), terminator);
+            assert!(cfg.find_block(3).is_none());
+            assert_eq!(dce.removed_instructions, 4);
+        }
+    }
+}
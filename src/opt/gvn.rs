@@ -0,0 +1,474 @@
+use crate::analysis::dom::*;
+use crate::analysis::effects::*;
+use crate::block::*;
+use crate::cfg::ControlFlowGraph;
+use crate::instructions::Instruction;
+use crate::module::Function;
+
+use std::collections::{HashMap, HashSet};
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+enum Expression {
+    Int(i64),
+    Float(u64),
+    Unary(Instruction, u32),
+    Binary(Instruction, u32, u32),
+    Static(u32),
+    Global(u32),
+    Env(u32),
+    Field(u32, u32),
+}
+
+/// Where an expression was first computed: block id and instruction index.
+type Site = (usize, usize);
+
+#[derive(Clone, Default)]
+struct Table {
+    expressions: HashMap<Expression, (u32, Option<Site>)>,
+    locals: HashMap<u32, u32>,
+}
+
+/// What the blocks between a dominator and a join point may overwrite.
+#[derive(Default)]
+struct Kills {
+    locals: HashSet<u32>,
+    effects: Effects,
+}
+
+impl Kills {
+    fn add(&mut self, ins: &Instruction, summaries: Option<&EffectAnalysis>) {
+        if let Instruction::StLocal(n) = ins {
+            self.locals.insert(*n);
+        }
+        self.effects.union(&Effects::of(ins, summaries));
+    }
+
+    fn apply(&self, table: &mut Table) {
+        for local in self.locals.iter() {
+            table.locals.remove(local);
+        }
+        let effects = &self.effects;
+        table.expressions.retain(|expr, _| match expr {
+            Expression::Static(n) => !effects.may_write_static(*n),
+            Expression::Global(_) => !effects.may_write_global(),
+            Expression::Env(n) => !effects.may_write_env(*n),
+            Expression::Field(..) => !effects.may_write_fields(),
+            _ => true,
+        });
+    }
+}
+
+/// Dominator based global value numbering.
+///
+/// Computations that were already performed in a dominating position with the
+/// same operands are replaced by a load of a temporary local which the first
+/// computation stores into. Loads of statics, globals, environments and
+/// fields are numbered too and are invalidated by stores to the same location,
+/// calls and yield points. With effect summaries, calls only invalidate what
+/// the callee may write.
+#[derive(Default)]
+pub struct GlobalValueNumbering {
+    pub replaced: usize,
+    next_value: u32,
+    redundant: Vec<(Site, Site)>,
+    effects: Option<EffectAnalysis>,
+}
+
+impl GlobalValueNumbering {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_effects(effects: EffectAnalysis) -> Self {
+        Self {
+            effects: Some(effects),
+            ..Default::default()
+        }
+    }
+
+    pub fn run(&mut self, function: &mut Function) -> bool {
+        let cfg = &mut function.cfg;
+        self.next_value = 0;
+        self.redundant.clear();
+
+        let mut dt = DominatorTree::new();
+        dt.analyze(cfg);
+        let root = match dt.get_index(&cfg.get_entry_block()) {
+            Some(root) => root,
+            None => return false,
+        };
+
+        let after_handlers = reached_around_handlers(cfg, &dt);
+        let mut stack = vec![(root, Table::default())];
+        while let Some((n, mut table)) = stack.pop() {
+            let block = dt.get_block(n);
+            // a handler can be entered from the middle of a block, before
+            // what that block computes is available, and so can the joins
+            // its code reaches without passing through the dominator
+            let handles = block
+                .borrow()
+                .in_edges
+                .iter()
+                .any(|edge| edge.borrow().ty == EdgeType::Exception);
+            if handles || after_handlers.contains(&block.borrow().id) {
+                table = Table::default();
+            } else if block.borrow().predecessors.len() > 1 {
+                if let Some(idom) = dt.get_dominator(block.clone()) {
+                    kills_between(&idom, &block, self.effects.as_ref()).apply(&mut table);
+                }
+            }
+            self.number_block(&block, &mut table);
+            for child in dt.dominated[n].iter() {
+                stack.push((*child, table.clone()));
+            }
+        }
+
+        if self.redundant.is_empty() {
+            return false;
+        }
+        self.rewrite(function);
+        true
+    }
+
+    fn fresh(&mut self) -> u32 {
+        self.next_value += 1;
+        self.next_value
+    }
+
+    fn lookup(&mut self, table: &mut Table, expr: Expression, site: Option<Site>) -> u32 {
+        if let Some((value, first)) = table.expressions.get(&expr) {
+            if let (Some(first), Some(site)) = (first, site) {
+                self.redundant.push((site, *first));
+            }
+            return *value;
+        }
+        let value = self.fresh();
+        table.expressions.insert(expr, (value, site));
+        value
+    }
+
+    fn number_block(&mut self, block: &CodeBlockRef, table: &mut Table) {
+        use Instruction::*;
+        let id = block.borrow().id;
+        let mut stack: Vec<u32> = vec![];
+        let pop = |this: &mut Self, stack: &mut Vec<u32>| match stack.pop() {
+            Some(value) => value,
+            None => this.fresh(),
+        };
+
+        for (i, ins) in block.borrow().instructions.iter().enumerate() {
+            let site = Some((id, i));
+            match ins {
+                LdInt(value) => {
+                    let value = self.lookup(table, Expression::Int(*value), None);
+                    stack.push(value);
+                }
+                LdFloat(bits) => {
+                    let value = self.lookup(table, Expression::Float(*bits), None);
+                    stack.push(value);
+                }
+                LdLocal(n) => {
+                    let value = match table.locals.get(n) {
+                        Some(value) => *value,
+                        None => {
+                            let value = self.fresh();
+                            table.locals.insert(*n, value);
+                            value
+                        }
+                    };
+                    stack.push(value);
+                }
+                StLocal(n) => {
+                    let value = pop(self, &mut stack);
+                    table.locals.insert(*n, value);
+                }
+                Dup => {
+                    let value = pop(self, &mut stack);
+                    stack.push(value);
+                    stack.push(value);
+                }
+                Add | Sub | Mul | Div | Mod | Shl | Shr | Eq | Lt | Le | Gt | And | Or | Xor => {
+                    let mut rhs = pop(self, &mut stack);
+                    let mut lhs = pop(self, &mut stack);
+                    if ins.is_commutative() && lhs > rhs {
+                        std::mem::swap(&mut lhs, &mut rhs);
+                    }
+                    let value = self.lookup(table, Expression::Binary(*ins, lhs, rhs), site);
+                    stack.push(value);
+                }
+                Not | Neg | IntToFloat | FloatToInt => {
+                    let operand = pop(self, &mut stack);
+                    let value = self.lookup(table, Expression::Unary(*ins, operand), site);
+                    stack.push(value);
+                }
+                LdStatic(n) => {
+                    let value = self.lookup(table, Expression::Static(*n), site);
+                    stack.push(value);
+                }
+                LdGlobal(n) => {
+                    let value = self.lookup(table, Expression::Global(*n), site);
+                    stack.push(value);
+                }
+                LdEnv(n) => {
+                    let value = self.lookup(table, Expression::Env(*n), site);
+                    stack.push(value);
+                }
+                LdField => {
+                    let key = pop(self, &mut stack);
+                    let object = pop(self, &mut stack);
+                    let value = self.lookup(table, Expression::Field(object, key), site);
+                    stack.push(value);
+                }
+                // the same field as `LdInt(n), LdField`
+                LdFieldAt(n) => {
+                    let object = pop(self, &mut stack);
+                    let key = self.lookup(table, Expression::Int(*n as i64), None);
+                    let value = self.lookup(table, Expression::Field(object, key), site);
+                    stack.push(value);
+                }
+                Call(_) | TailCall(_) | CallClosure(_) => {
+                    let mut kills = Kills::default();
+                    kills.add(ins, self.effects.as_ref());
+                    kills.apply(table);
+                    // the callee's arity is unknown here
+                    stack.clear();
+                    let value = self.fresh();
+                    stack.push(value);
+                }
+                _ => {
+                    let mut kills = Kills::default();
+                    kills.add(ins, self.effects.as_ref());
+                    kills.apply(table);
+                    let (pops, pushes) = ins.stack_effect().unwrap();
+                    for _ in 0..pops {
+                        pop(self, &mut stack);
+                    }
+                    for _ in 0..pushes {
+                        let value = self.fresh();
+                        stack.push(value);
+                    }
+                }
+            }
+        }
+    }
+
+    fn rewrite(&mut self, function: &mut Function) {
+        let cfg = &mut function.cfg;
+        let mut next_local = std::cmp::max(function.locals, cfg.first_free_local());
+
+        let mut temps: HashMap<Site, u32> = HashMap::new();
+        for (_, first) in self.redundant.iter() {
+            temps.entry(*first).or_insert_with(|| {
+                next_local += 1;
+                next_local - 1
+            });
+        }
+
+        // (index, instructions replacing the one at index) per block
+        let mut edits: HashMap<usize, Vec<(usize, Vec<Instruction>)>> = HashMap::new();
+        for block in cfg.blocks.iter() {
+            let block = block.borrow();
+            for (i, ins) in block.instructions.iter().enumerate() {
+                if let Some(temp) = temps.get(&(block.id, i)) {
+                    edits
+                        .entry(block.id)
+                        .or_default()
+                        .push((i, vec![*ins, Instruction::Dup, Instruction::StLocal(*temp)]));
+                }
+            }
+        }
+        for (site, first) in self.redundant.iter() {
+            let block = cfg.find_block(site.0).unwrap();
+            let ins = block.borrow().instructions[site.1];
+            let (pops, _) = ins.stack_effect().unwrap();
+            let mut replacement = vec![];
+            if pops > 0 {
+                replacement.push(Instruction::Pop(pops));
+            }
+            replacement.push(Instruction::LdLocal(temps[first]));
+            edits.entry(site.0).or_default().push((site.1, replacement));
+            self.replaced += 1;
+        }
+
+        for block in cfg.blocks.iter_mut() {
+            let id = block.borrow().id;
+            if let Some(mut edits) = edits.remove(&id) {
+                edits.sort_by_key(|edit| std::cmp::Reverse(edit.0));
+                let mut block = block.borrow_mut();
+                for (i, replacement) in edits {
+                    block.instructions.splice(i..=i, replacement);
+                }
+            }
+        }
+        function.locals = next_local;
+        cfg.remove_dead_exception_edges();
+    }
+}
+
+// Collects the blocks reachable from a handler entry that the handler does
+// not dominate. Their dominator's values may not have been computed when
+// control arrives through the handler.
+fn reached_around_handlers(cfg: &ControlFlowGraph, dt: &DominatorTree) -> HashSet<usize> {
+    let mut reached = HashSet::new();
+    for handler in cfg.blocks.iter() {
+        let handles = handler
+            .borrow()
+            .in_edges
+            .iter()
+            .any(|edge| edge.borrow().ty == EdgeType::Exception);
+        if !handles || dt.get_index(handler).is_none() {
+            continue;
+        }
+        let mut visited = HashSet::new();
+        let mut stack = handler.borrow().successors.clone();
+        while let Some(current) = stack.pop() {
+            if !visited.insert(current.borrow().id) {
+                continue;
+            }
+            if !dt.dominates(handler.clone(), current.clone(), cfg) {
+                reached.insert(current.borrow().id);
+            }
+            stack.extend(current.borrow().successors.iter().cloned());
+        }
+    }
+    reached
+}
+
+// Collects the side effects of every block on a path from `dominator` to
+// `block`, not counting the dominator itself.
+fn kills_between(
+    dominator: &CodeBlockRef,
+    block: &CodeBlockRef,
+    summaries: Option<&EffectAnalysis>,
+) -> Kills {
+    let mut kills = Kills::default();
+    let mut visited = HashSet::new();
+    visited.insert(dominator.borrow().id);
+    let mut stack = block.borrow().predecessors.clone();
+    while let Some(current) = stack.pop() {
+        if !visited.insert(current.borrow().id) {
+            continue;
+        }
+        for ins in current.borrow().instructions.iter() {
+            kills.add(ins, summaries);
+        }
+        stack.extend(current.borrow().predecessors.iter().cloned());
+    }
+    kills
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use EdgeType::*;
+    use Instruction::*;
+
+    fn instructions(function: &Function, id: usize) -> Vec<Instruction> {
+        let block = function.cfg.find_block(id).unwrap();
+        let instructions = block.borrow().instructions.clone();
+        instructions
+    }
+
+    #[test]
+    fn reuses_commuted_product_of_dominator() {
+        let cfg = ControlFlowGraph::from_blocks(
+            vec![
+                vec![LdLocal(0), LdLocal(1), Mul, StStatic(0)],
+                vec![LdLocal(1), LdLocal(0), Mul, StStatic(1)],
+            ],
+            &[
+                (0, 2, FallThrough),
+                (2, 3, FallThrough),
+                (3, 1, FallThrough),
+            ],
+        );
+        let mut function = Function::new("f", 2, cfg);
+        let mut gvn = GlobalValueNumbering::new();
+        assert!(gvn.run(&mut function));
+
+        assert_eq!(
+            instructions(&function, 2),
+            vec![LdLocal(0), LdLocal(1), Mul, Dup, StLocal(2), StStatic(0)]
+        );
+        assert_eq!(
+            instructions(&function, 3),
+            vec![LdLocal(1), LdLocal(0), Pop(2), LdLocal(2), StStatic(1)]
+        );
+        assert_eq!(gvn.replaced, 1);
+        assert_eq!(function.locals, 3);
+    }
+
+    // 2 branches around 3 to 4, which reads static 0 again.
+    fn diamond(side: Vec<Instruction>) -> Function {
+        let cfg = ControlFlowGraph::from_blocks(
+            vec![
+                vec![LdStatic(0), Pop(1), LdLocal(0), JmpZ(4)],
+                side,
+                vec![LdStatic(0), StStatic(1)],
+            ],
+            &[
+                (0, 2, FallThrough),
+                (2, 4, Branch),
+                (2, 3, FallThrough),
+                (3, 4, FallThrough),
+                (4, 1, FallThrough),
+            ],
+        );
+        Function::new("f", 1, cfg)
+    }
+
+    #[test]
+    fn store_on_one_path_kills_load_at_join() {
+        let mut function = diamond(vec![LdInt(1), StStatic(1)]);
+        assert!(GlobalValueNumbering::new().run(&mut function));
+        assert_eq!(instructions(&function, 4), vec![LdLocal(1), StStatic(1)]);
+
+        let mut function = diamond(vec![LdInt(1), StStatic(0)]);
+        assert!(!GlobalValueNumbering::new().run(&mut function));
+        assert_eq!(instructions(&function, 4), vec![LdStatic(0), StStatic(1)]);
+    }
+
+    #[test]
+    fn temporaries_start_above_declared_locals() {
+        let cfg = ControlFlowGraph::from_blocks(
+            vec![vec![LdStatic(0), StStatic(1), LdStatic(0), StStatic(1)]],
+            &[(0, 2, FallThrough), (2, 1, FallThrough)],
+        );
+        let mut function = Function::new("f", 0, cfg);
+        function.locals = 3;
+        assert!(GlobalValueNumbering::new().run(&mut function));
+
+        let expected = vec![
+            LdStatic(0),
+            Dup,
+            StLocal(3),
+            StStatic(1),
+            LdLocal(3),
+            StStatic(1),
+        ];
+        assert_eq!(instructions(&function, 2), expected);
+        assert_eq!(function.locals, 4);
+    }
+
+    #[test]
+    fn join_reached_through_handler_recomputes() {
+        // 3 handles the call in 2, before 2 has computed the product
+        let protected = vec![Call(0), LdLocal(0), LdLocal(1), Mul, StLocal(2), Jmp(4)];
+        let join = vec![LdLocal(0), LdLocal(1), Mul, Ret];
+        let cfg = ControlFlowGraph::from_blocks(
+            vec![protected.clone(), vec![Pop(1), Jmp(4)], join.clone()],
+            &[
+                (0, 2, FallThrough),
+                (2, 3, Exception),
+                (2, 4, Branch),
+                (3, 4, Branch),
+                (4, 1, Return),
+            ],
+        );
+        let mut function = Function::new("f", 2, cfg);
+        function.locals = 3;
+        assert!(!GlobalValueNumbering::new().run(&mut function));
+        assert_eq!(instructions(&function, 2), protected);
+        assert_eq!(instructions(&function, 4), join);
+    }
+}
@@ -0,0 +1,471 @@
+use crate::analysis::callgraph::*;
+use crate::analysis::liveness::*;
+use crate::block::*;
+use crate::cfg::*;
+use crate::instructions::Instruction;
+use crate::module::*;
+use crate::verifier;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// Replaces calls to small functions by a copy of the callee's blocks.
+///
+/// The callee's locals are moved to fresh slots of the caller and the
+/// arguments are stored into them from the operand stack. Returns become
+/// jumps to the code after the call that leave the callee's result on top of
+/// the caller's stack, and so does falling off the copied exit block. A
+/// return without a value pushes nil from a fresh local that is never stored.
+/// Functions are processed bottom-up so that a callee has already been
+/// inlined into when its size is checked.
+pub struct Inliner {
+    pub inlined: usize,
+    /// Callees with more instructions than this are left alone.
+    pub max_size: usize,
+}
+
+impl Default for Inliner {
+    fn default() -> Self {
+        Self {
+            inlined: 0,
+            max_size: 16,
+        }
+    }
+}
+
+/// What the caller needs to know to inline a call to a function.
+struct Callee {
+    /// Operand stack height before the `Ret` or `RetVoid` ending a block, by
+    /// block id.
+    returns: HashMap<usize, u32>,
+    /// Operand stack height at the end of the exit block, None if control
+    /// never falls off it.
+    exit_height: Option<u32>,
+    /// Whether some return leaves more than its result on the stack.
+    needs_temp: bool,
+    /// Whether some return has no value and has to push nil.
+    needs_nil: bool,
+}
+
+impl Inliner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn run(&mut self, module: &mut Module) -> bool {
+        let mut cg = CallGraph::new();
+        cg.analyze(module);
+        let inlined = self.inlined;
+        for f in cg.bottom_up_order() {
+            // take the caller out so the callee can be read while it changes
+            let mut caller = std::mem::take(&mut module.functions[f as usize]);
+            let mut worklist = caller.cfg.blocks.clone();
+            while let Some(block) = worklist.pop() {
+                let site = block
+                    .borrow()
+                    .instructions
+                    .iter()
+                    .enumerate()
+                    .find_map(|(i, ins)| match ins {
+                        Instruction::Call(n) => {
+                            let info = self.inlinable(module, &cg, f, *n)?;
+                            Some((i, *n, info))
+                        }
+                        _ => None,
+                    });
+                if let Some((i, n, info)) = site {
+                    let callee = &module.functions[n as usize];
+                    let rest = inline_call(&mut caller, block, i, callee, info);
+                    worklist.push(rest);
+                    self.inlined += 1;
+                }
+            }
+            module.functions[f as usize] = caller;
+        }
+        self.inlined != inlined
+    }
+
+    fn inlinable(&self, module: &Module, cg: &CallGraph, caller: u32, n: u32) -> Option<Callee> {
+        let function = module.resolve(n)?;
+        if n == caller || cg.is_recursive(n) || function.cfg.ins_count() > self.max_size {
+            return None;
+        }
+        // environment slots, closures, throws, traps and tail calls belong to
+        // the callee's own frame
+        let frame_bound = function.cfg.blocks.iter().any(|block| {
+            block.borrow().instructions.iter().any(|ins| {
+                matches!(
+                    ins,
+                    Instruction::Throw
+                        | Instruction::TailCall(_)
+                        | Instruction::Trap(_)
+                        | Instruction::Unreachable
+                        | Instruction::LdEnv(_)
+                        | Instruction::StEnv(_)
+                        | Instruction::MkClosure(_)
+                )
+            })
+        });
+        if frame_bound {
+            return None;
+        }
+        // a call starts with its locals cleared, a copy in a loop would not
+        let mut liveness = LivenessAnalysis::new();
+        liveness.analyze(&function.cfg);
+        let entry = function.cfg.entry.borrow().id;
+        let reads_uninitialized = liveness
+            .live_in(entry)
+            .map(|live| {
+                live.iter()
+                    .any(|slot| matches!(slot, Slot::Local(n) if *n >= function.params))
+            })
+            .unwrap_or(false);
+        if reads_uninitialized {
+            return None;
+        }
+
+        let heights = verifier::stack_heights(module, n).ok()?;
+        let height_at = |block: &CodeBlock, end: usize| {
+            let mut height = *heights.get(&block.id)?;
+            for ins in block.instructions[..end].iter() {
+                let (pops, pushes) = module.stack_effect(ins)?;
+                height = height - pops + pushes;
+            }
+            Some(height)
+        };
+        let mut callee = Callee {
+            returns: HashMap::new(),
+            exit_height: None,
+            needs_temp: false,
+            needs_nil: false,
+        };
+        for block in function.cfg.blocks.iter() {
+            let block = block.borrow();
+            let len = block.instructions.len();
+            let value = match block.instructions.last() {
+                Some(Instruction::Ret) => true,
+                Some(Instruction::RetVoid) => false,
+                _ => continue,
+            };
+            let height = height_at(&block, len - 1)?;
+            callee.needs_temp |= value && height > 1;
+            callee.needs_nil |= !value;
+            callee.returns.insert(block.id, height);
+        }
+
+        let exit = function.cfg.exit.borrow();
+        if exit.instructions.last().is_some_and(|ins| ins.is_branch()) {
+            return None;
+        }
+        let falls_off = !exit.instructions.last().is_some_and(|ins| ins.is_return());
+        if falls_off && heights.contains_key(&exit.id) {
+            // without a value on the stack the call returns nil
+            let height = height_at(&exit, exit.instructions.len())?;
+            callee.needs_temp |= height > 1;
+            callee.needs_nil |= height == 0;
+            callee.exit_height = Some(height);
+        }
+        Some(callee)
+    }
+}
+
+// Inlines the call at `index` of `block` and returns the block holding the
+// code that followed it.
+fn inline_call(
+    caller: &mut Function,
+    mut block: CodeBlockRef,
+    index: usize,
+    callee: &Function,
+    info: Callee,
+) -> CodeBlockRef {
+    let base = std::cmp::max(caller.locals, caller.cfg.first_free_local());
+    let temp = base + callee.locals;
+    caller.locals = temp;
+    if info.needs_temp {
+        caller.locals += 1;
+    }
+    let nil = caller.locals;
+    if info.needs_nil {
+        caller.locals += 1;
+    }
+    let cfg = &mut caller.cfg;
+
+    // move the code after the call and the outgoing edges to a new block
+    let rest = {
+        let mut block = block.borrow_mut();
+        CodeBlockRef::new(CodeBlock {
+            id: cfg.new_id(),
+            instructions: block.instructions.split_off(index + 1),
+            switch: block.switch.take(),
+            handler: block.handler,
+            ..Default::default()
+        })
+    };
+    cfg.insert_block(rest.clone());
+    if cfg.exit.ptr_eq(&block) {
+        cfg.exit = rest.clone();
+    }
+    let out_edges = block.borrow().out_edges.clone();
+    for edge in out_edges {
+        let tail = edge.borrow().tail.as_ref().unwrap().clone();
+        let ty = edge.borrow().ty;
+        cfg.remove_edge(edge);
+        // exception edges are recreated below for the blocks that may throw
+        if ty != EdgeType::Exception {
+            link(cfg, &rest, &tail, ty);
+        }
+    }
+
+    {
+        let mut block = block.borrow_mut();
+        block.instructions.pop();
+        for n in (0..callee.params).rev() {
+            block.instructions.push(Instruction::StLocal(base + n));
+        }
+    }
+
+    let mut copies = cfg.clone_subgraph(&callee.cfg.blocks);
+    for copy in copies.values_mut() {
+        for ins in copy.borrow_mut().instructions.iter_mut() {
+            match ins {
+                Instruction::LdLocal(n) | Instruction::StLocal(n) => *n += base,
+                _ => (),
+            }
+        }
+    }
+    let entry = copies[&callee.cfg.entry.borrow().id].clone();
+    link(cfg, &block, &entry, EdgeType::FallThrough);
+
+    // returns jump to the code after the call with the result on the stack
+    let target = rest.borrow().id as u32;
+    for (id, height) in info.returns.iter() {
+        let mut copy = copies[id].clone();
+        {
+            let mut copy = copy.borrow_mut();
+            let value = copy.instructions.pop() == Some(Instruction::Ret);
+            copy.instructions.extend(result(*height, value, temp, nil));
+            copy.instructions.push(Instruction::Jmp(target));
+        }
+        let returns = copy
+            .borrow()
+            .out_edges
+            .iter()
+            .filter(|edge| edge.borrow().ty == EdgeType::Return)
+            .cloned()
+            .collect::<Vec<_>>();
+        for edge in returns {
+            cfg.remove_edge(edge);
+        }
+        link(cfg, &copy, &rest, EdgeType::Branch);
+    }
+    let exit_id = callee.cfg.exit.borrow().id;
+    let mut exit = copies[&exit_id].clone();
+    match info.exit_height {
+        Some(height) => {
+            let code = result(height, height > 0, temp, nil);
+            exit.borrow_mut().instructions.extend(code);
+            link(cfg, &exit, &rest, EdgeType::FallThrough);
+        }
+        // an exit block only the returns led to is dead now
+        None if exit.borrow().in_edges.is_empty() => {
+            cfg.remove_block(&exit);
+            copies.remove(&exit_id);
+        }
+        None => (),
+    }
+
+    // what the callee does not catch itself goes to the handler of the call
+    let handler = block.borrow().handler;
+    if let Some(handler) = handler.and_then(|handler| cfg.find_block(handler)) {
+        let id = handler.borrow().id;
+        let mut protected = vec![block, rest.clone()];
+        protected.extend(copies.into_values().filter(|copy| copy.borrow().handler.is_none()));
+        for mut head in protected {
+            head.borrow_mut().handler = Some(id);
+            if head.borrow().may_throw() {
+                link(cfg, &head, &handler, EdgeType::Exception);
+            }
+        }
+    }
+    rest
+}
+
+// Leaves just the callee's result on a stack `height` values high: the value
+// on top, or nil from the never stored local `nil` for a return without one.
+fn result(height: u32, value: bool, temp: u32, nil: u32) -> Vec<Instruction> {
+    match (value, height) {
+        (true, 1) => vec![],
+        (true, _) => vec![
+            Instruction::StLocal(temp),
+            Instruction::Pop(height - 1),
+            Instruction::LdLocal(temp),
+        ],
+        (false, 0) => vec![Instruction::LdLocal(nil)],
+        (false, _) => vec![Instruction::Pop(height), Instruction::LdLocal(nil)],
+    }
+}
+
+fn link(cfg: &mut ControlFlowGraph, head: &CodeBlockRef, tail: &CodeBlockRef, ty: EdgeType) {
+    cfg.insert_edge(Rc::new(RefCell::new(Edge {
+        head: Some(head.clone()),
+        tail: Some(tail.clone()),
+        ty,
+    })));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::{Interpreter, Value};
+    use EdgeType::*;
+    use Instruction::*;
+
+    fn straight_line(params: u32, instructions: Vec<Instruction>) -> Function {
+        let cfg = ControlFlowGraph::from_blocks(
+            vec![instructions],
+            &[(0, 2, FallThrough), (2, 1, FallThrough)],
+        );
+        Function::new("f", params, cfg)
+    }
+
+    // The instructions on the only path through a function without branches.
+    fn trace(function: &Function) -> Vec<Instruction> {
+        let mut code = vec![];
+        let mut block = function.cfg.entry.clone();
+        loop {
+            code.extend(block.borrow().instructions.iter().copied());
+            let next = match block.borrow().successors.as_slice() {
+                [] => break,
+                [next] => next.clone(),
+                _ => panic!("branch in block {}", block.borrow().id),
+            };
+            block = next;
+        }
+        code
+    }
+
+    #[test]
+    fn moves_arguments_to_callee_locals() {
+        let mut module = Module::new();
+        module.add_function(straight_line(2, vec![LdLocal(0), LdLocal(1), Mul]));
+        module.add_function(straight_line(0, vec![LdInt(6), LdInt(7), Call(0)]));
+        let mut inliner = Inliner::new();
+        assert!(inliner.run(&mut module));
+
+        assert_eq!(
+            trace(&module.functions[1]),
+            vec![
+                LdInt(6),
+                LdInt(7),
+                StLocal(1),
+                StLocal(0),
+                LdLocal(0),
+                LdLocal(1),
+                Mul
+            ]
+        );
+        assert_eq!(module.functions[1].locals, 2);
+        assert_eq!(inliner.inlined, 1);
+        assert_eq!(module.verify(), Ok(()));
+        let result = Interpreter::new(&module).call(1, &[]);
+        assert_eq!(result, Ok(Value::Int(42)));
+    }
+
+    #[test]
+    fn keeps_only_top_of_callee_stack() {
+        let mut module = Module::new();
+        module.add_function(straight_line(1, vec![LdInt(9), LdLocal(0)]));
+        module.add_function(straight_line(0, vec![LdInt(3), Call(0)]));
+        assert!(Inliner::new().run(&mut module));
+
+        assert_eq!(
+            trace(&module.functions[1]),
+            vec![
+                LdInt(3),
+                StLocal(0),
+                LdInt(9),
+                LdLocal(0),
+                StLocal(1),
+                Pop(1),
+                LdLocal(1)
+            ]
+        );
+        let result = Interpreter::new(&module).call(1, &[]);
+        assert_eq!(result, Ok(Value::Int(3)));
+    }
+
+    #[test]
+    fn leaves_recursive_callees() {
+        let mut module = Module::new();
+        module.add_function(straight_line(0, vec![Call(0)]));
+        module.add_function(straight_line(0, vec![Call(0)]));
+        assert!(!Inliner::new().run(&mut module));
+        assert_eq!(trace(&module.functions[1]), vec![Call(0)]);
+    }
+
+    #[test]
+    fn returns_become_jumps() {
+        // f(a): if a == 0 { return 5 }; 9; return a * 2, leaving 9 below
+        let f = ControlFlowGraph::from_blocks(
+            vec![
+                vec![LdLocal(0), JmpZ(4)],
+                vec![LdInt(9), LdLocal(0), LdInt(2), Mul, Ret],
+                vec![LdInt(5), Ret],
+            ],
+            &[
+                (0, 2, FallThrough),
+                (2, 4, Branch),
+                (2, 3, FallThrough),
+                (3, 1, Return),
+                (4, 1, Return),
+            ],
+        );
+        // g(a): return nothing
+        let g = ControlFlowGraph::from_blocks(
+            vec![vec![LdLocal(0), Pop(1), RetVoid]],
+            &[(0, 2, FallThrough), (2, 1, Return)],
+        );
+        // h(a): f(a); g(1); return the result of f
+        let h = ControlFlowGraph::from_blocks(
+            vec![vec![LdLocal(0), Call(0), LdInt(1), Call(1), Pop(1), Ret]],
+            &[(0, 2, FallThrough), (2, 1, Return)],
+        );
+        let mut module = Module::new();
+        module.add_function(Function::new("f", 1, f));
+        module.add_function(Function::new("g", 1, g));
+        module.add_function(Function::new("h", 1, h));
+        let mut inliner = Inliner::new();
+        assert!(inliner.run(&mut module));
+        assert_eq!(inliner.inlined, 2);
+
+        // 3 continues after the call of f, 9 after the call of g
+        let block = |id| {
+            let block = module.functions[2].cfg.find_block(id).unwrap();
+            let instructions = block.borrow().instructions.clone();
+            instructions
+        };
+        let ret = vec![
+            LdInt(9),
+            LdLocal(1),
+            LdInt(2),
+            Mul,
+            StLocal(2),
+            Pop(1),
+            LdLocal(2),
+            Jmp(3),
+        ];
+        assert_eq!(block(7), ret);
+        assert_eq!(block(8), vec![LdInt(5), Jmp(3)]);
+        assert_eq!(block(3), vec![LdInt(1), StLocal(3)]);
+        let ret_void = vec![LdLocal(3), Pop(1), LdLocal(4), Jmp(9)];
+        assert_eq!(block(12), ret_void);
+        assert_eq!(block(9), vec![Pop(1), Ret]);
+        assert_eq!(module.functions[2].locals, 5);
+
+        assert_eq!(module.verify(), Ok(()));
+        let mut interpreter = Interpreter::new(&module);
+        assert_eq!(interpreter.call(2, &[Value::Int(0)]), Ok(Value::Int(5)));
+        assert_eq!(interpreter.call(2, &[Value::Int(3)]), Ok(Value::Int(6)));
+    }
+}
@@ -0,0 +1,509 @@
+use crate::analysis::cycleanalysis::*;
+use crate::analysis::dom::*;
+use crate::analysis::effects::*;
+use crate::block::*;
+use crate::cfg::*;
+use crate::instructions::Instruction;
+use crate::module::Function;
+
+use std::collections::{HashMap, HashSet};
+
+pub struct Loop {
+    pub header: CodeBlockRef,
+    /// Ids of the blocks in the loop, header included.
+    pub body: HashSet<usize>,
+}
+
+/// Finds the natural loops of `cfg`, innermost first. Back edges whose tail
+/// does not dominate their head (irreducible control flow) are ignored.
+pub fn find_loops(cfg: &ControlFlowGraph, dt: &DominatorTree, ca: &CycleAnalysis) -> Vec<Loop> {
+    let mut loops: HashMap<usize, Loop> = HashMap::new();
+    for edge in ca.all_back_edges().iter() {
+        let latch = edge.borrow().head.as_ref().unwrap().clone();
+        let header = edge.borrow().tail.as_ref().unwrap().clone();
+        if dt.get_index(&latch).is_none() || !dt.dominates(header.clone(), latch.clone(), cfg) {
+            continue;
+        }
+
+        let id = header.borrow().id;
+        let lp = loops.entry(id).or_insert_with(|| Loop {
+            header: header.clone(),
+            body: vec![id].into_iter().collect(),
+        });
+        let mut stack = vec![latch];
+        while let Some(block) = stack.pop() {
+            if lp.body.insert(block.borrow().id) {
+                stack.extend(block.borrow().predecessors.iter().cloned());
+            }
+        }
+    }
+
+    let mut loops = loops.into_values().collect::<Vec<_>>();
+    loops.sort_by_key(|lp| (lp.body.len(), lp.header.borrow().id));
+    loops
+}
+
+/// Returns the single block through which control enters `lp`, creating it
+/// on the entering edges when there is none yet.
+pub fn get_or_insert_preheader(cfg: &mut ControlFlowGraph, lp: &Loop) -> Option<CodeBlockRef> {
+    let entering = lp
+        .header
+        .borrow()
+        .in_edges
+        .iter()
+        .filter(|edge| {
+            let head = edge.borrow().head.as_ref().unwrap().borrow().id;
+            !lp.body.contains(&head)
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+
+    // a handler starts with the exception on the stack, unlike other entries
+    let handles = entering
+        .iter()
+        .any(|edge| edge.borrow().ty == EdgeType::Exception);
+    if entering.is_empty() || handles {
+        return None;
+    }
+    if entering.len() == 1 {
+        let head = entering[0].borrow().head.as_ref().unwrap().clone();
+        if head.borrow().successors.len() == 1 && head.borrow().id != cfg.entry.borrow().id {
+            return Some(head);
+        }
+    }
+
+    let preheader = cfg.insert_block_on_edge(entering[0].clone());
+    for edge in entering.into_iter().skip(1) {
+        cfg.redirect_edge(edge, preheader.clone());
+    }
+    Some(preheader)
+}
+
+/// Loop-invariant code motion.
+///
+/// Side-effect free expressions inside a loop whose operands are not written
+/// by the loop are computed once in the loop preheader and replaced by a load
+/// of a temporary local. Loads of statics, globals and environments are only
+/// considered invariant if the loop contains no store to them, no yield point
+/// and no call that may write them. Expressions that may trap are only hoisted
+/// from code that runs whenever the loop is entered, so that the preheader
+/// does not trap where the loop would have been left or skipped before.
+#[derive(Default)]
+pub struct LoopInvariantCodeMotion {
+    pub hoisted: usize,
+    effects: Option<EffectAnalysis>,
+}
+
+struct Invariance {
+    locals: HashSet<u32>,
+    effects: Effects,
+}
+
+impl Invariance {
+    fn new(cfg: &ControlFlowGraph, lp: &Loop, summaries: Option<&EffectAnalysis>) -> Self {
+        let mut this = Self {
+            locals: HashSet::new(),
+            effects: Effects::default(),
+        };
+        for block in cfg.blocks.iter() {
+            if !lp.body.contains(&block.borrow().id) {
+                continue;
+            }
+            for ins in block.borrow().instructions.iter() {
+                if let Instruction::StLocal(n) = ins {
+                    this.locals.insert(*n);
+                }
+                this.effects.union(&Effects::of(ins, summaries));
+            }
+        }
+        this
+    }
+
+    fn is_invariant(&self, ins: &Instruction) -> bool {
+        use Instruction::*;
+        match ins {
+            LdInt(_) | LdFloat(_) | Add | Sub | Mul | Shl | Shr => true,
+            Eq | Lt | Le | Gt | Not | And | Or | Xor | Neg | IntToFloat | FloatToInt => true,
+            LdLocal(n) => !self.locals.contains(n),
+            LdStatic(n) => !self.effects.may_write_static(*n),
+            LdGlobal(_) => !self.effects.may_write_global(),
+            LdEnv(n) => !self.effects.may_write_env(*n),
+            _ => false,
+        }
+    }
+}
+
+impl LoopInvariantCodeMotion {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_effects(effects: EffectAnalysis) -> Self {
+        Self {
+            effects: Some(effects),
+            ..Default::default()
+        }
+    }
+
+    pub fn run(&mut self, function: &mut Function) -> bool {
+        let mut changed = false;
+        // every hoist creates blocks and moves code, so start over from fresh
+        // analyses until nothing is left to hoist
+        loop {
+            let cfg = &function.cfg;
+            let mut dt = DominatorTree::new();
+            dt.analyze(cfg);
+            let mut ca = CycleAnalysis::new();
+            ca.analyze(cfg);
+
+            let mut hoisted = false;
+            for lp in find_loops(cfg, &dt, &ca) {
+                if self.hoist(function, &dt, &lp) {
+                    hoisted = true;
+                    break;
+                }
+            }
+            if !hoisted {
+                break;
+            }
+            changed = true;
+        }
+        changed
+    }
+
+    fn hoist(&mut self, function: &mut Function, dt: &DominatorTree, lp: &Loop) -> bool {
+        let cfg = &mut function.cfg;
+        let entering = lp
+            .header
+            .borrow()
+            .in_edges
+            .iter()
+            .filter(|edge| {
+                let head = edge.borrow().head.as_ref().unwrap().borrow().id;
+                !lp.body.contains(&head)
+            })
+            .map(|edge| edge.borrow().ty)
+            .collect::<Vec<_>>();
+        // without a preheader there is nowhere to hoist to, which has to be
+        // known before the body is rewritten
+        if entering.is_empty() || entering.contains(&EdgeType::Exception) {
+            return false;
+        }
+        let invariance = Invariance::new(cfg, lp, self.effects.as_ref());
+        let always = always_executed(cfg, dt, lp);
+        let mut next_local = std::cmp::max(function.locals, cfg.first_free_local());
+        let mut temps: HashMap<Vec<Instruction>, u32> = HashMap::new();
+        let mut hoisted: Vec<Vec<Instruction>> = vec![];
+
+        for block in cfg.blocks.iter_mut() {
+            if !lp.body.contains(&block.borrow().id) {
+                continue;
+            }
+            let always = always.contains(&block.borrow().id);
+            let mut instructions = block.borrow().instructions.clone();
+            let mut end = instructions.len();
+            let mut changed = false;
+            while end > 0 {
+                let start = match invariant_expression(&instructions, end - 1, &invariance) {
+                    Some(start) => start,
+                    None => {
+                        end -= 1;
+                        continue;
+                    }
+                };
+                let expr = instructions[start..end].to_vec();
+                // code before the expression may leave the loop by throwing
+                let reached = always && !instructions[..start].iter().any(|ins| ins.may_throw());
+                if may_trap(&expr) && !reached && !temps.contains_key(&expr) {
+                    end -= 1;
+                    continue;
+                }
+                let temp = *temps.entry(expr.clone()).or_insert_with(|| {
+                    hoisted.push(expr);
+                    next_local += 1;
+                    next_local - 1
+                });
+                instructions.splice(start..end, vec![Instruction::LdLocal(temp)]);
+                self.hoisted += 1;
+                changed = true;
+                end = start;
+            }
+            if changed {
+                block.borrow_mut().instructions = instructions;
+            }
+        }
+
+        if hoisted.is_empty() {
+            return false;
+        }
+
+        let mut preheader = get_or_insert_preheader(cfg, lp).unwrap();
+        let mut code = vec![];
+        for expr in hoisted {
+            let temp = temps[&expr];
+            code.extend(expr);
+            code.push(Instruction::StLocal(temp));
+        }
+        let mut preheader = preheader.borrow_mut();
+        let at = match preheader.instructions.last() {
+            Some(ins) if ins.is_branch() => preheader.instructions.len() - 1,
+            _ => preheader.instructions.len(),
+        };
+        preheader.instructions.splice(at..at, code);
+        drop(preheader);
+        function.cfg.remove_dead_exception_edges();
+        function.locals = next_local;
+        true
+    }
+}
+
+// The blocks of `lp` that run whenever the loop is entered: those dominating
+// every block control can leave the loop from, or just the header of a loop
+// that is never left.
+fn always_executed(cfg: &ControlFlowGraph, dt: &DominatorTree, lp: &Loop) -> HashSet<usize> {
+    let body = cfg
+        .blocks
+        .iter()
+        .filter(|block| lp.body.contains(&block.borrow().id) && dt.get_index(block).is_some())
+        .cloned()
+        .collect::<Vec<_>>();
+    let exiting = body
+        .iter()
+        .filter(|block| {
+            let block = block.borrow();
+            let leaves = block
+                .instructions
+                .last()
+                .is_some_and(|ins| ins.is_return() || *ins == Instruction::Throw);
+            leaves
+                || block
+                    .successors
+                    .iter()
+                    .any(|succ| !lp.body.contains(&succ.borrow().id))
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    if exiting.is_empty() {
+        return vec![lp.header.borrow().id].into_iter().collect();
+    }
+    body.into_iter()
+        .filter(|block| {
+            exiting
+                .iter()
+                .all(|exit| dt.dominates(block.clone(), exit.clone(), cfg))
+        })
+        .map(|block| block.borrow().id)
+        .collect()
+}
+
+// Arithmetic and comparisons other than `Eq` trap on operands of the wrong
+// type, invariant loads do not.
+fn may_trap(expr: &[Instruction]) -> bool {
+    use Instruction::*;
+    expr.iter()
+        .any(|ins| !ins.is_pure() && !matches!(ins, LdStatic(_) | LdGlobal(_) | LdEnv(_)))
+}
+
+// Looks for an invariant expression producing exactly one value that ends at
+// `end`, returning its first instruction. Single constants and local loads are
+// not worth a temporary.
+fn invariant_expression(
+    instructions: &[Instruction],
+    end: usize,
+    invariance: &Invariance,
+) -> Option<usize> {
+    let mut needed = 1;
+    let mut start = end + 1;
+    while needed > 0 {
+        if start == 0 {
+            return None;
+        }
+        start -= 1;
+        let ins = &instructions[start];
+        if !invariance.is_invariant(ins) {
+            return None;
+        }
+        let (pops, pushes) = ins.stack_effect().unwrap();
+        if pushes > needed {
+            return None;
+        }
+        needed = needed - pushes + pops;
+    }
+    if start == end {
+        if let Instruction::LdInt(_) | Instruction::LdFloat(_) | Instruction::LdLocal(_) =
+            instructions[start]
+        {
+            return None;
+        }
+    }
+    Some(start)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use EdgeType::*;
+    use Instruction::*;
+
+    fn instructions(function: &Function, id: usize) -> Vec<Instruction> {
+        let block = function.cfg.find_block(id).unwrap();
+        let instructions = block.borrow().instructions.clone();
+        instructions
+    }
+
+    // 2 initializes the counter in local 2, 3 loops on itself.
+    fn counting_loop(body: Vec<Instruction>) -> Function {
+        let mut header = body;
+        header.extend(vec![LdLocal(2), LdInt(1), Sub, Dup, StLocal(2), JmpNz(3)]);
+        let cfg = ControlFlowGraph::from_blocks(
+            vec![vec![LdInt(10), StLocal(2)], header, vec![]],
+            &[
+                (0, 2, FallThrough),
+                (2, 3, FallThrough),
+                (3, 3, Branch),
+                (3, 4, FallThrough),
+                (4, 1, FallThrough),
+            ],
+        );
+        let mut function = Function::new("f", 2, cfg);
+        function.locals = 3;
+        function
+    }
+
+    #[test]
+    fn hoists_product_of_unchanged_locals() {
+        let mut function = counting_loop(vec![LdLocal(0), LdLocal(1), Mul, StStatic(0)]);
+        let mut licm = LoopInvariantCodeMotion::new();
+        assert!(licm.run(&mut function));
+
+        assert_eq!(
+            instructions(&function, 2),
+            vec![
+                LdInt(10),
+                StLocal(2),
+                LdLocal(0),
+                LdLocal(1),
+                Mul,
+                StLocal(3)
+            ]
+        );
+        assert_eq!(instructions(&function, 3)[..2], [LdLocal(3), StStatic(0)]);
+        assert_eq!(licm.hoisted, 1);
+        assert_eq!(function.locals, 4);
+    }
+
+    #[test]
+    fn hoists_static_load_only_without_store_in_loop() {
+        let mut function = counting_loop(vec![LdStatic(0), StStatic(1)]);
+        assert!(LoopInvariantCodeMotion::new().run(&mut function));
+        assert_eq!(
+            instructions(&function, 2),
+            vec![LdInt(10), StLocal(2), LdStatic(0), StLocal(3)]
+        );
+
+        let mut function = counting_loop(vec![LdStatic(0), LdInt(1), Add, StStatic(0)]);
+        assert!(!LoopInvariantCodeMotion::new().run(&mut function));
+        assert_eq!(instructions(&function, 2), vec![LdInt(10), StLocal(2)]);
+    }
+
+    #[test]
+    fn temporaries_start_above_declared_locals() {
+        let mut function = counting_loop(vec![LdStatic(0), StStatic(1)]);
+        function.locals = 5;
+        assert!(LoopInvariantCodeMotion::new().run(&mut function));
+        assert_eq!(instructions(&function, 3)[..2], [LdLocal(5), StStatic(1)]);
+        assert_eq!(function.locals, 6);
+    }
+
+    #[test]
+    fn leaves_loop_entered_through_handler() {
+        // f(a): i = 0; try { throw } catch { i = i + 1; if i < 3 { x = a * 2;
+        // throw } }; return x + i
+        let body = vec![LdLocal(0), LdInt(2), Mul, StLocal(2), LdLocal(1), Throw];
+        let cfg = ControlFlowGraph::from_blocks(
+            vec![
+                vec![LdInt(0), StLocal(1), LdInt(0), Throw],
+                vec![
+                    Pop(1),
+                    LdLocal(1),
+                    LdInt(1),
+                    Add,
+                    StLocal(1),
+                    LdLocal(1),
+                    LdInt(3),
+                    Lt,
+                    JmpZ(5),
+                ],
+                body.clone(),
+                vec![LdLocal(2), LdLocal(1), Add, Ret],
+            ],
+            &[
+                (0, 2, FallThrough),
+                (2, 3, Exception),
+                (3, 5, Branch),
+                (3, 4, FallThrough),
+                (4, 3, Exception),
+                (5, 1, Return),
+            ],
+        );
+        let mut function = Function::new("f", 1, cfg);
+        function.locals = 3;
+        assert!(!LoopInvariantCodeMotion::new().run(&mut function));
+        assert_eq!(instructions(&function, 4), body);
+        assert_eq!(function.cfg.size(), 6);
+    }
+
+    #[test]
+    fn keeps_trapping_code_of_loop_that_may_not_run() {
+        // f(a, n): i = 0; while i < n { x = a + 1; s = a == 1; i = i + 1 }
+        let body = vec![
+            LdLocal(0),
+            LdInt(1),
+            Add,
+            StLocal(3),
+            LdLocal(0),
+            LdInt(1),
+            Eq,
+            StStatic(0),
+            LdLocal(2),
+            LdInt(1),
+            Add,
+            StLocal(2),
+            Jmp(3),
+        ];
+        let cfg = ControlFlowGraph::from_blocks(
+            vec![
+                vec![LdInt(0), StLocal(2)],
+                vec![LdLocal(2), LdLocal(1), Lt, JmpZ(5)],
+                body,
+                vec![],
+            ],
+            &[
+                (0, 2, FallThrough),
+                (2, 3, FallThrough),
+                (3, 5, Branch),
+                (3, 4, FallThrough),
+                (4, 3, Branch),
+                (5, 1, FallThrough),
+            ],
+        );
+        let mut function = Function::new("f", 2, cfg);
+        function.locals = 4;
+        assert!(LoopInvariantCodeMotion::new().run(&mut function));
+
+        let preheader = vec![LdInt(0), StLocal(2), LdLocal(0), LdInt(1), Eq, StLocal(4)];
+        assert_eq!(instructions(&function, 2), preheader);
+        let body = instructions(&function, 4);
+        assert_eq!(
+            body[..6],
+            [
+                LdLocal(0),
+                LdInt(1),
+                Add,
+                StLocal(3),
+                LdLocal(4),
+                StStatic(0)
+            ]
+        );
+    }
+}
@@ -0,0 +1,9 @@
+pub mod constprop;
+pub mod coroutine;
+pub mod dce;
+pub mod gvn;
+pub mod inline;
+pub mod licm;
+pub mod pre;
+pub mod tailcall;
+pub mod unbox;
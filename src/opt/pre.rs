@@ -0,0 +1,566 @@
+use crate::analysis::dom::*;
+use crate::analysis::effects::*;
+use crate::analysis::postdom::*;
+use crate::block::*;
+use crate::cfg::*;
+use crate::instructions::Instruction;
+use crate::module::Function;
+
+use std::collections::HashMap;
+
+struct Occurrence {
+    expr: usize,
+    start: usize,
+    end: usize,
+}
+
+struct BlockInfo {
+    block: CodeBlockRef,
+    preds: Vec<usize>,
+    succs: Vec<usize>,
+    occurrences: Vec<Occurrence>,
+}
+
+/// Per expression solution of the lazy code motion equations, indexed like
+/// `PartialRedundancyElimination::blocks`.
+struct Placement {
+    used_out: Vec<bool>,
+    latest: Vec<bool>,
+    uses: Vec<Option<usize>>,
+}
+
+/// Partial redundancy elimination by lazy code motion.
+///
+/// Expressions over locals, constants and statics are moved to the latest
+/// points where they are still computed on every path, so that computations
+/// already performed on some incoming paths are not repeated and loop
+/// invariant ones leave the loop. Edges into join points are split first so
+/// computations can be placed on them; splits that end up empty are undone.
+/// Computations are only placed at block entries, which a handler entered from
+/// the middle of a protected block has passed, and expressions that may trap
+/// are not moved across side effects, including instructions that may throw.
+#[derive(Default)]
+pub struct PartialRedundancyElimination {
+    pub inserted: usize,
+    pub replaced: usize,
+    expressions: Vec<Vec<Instruction>>,
+    blocks: Vec<BlockInfo>,
+    effects: Option<EffectAnalysis>,
+}
+
+impl PartialRedundancyElimination {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_effects(effects: EffectAnalysis) -> Self {
+        Self {
+            effects: Some(effects),
+            ..Default::default()
+        }
+    }
+
+    pub fn run(&mut self, function: &mut Function) -> bool {
+        let split = split_join_edges(&mut function.cfg);
+        let changed = self.optimize(function);
+        for block in split {
+            remove_if_empty(&mut function.cfg, &block);
+        }
+        self.expressions.clear();
+        self.blocks.clear();
+        changed
+    }
+
+    fn optimize(&mut self, function: &mut Function) -> bool {
+        let cfg = &mut function.cfg;
+        let mut dt = DominatorTree::new();
+        dt.analyze(cfg);
+        let mut pdt = PostDominatorTree::new();
+        pdt.analyze(cfg);
+
+        self.collect(cfg, &dt);
+        if self.expressions.is_empty() {
+            return false;
+        }
+
+        let mut next_local = std::cmp::max(function.locals, cfg.first_free_local());
+        let mut insertions: HashMap<usize, Vec<Instruction>> = HashMap::new();
+        let mut replacements: HashMap<usize, Vec<(usize, usize, u32)>> = HashMap::new();
+        for expr in 0..self.expressions.len() {
+            let placement = self.solve(expr, &pdt);
+            let mut temp = None;
+            let mut local = || {
+                *temp.get_or_insert_with(|| {
+                    next_local += 1;
+                    next_local - 1
+                })
+            };
+            for b in 0..self.blocks.len() {
+                if placement.latest[b] && placement.used_out[b] {
+                    let code = insertions.entry(b).or_default();
+                    code.extend(self.expressions[expr].iter().copied());
+                    code.push(Instruction::StLocal(local()));
+                    self.inserted += 1;
+                }
+                if let Some(i) = placement.uses[b] {
+                    if !placement.latest[b] || placement.used_out[b] {
+                        let occurrence = &self.blocks[b].occurrences[i];
+                        replacements.entry(b).or_default().push((
+                            occurrence.start,
+                            occurrence.end,
+                            local(),
+                        ));
+                        self.replaced += 1;
+                    }
+                }
+            }
+        }
+
+        if insertions.is_empty() && replacements.is_empty() {
+            return false;
+        }
+        for (b, info) in self.blocks.iter_mut().enumerate() {
+            let mut block = info.block.borrow_mut();
+            if let Some(mut replacements) = replacements.remove(&b) {
+                replacements.sort_by_key(|replacement| std::cmp::Reverse(replacement.0));
+                for (start, end, temp) in replacements {
+                    block
+                        .instructions
+                        .splice(start..end, vec![Instruction::LdLocal(temp)]);
+                }
+            }
+            if let Some(code) = insertions.remove(&b) {
+                block.instructions.splice(0..0, code);
+            }
+        }
+        function.locals = next_local;
+        true
+    }
+
+    fn collect(&mut self, cfg: &ControlFlowGraph, dt: &DominatorTree) {
+        let reachable = cfg
+            .blocks
+            .iter()
+            .filter(|block| dt.get_index(block).is_some())
+            .cloned()
+            .collect::<Vec<_>>();
+        let index = reachable
+            .iter()
+            .enumerate()
+            .map(|(i, block)| (block.borrow().id, i))
+            .collect::<HashMap<_, _>>();
+
+        let mut exprs: HashMap<Vec<Instruction>, usize> = HashMap::new();
+        for block in reachable {
+            let mut occurrences = vec![];
+            {
+                let instructions = &block.borrow().instructions;
+                let mut end = instructions.len();
+                while end > 0 {
+                    match expression_start(instructions, end - 1) {
+                        Some(start) => {
+                            let code = instructions[start..end].to_vec();
+                            let next = self.expressions.len();
+                            let expr = *exprs.entry(code.clone()).or_insert(next);
+                            if expr == next {
+                                self.expressions.push(code);
+                            }
+                            occurrences.push(Occurrence { expr, start, end });
+                            end = start;
+                        }
+                        None => end -= 1,
+                    }
+                }
+            }
+            occurrences.reverse();
+            let ids = |blocks: &Vec<CodeBlockRef>| {
+                blocks
+                    .iter()
+                    .filter_map(|block| index.get(&block.borrow().id).copied())
+                    .collect::<Vec<_>>()
+            };
+            let preds = ids(&block.borrow().predecessors);
+            let succs = ids(&block.borrow().successors);
+            self.blocks.push(BlockInfo {
+                block,
+                preds,
+                succs,
+                occurrences,
+            });
+        }
+    }
+
+    fn solve(&self, expr: usize, pdt: &PostDominatorTree) -> Placement {
+        let n = self.blocks.len();
+        let code = &self.expressions[expr];
+        let traps = may_trap(code);
+
+        let mut uses = vec![None; n];
+        let mut kill = vec![false; n];
+        let mut blocked = vec![false; n];
+        for (b, info) in self.blocks.iter().enumerate() {
+            let block = info.block.borrow();
+            let len = block.instructions.len();
+            let first_kill = block
+                .instructions
+                .iter()
+                .position(|ins| kills(code, ins, self.effects.as_ref()))
+                .unwrap_or(len);
+            let first_effect = if traps {
+                block
+                    .instructions
+                    .iter()
+                    .position(|ins| has_side_effect(ins, self.effects.as_ref()))
+                    .unwrap_or(len)
+            } else {
+                len
+            };
+            kill[b] = first_kill < len;
+            blocked[b] = first_effect < len;
+            uses[b] = info.occurrences.iter().position(|occurrence| {
+                occurrence.expr == expr
+                    && occurrence.end <= first_kill
+                    && occurrence.start <= first_effect
+            });
+        }
+        let used = |b: usize| uses[b].is_some();
+
+        // anticipated expressions; blocks that never reach the exit are
+        // treated like exits so that nothing is speculated into endless loops.
+        // A side effect ends the anticipation of an expression that may trap,
+        // so that the trap is not moved before it.
+        let mut ant_in = vec![true; n];
+        fixpoint(|| {
+            let mut changed = false;
+            for b in (0..n).rev() {
+                let info = &self.blocks[b];
+                let out = pdt.get_index(&info.block).is_some()
+                    && !info.succs.is_empty()
+                    && info.succs.iter().all(|s| ant_in[*s]);
+                let value = used(b) || (out && !kill[b] && !blocked[b]);
+                changed |= value != ant_in[b];
+                ant_in[b] = value;
+            }
+            changed
+        });
+
+        let mut avail_out = vec![true; n];
+        let mut avail_in = vec![false; n];
+        fixpoint(|| {
+            let mut changed = false;
+            for b in 0..n {
+                let info = &self.blocks[b];
+                avail_in[b] = !info.preds.is_empty() && info.preds.iter().all(|p| avail_out[*p]);
+                let value = (ant_in[b] || avail_in[b]) && !kill[b];
+                changed |= value != avail_out[b];
+                avail_out[b] = value;
+            }
+            changed
+        });
+        let earliest = (0..n)
+            .map(|b| ant_in[b] && !avail_in[b])
+            .collect::<Vec<_>>();
+
+        let mut post_out = vec![true; n];
+        let mut post_in = vec![false; n];
+        fixpoint(|| {
+            let mut changed = false;
+            for b in 0..n {
+                let info = &self.blocks[b];
+                post_in[b] = !info.preds.is_empty() && info.preds.iter().all(|p| post_out[*p]);
+                let value = (earliest[b] || post_in[b]) && !used(b);
+                changed |= value != post_out[b];
+                post_out[b] = value;
+            }
+            changed
+        });
+        let latest = (0..n)
+            .map(|b| {
+                let info = &self.blocks[b];
+                let later = !info.succs.is_empty()
+                    && info.succs.iter().all(|s| earliest[*s] || post_in[*s]);
+                (earliest[b] || post_in[b]) && (used(b) || !later)
+            })
+            .collect::<Vec<_>>();
+
+        let mut used_in = vec![false; n];
+        let mut used_out = vec![false; n];
+        fixpoint(|| {
+            let mut changed = false;
+            for b in (0..n).rev() {
+                let info = &self.blocks[b];
+                used_out[b] = info.succs.iter().any(|s| used_in[*s]);
+                let value = (used(b) || used_out[b]) && !latest[b];
+                changed |= value != used_in[b];
+                used_in[b] = value;
+            }
+            changed
+        });
+
+        Placement {
+            used_out,
+            latest,
+            uses,
+        }
+    }
+}
+
+fn fixpoint<F: FnMut() -> bool>(mut step: F) {
+    while step() {}
+}
+
+fn is_operand(ins: &Instruction) -> bool {
+    use Instruction::*;
+    matches!(ins, LdInt(_) | LdFloat(_) | LdLocal(_) | LdStatic(_))
+}
+
+fn is_operator(ins: &Instruction) -> bool {
+    use Instruction::*;
+    matches!(ins, Add | Sub | Mul | Shl | Shr | IntToFloat | FloatToInt)
+        || matches!(ins, Eq | Lt | Le | Gt | Not | And | Or | Xor | Neg)
+}
+
+// Finds the start of an expression over locals, constants and statics that
+// ends at `end` and contains at least one operator.
+fn expression_start(instructions: &[Instruction], end: usize) -> Option<usize> {
+    if !is_operator(&instructions[end]) {
+        return None;
+    }
+    let mut needed = 1;
+    let mut start = end + 1;
+    while needed > 0 {
+        if start == 0 {
+            return None;
+        }
+        start -= 1;
+        let ins = &instructions[start];
+        if !is_operand(ins) && !is_operator(ins) {
+            return None;
+        }
+        let (pops, pushes) = ins.stack_effect().unwrap();
+        needed = needed - pushes + pops;
+    }
+    Some(start)
+}
+
+fn kills(code: &[Instruction], ins: &Instruction, summaries: Option<&EffectAnalysis>) -> bool {
+    if let Instruction::StLocal(n) = ins {
+        return code.contains(&Instruction::LdLocal(*n));
+    }
+    let effects = Effects::of(ins, summaries);
+    code.iter().any(|operand| match operand {
+        Instruction::LdStatic(n) => effects.may_write_static(*n),
+        _ => false,
+    })
+}
+
+// Arithmetic and comparisons other than `Eq` trap on operands of the wrong
+// type.
+fn may_trap(code: &[Instruction]) -> bool {
+    code.iter()
+        .any(|ins| !ins.is_pure() && !matches!(ins, Instruction::LdStatic(_)))
+}
+
+// Whether a trap moved from after `ins` to before it could be told apart.
+fn has_side_effect(ins: &Instruction, summaries: Option<&EffectAnalysis>) -> bool {
+    let effects = Effects::of(ins, summaries);
+    ins.may_throw()
+        || !effects.writes_statics.is_empty()
+        || !effects.writes_envs.is_empty()
+        || effects.writes_fields
+        || effects.may_yield
+        || effects.may_not_terminate
+}
+
+// Exception edges are left alone: code placed on them would run with the
+// exception on the stack.
+fn split_join_edges(cfg: &mut ControlFlowGraph) -> Vec<CodeBlockRef> {
+    let edges = cfg
+        .edges
+        .borrow()
+        .iter()
+        .filter(|edge| {
+            let edge = edge.borrow();
+            let joins = edge.tail.as_ref().unwrap().borrow().predecessors.len() > 1;
+            joins && edge.ty != EdgeType::Exception
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    edges
+        .into_iter()
+        .map(|edge| cfg.insert_block_on_edge(edge))
+        .collect()
+}
+
+fn remove_if_empty(cfg: &mut ControlFlowGraph, block: &CodeBlockRef) {
+    let empty = matches!(
+        block.borrow().instructions.as_slice(),
+        [] | [Instruction::Jmp(_)]
+    );
+    if !empty {
+        return;
+    }
+    let incoming = block.borrow().in_edges[0].clone();
+    let tail = block.borrow().successors[0].clone();
+    cfg.redirect_edge(incoming, tail);
+    cfg.remove_block(block);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use EdgeType::*;
+    use Instruction::*;
+
+    // 2 branches to 3, which computes local 1 + local 2, or to 4; both then
+    // join in 5.
+    fn diamond(join: Vec<Instruction>) -> Function {
+        let cfg = ControlFlowGraph::from_blocks(
+            vec![
+                vec![LdLocal(0), JmpZ(4)],
+                vec![LdLocal(1), LdLocal(2), Add, StStatic(0), Jmp(5)],
+                vec![],
+                join,
+            ],
+            &[
+                (0, 2, FallThrough),
+                (2, 4, Branch),
+                (2, 3, FallThrough),
+                (3, 5, Branch),
+                (4, 5, FallThrough),
+                (5, 1, FallThrough),
+            ],
+        );
+        let mut function = Function::new("f", 3, cfg);
+        function.locals = 3;
+        function
+    }
+
+    #[test]
+    fn computes_expression_on_path_that_lacks_it() {
+        let mut function = diamond(vec![LdLocal(1), LdLocal(2), Add, StStatic(1)]);
+        let mut pre = PartialRedundancyElimination::new();
+        assert!(pre.run(&mut function));
+        assert_eq!(function.locals, 4);
+
+        let block = |id| function.cfg.find_block(id).unwrap();
+        assert_eq!(
+            block(3).borrow().instructions,
+            vec![
+                LdLocal(1),
+                LdLocal(2),
+                Add,
+                StLocal(3),
+                LdLocal(3),
+                StStatic(0),
+                Jmp(5)
+            ]
+        );
+        let split = block(4).borrow().successors[0].clone();
+        assert_eq!(
+            split.borrow().instructions,
+            vec![LdLocal(1), LdLocal(2), Add, StLocal(3)]
+        );
+        assert_eq!(split.borrow().successors[0].borrow().id, 5);
+        assert_eq!(
+            block(5).borrow().instructions,
+            vec![LdLocal(3), StStatic(1)]
+        );
+        assert_eq!(block(3).borrow().successors[0].borrow().id, 5);
+        assert_eq!((pre.inserted, pre.replaced), (2, 2));
+    }
+
+    #[test]
+    fn leaves_expression_not_needed_at_join() {
+        let mut function = diamond(vec![LdLocal(1), StStatic(1)]);
+        assert!(!PartialRedundancyElimination::new().run(&mut function));
+        assert_eq!(function.cfg.size(), 6);
+        assert_eq!(function.locals, 3);
+    }
+
+    #[test]
+    fn temporaries_start_above_declared_locals() {
+        let mut function = diamond(vec![LdLocal(1), LdLocal(2), Add, StStatic(1)]);
+        function.locals = 6;
+        assert!(PartialRedundancyElimination::new().run(&mut function));
+        let join = function.cfg.find_block(5).unwrap();
+        assert_eq!(join.borrow().instructions, vec![LdLocal(6), StStatic(1)]);
+        assert_eq!(function.locals, 7);
+    }
+
+    // 2 calls 0 and jumps to 4, 3 handles what the call throws and joins it.
+    fn protected(op: Instruction) -> Function {
+        let cfg = ControlFlowGraph::from_blocks(
+            vec![
+                vec![Call(0), LdLocal(0), LdLocal(1), op, StStatic(0), Jmp(4)],
+                vec![Pop(1), Jmp(4)],
+                vec![LdLocal(0), LdLocal(1), op, Ret],
+            ],
+            &[
+                (0, 2, FallThrough),
+                (2, 3, Exception),
+                (2, 4, Branch),
+                (3, 4, Branch),
+                (4, 1, Return),
+            ],
+        );
+        Function::new("f", 2, cfg)
+    }
+
+    #[test]
+    fn computes_expression_before_code_that_may_throw() {
+        let mut function = protected(Eq);
+        assert!(PartialRedundancyElimination::new().run(&mut function));
+
+        let block = |id| {
+            function
+                .cfg
+                .find_block(id)
+                .unwrap()
+                .borrow()
+                .instructions
+                .clone()
+        };
+        assert_eq!(
+            block(2),
+            vec![
+                LdLocal(0),
+                LdLocal(1),
+                Eq,
+                StLocal(2),
+                Call(0),
+                LdLocal(2),
+                StStatic(0),
+                Jmp(4)
+            ]
+        );
+        assert_eq!(block(3), vec![Pop(1), Jmp(4)]);
+        assert_eq!(block(4), vec![LdLocal(2), Ret]);
+        assert_eq!(function.cfg.size(), 5);
+    }
+
+    #[test]
+    fn keeps_trapping_expression_behind_side_effects() {
+        let mut function = protected(Add);
+        assert!(!PartialRedundancyElimination::new().run(&mut function));
+        let join = function.cfg.find_block(4).unwrap();
+        assert_eq!(
+            join.borrow().instructions,
+            vec![LdLocal(0), LdLocal(1), Add, Ret]
+        );
+
+        let join = vec![
+            LdInt(1),
+            StStatic(1),
+            LdLocal(1),
+            LdLocal(2),
+            Add,
+            StStatic(2),
+        ];
+        let mut function = diamond(join.clone());
+        assert!(!PartialRedundancyElimination::new().run(&mut function));
+        let block = function.cfg.find_block(5).unwrap();
+        assert_eq!(block.borrow().instructions, join);
+        assert_eq!(function.cfg.size(), 6);
+    }
+}
@@ -0,0 +1,249 @@
+use crate::analysis::liveness::*;
+use crate::block::*;
+use crate::cfg::*;
+use crate::instructions::Instruction;
+use crate::module::*;
+use crate::verifier;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Turns self tail calls into loops.
+///
+/// `TailCall(n)` from function `n` stores the arguments into the parameter
+/// locals, drops what is left on the operand stack and jumps back to the
+/// start of the function. The jump is preceded by a `ThreadYield` so the loop
+/// can be preempted like the recursion it replaces. Functions whose other
+/// locals may be read before being written are skipped, as a real call would
+/// start with them cleared. So are functions with an environment, which a
+/// real call would create anew and closures of earlier iterations may hold.
+#[derive(Default)]
+pub struct TailCallElimination {
+    pub converted: usize,
+}
+
+impl TailCallElimination {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn run(&mut self, module: &mut Module) -> bool {
+        let converted = self.converted;
+        for n in 0..module.functions.len() as u32 {
+            let heights = match verifier::stack_heights(module, n) {
+                Ok(heights) => heights,
+                Err(_) => continue,
+            };
+            let function = &module.functions[n as usize];
+            let mut sites = vec![];
+            for block in function.cfg.blocks.iter() {
+                let block = block.borrow();
+                if block.instructions.last() != Some(&Instruction::TailCall(n)) {
+                    continue;
+                }
+                let mut height = match heights.get(&block.id) {
+                    Some(height) => *height,
+                    None => continue,
+                };
+                for ins in block.instructions[..block.instructions.len() - 1].iter() {
+                    let (pops, pushes) = module.stack_effect(ins).unwrap();
+                    height = height - pops + pushes;
+                }
+                sites.push((block.id, height - function.params));
+            }
+            if sites.is_empty() || !function.env.is_empty() || reads_uninitialized(function) {
+                continue;
+            }
+
+            // the code of the entry block may move to the header
+            let function = &mut module.functions[n as usize];
+            let header = loop_header(&mut function.cfg);
+            for (id, leftover) in sites {
+                let block = match function.cfg.find_block(id) {
+                    Some(block) if block.borrow().instructions.is_empty() => header.clone(),
+                    Some(block) => block,
+                    None => continue,
+                };
+                self.convert(function, block, header.clone(), leftover);
+            }
+        }
+        self.converted != converted
+    }
+
+    fn convert(
+        &mut self,
+        function: &mut Function,
+        mut block: CodeBlockRef,
+        header: CodeBlockRef,
+        leftover: u32,
+    ) {
+        let cfg = &mut function.cfg;
+        let out_edges = block.borrow().out_edges.clone();
+        for edge in out_edges {
+            cfg.remove_edge(edge);
+        }
+        let handler = block
+            .borrow()
+            .handler
+            .and_then(|handler| cfg.find_block(handler));
+        {
+            let mut block = block.borrow_mut();
+            block.instructions.pop();
+            for n in (0..function.params).rev() {
+                block.instructions.push(Instruction::StLocal(n));
+            }
+            if leftover > 0 {
+                block.instructions.push(Instruction::Pop(leftover));
+            }
+            block.instructions.push(Instruction::ThreadYield);
+            block
+                .instructions
+                .push(Instruction::Jmp(header.borrow().id as u32));
+        }
+        if let Some(handler) = handler.filter(|_| block.borrow().may_throw()) {
+            cfg.insert_edge(Rc::new(RefCell::new(Edge {
+                head: Some(block.clone()),
+                tail: Some(handler),
+                ty: EdgeType::Exception,
+            })));
+        }
+        cfg.insert_edge(Rc::new(RefCell::new(Edge {
+            head: Some(block),
+            tail: Some(header),
+            ty: EdgeType::Branch,
+        })));
+        self.converted += 1;
+    }
+}
+
+fn reads_uninitialized(function: &Function) -> bool {
+    let mut liveness = LivenessAnalysis::new();
+    liveness.analyze(&function.cfg);
+    let entry = function.cfg.entry.borrow().id;
+    liveness.live_in(entry).is_some_and(|live| {
+        live.iter()
+            .any(|slot| matches!(slot, Slot::Local(n) if *n >= function.params))
+    })
+}
+
+// The block a self tail call jumps to. The entry block itself is kept free
+// of predecessors: if it holds code, that code is moved to a new block.
+pub(crate) fn loop_header(cfg: &mut ControlFlowGraph) -> CodeBlockRef {
+    let mut entry = cfg.get_entry_block();
+    {
+        let entry = entry.borrow();
+        if entry.instructions.is_empty() && entry.successors.len() == 1 {
+            return entry.successors[0].clone();
+        }
+    }
+
+    let id = cfg.new_id();
+    let header = {
+        let mut entry = entry.borrow_mut();
+        CodeBlockRef::new(CodeBlock {
+            id,
+            instructions: std::mem::take(&mut entry.instructions),
+            switch: entry.switch.take(),
+            handler: entry.handler.take(),
+            ..Default::default()
+        })
+    };
+    let header = cfg.insert_block(header);
+    let out_edges = entry.borrow().out_edges.clone();
+    for edge in out_edges {
+        let tail = edge.borrow().tail.as_ref().unwrap().clone();
+        let ty = edge.borrow().ty;
+        cfg.remove_edge(edge);
+        cfg.insert_edge(Rc::new(RefCell::new(Edge {
+            head: Some(header.clone()),
+            tail: Some(tail),
+            ty,
+        })));
+    }
+    cfg.insert_edge(Rc::new(RefCell::new(Edge {
+        head: Some(entry),
+        tail: Some(header.clone()),
+        ty: EdgeType::FallThrough,
+    })));
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::{Interpreter, Value};
+    use EdgeType::*;
+    use Instruction::*;
+
+    // sum(n, acc): if n == 0 { acc } else { <extra>; sum(n - 1, acc + n) }
+    fn sum(extra: Vec<Instruction>) -> Module {
+        let mut recurse = extra;
+        recurse.extend(vec![
+            LdLocal(0),
+            LdInt(1),
+            Sub,
+            LdLocal(1),
+            LdLocal(0),
+            Add,
+            TailCall(0),
+        ]);
+        let cfg = ControlFlowGraph::from_blocks(
+            vec![vec![LdLocal(0), JmpZ(4)], recurse, vec![LdLocal(1)]],
+            &[
+                (0, 2, FallThrough),
+                (2, 4, Branch),
+                (2, 3, FallThrough),
+                (4, 1, FallThrough),
+            ],
+        );
+        let mut module = Module::new();
+        module.add_function(Function::new("sum", 2, cfg));
+        module
+    }
+
+    fn call(module: &Module, n: i64) -> Result<Value, crate::interpreter::Trap> {
+        Interpreter::new(module).call(0, &[Value::Int(n), Value::Int(0)])
+    }
+
+    #[test]
+    fn self_tail_call_becomes_yielding_loop() {
+        let mut module = sum(vec![]);
+        let mut tce = TailCallElimination::new();
+        assert!(tce.run(&mut module));
+
+        let block = module.functions[0].cfg.find_block(3).unwrap();
+        assert_eq!(
+            block.borrow().instructions[6..],
+            [StLocal(1), StLocal(0), ThreadYield, Jmp(2)]
+        );
+        let successors = block
+            .borrow()
+            .successors
+            .iter()
+            .map(|block| block.borrow().id)
+            .collect::<Vec<_>>();
+        assert_eq!(successors, vec![2]);
+        assert_eq!(tce.converted, 1);
+        assert_eq!(module.verify(), Ok(()));
+        assert_eq!(call(&module, 100), Ok(Value::Int(5050)));
+    }
+
+    #[test]
+    fn drops_values_left_below_arguments() {
+        let mut module = sum(vec![LdInt(7)]);
+        assert!(TailCallElimination::new().run(&mut module));
+
+        let block = module.functions[0].cfg.find_block(3).unwrap();
+        assert_eq!(
+            block.borrow().instructions[7..],
+            [StLocal(1), StLocal(0), Pop(1), ThreadYield, Jmp(2)]
+        );
+        assert_eq!(call(&module, 4), Ok(Value::Int(10)));
+    }
+
+    #[test]
+    fn skips_function_reading_uninitialized_local() {
+        let mut module = sum(vec![LdLocal(2), Pop(1)]);
+        assert!(!TailCallElimination::new().run(&mut module));
+    }
+}
@@ -0,0 +1,138 @@
+use crate::analysis::capture::*;
+use crate::instructions::Instruction;
+use crate::module::*;
+
+use std::collections::HashMap;
+
+/// Moves the environment slots no closure can reach into plain locals.
+///
+/// `LdEnv`/`StEnv` of such a slot become `LdLocal`/`StLocal` of a fresh
+/// local, which starts out nil just like the slot. A function left without
+/// environment accesses or closures drops its environment, so calling it no
+/// longer allocates one.
+#[derive(Default)]
+pub struct EnvironmentUnboxing {
+    pub unboxed: usize,
+}
+
+impl EnvironmentUnboxing {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn run(&mut self, module: &mut Module) -> bool {
+        let mut ca = CaptureAnalysis::new();
+        ca.analyze(module);
+        let unboxed = self.unboxed;
+        for (n, function) in module.functions.iter_mut().enumerate() {
+            let slots = ca.unboxed(n as u32);
+            let base = std::cmp::max(function.locals, function.cfg.first_free_local());
+            let mapping = slots
+                .iter()
+                .enumerate()
+                .map(|(i, slot)| (*slot, base + i as u32))
+                .collect::<HashMap<_, _>>();
+            function.locals = base + slots.len() as u32;
+            self.unboxed += slots.len();
+
+            let mut uses_env = false;
+            for block in function.cfg.blocks.iter_mut() {
+                for ins in block.borrow_mut().instructions.iter_mut() {
+                    match ins {
+                        Instruction::LdEnv(slot) if mapping.contains_key(slot) => {
+                            *ins = Instruction::LdLocal(mapping[slot]);
+                        }
+                        Instruction::StEnv(slot) if mapping.contains_key(slot) => {
+                            *ins = Instruction::StLocal(mapping[slot]);
+                        }
+                        Instruction::LdEnv(_)
+                        | Instruction::StEnv(_)
+                        | Instruction::MkClosure(_) => uses_env = true,
+                        _ => (),
+                    }
+                }
+            }
+            if !uses_env && !ca.closures.contains(&(n as u32)) {
+                function.env.clear();
+            }
+        }
+        self.unboxed != unboxed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::EdgeType::FallThrough;
+    use crate::cfg::ControlFlowGraph;
+    use crate::interpreter::{Interpreter, Value};
+    use Instruction::{Add, CallClosure, LdEnv, LdInt, LdLocal, MkClosure, Mul, StEnv, StLocal};
+
+    fn function(env: &[&str], instructions: Vec<Instruction>) -> Function {
+        let cfg = ControlFlowGraph::from_blocks(
+            vec![instructions],
+            &[(0, 2, FallThrough), (2, 1, FallThrough)],
+        );
+        let mut function = Function::new("f", 0, cfg);
+        function.env = env.iter().map(|name| name.to_string()).collect();
+        function
+    }
+
+    fn instructions(function: &Function) -> Vec<Instruction> {
+        let block = function.cfg.find_block(2).unwrap();
+        let instructions = block.borrow().instructions.clone();
+        instructions
+    }
+
+    #[test]
+    fn moves_uncaptured_slots_to_locals() {
+        // f: a = 1; b = 2; return (|| b * 10)() + a
+        let f = vec![
+            LdInt(1),
+            StEnv(0),
+            LdInt(2),
+            StEnv(1),
+            MkClosure(1),
+            CallClosure(0),
+            LdEnv(0),
+            Add,
+        ];
+        let closure = vec![LdEnv(1), LdInt(10), Mul];
+        // g: x = 3; return x
+        let g = vec![LdInt(3), StEnv(0), LdEnv(0)];
+        let mut module = Module::new();
+        module.add_function(function(&["a", "b"], f));
+        module.add_function(function(&["a", "b"], closure.clone()));
+        module.add_function(function(&["x"], g));
+        let mut unboxing = EnvironmentUnboxing::new();
+        assert!(unboxing.run(&mut module));
+        assert_eq!(unboxing.unboxed, 2);
+
+        let f = vec![
+            LdInt(1),
+            StLocal(0),
+            LdInt(2),
+            StEnv(1),
+            MkClosure(1),
+            CallClosure(0),
+            LdLocal(0),
+            Add,
+        ];
+        assert_eq!(instructions(&module.functions[0]), f);
+        assert_eq!(module.functions[0].locals, 1);
+        assert_eq!(module.functions[0].env.len(), 2);
+        assert_eq!(instructions(&module.functions[1]), closure);
+        assert_eq!(module.functions[1].env.len(), 2);
+        assert_eq!(
+            instructions(&module.functions[2]),
+            vec![LdInt(3), StLocal(0), LdLocal(0)]
+        );
+        assert!(module.functions[2].env.is_empty());
+
+        assert_eq!(module.verify(), Ok(()));
+        let mut interpreter = Interpreter::new(&module);
+        assert_eq!(interpreter.call(0, &[]), Ok(Value::Int(21)));
+        assert_eq!(interpreter.call(2, &[]), Ok(Value::Int(3)));
+        assert!(!unboxing.run(&mut module));
+    }
+}
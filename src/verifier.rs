@@ -0,0 +1,353 @@
+use crate::block::*;
+use crate::instructions::Instruction;
+use crate::module::*;
+
+use std::collections::HashMap;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum ErrorKind {
+    UnknownFunction(u32),
+    UnknownGlobal(u32),
+    UnknownStatic(u32),
+    LocalOutOfRange(u32),
+    EnvOutOfRange(u32),
+    /// More parameters than local slots.
+    TooManyParams,
+    /// A branch, return or trap before the end of its block.
+    MisplacedTerminator,
+    /// The final branch and the block's edges disagree.
+    BranchMismatch,
+    /// Control reaches the end of a block that has nowhere to go.
+    MissingFallThrough,
+    /// A `Switch` without a well-formed table, or a table without a `Switch`.
+    InvalidSwitchTable,
+    /// The exception edges disagree with the block's handler.
+    HandlerMismatch,
+    /// A closure of the function would copy environment slots it names
+    /// differently.
+    ClosureEnvMismatch(u32),
+    StackUnderflow,
+    StackMismatch { expected: u32, found: u32 },
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct VerifyError {
+    pub function: u32,
+    pub block: usize,
+    pub kind: ErrorKind,
+}
+
+impl std::fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use ErrorKind::*;
+        write!(f, "function {}, block {}: ", self.function, self.block)?;
+        match &self.kind {
+            UnknownFunction(n) => write!(f, "call to unknown function {}", n),
+            UnknownGlobal(n) => write!(f, "unknown global {}", n),
+            UnknownStatic(n) => write!(f, "unknown static {}", n),
+            LocalOutOfRange(n) => write!(f, "local {} out of range", n),
+            EnvOutOfRange(n) => write!(f, "environment slot {} out of range", n),
+            TooManyParams => write!(f, "more parameters than locals"),
+            MisplacedTerminator => write!(f, "terminator before the end of the block"),
+            BranchMismatch => write!(f, "branch does not match the outgoing edges"),
+            MissingFallThrough => write!(f, "no fall through successor"),
+            InvalidSwitchTable => write!(f, "switch table does not match the block"),
+            HandlerMismatch => write!(f, "exception edges do not match the handler"),
+            ClosureEnvMismatch(n) => write!(f, "closure of {} names its slots differently", n),
+            StackUnderflow => write!(f, "operand stack underflow"),
+            StackMismatch { expected, found } => write!(
+                f,
+                "operand stack height {} where {} was expected",
+                found, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// Checks that every function of `module` only refers to functions, slots
+/// and blocks that exist and that the operand stack has the same height on
+/// every path into a block.
+pub fn verify(module: &Module) -> Result<(), VerifyError> {
+    for n in 0..module.functions.len() {
+        verify_function(module, n as u32)?;
+    }
+    Ok(())
+}
+
+pub fn verify_function(module: &Module, n: u32) -> Result<(), VerifyError> {
+    stack_heights(module, n).map(|_| ())
+}
+
+/// Verifies function `n` and returns the operand stack height on entry to
+/// each of its reachable blocks, keyed by block id.
+pub fn stack_heights(module: &Module, n: u32) -> Result<HashMap<usize, u32>, VerifyError> {
+    let error = |block: usize, kind: ErrorKind| VerifyError {
+        function: n,
+        block,
+        kind,
+    };
+    let function = match module.functions.get(n as usize) {
+        Some(function) => function,
+        None => return Err(error(0, ErrorKind::UnknownFunction(n))),
+    };
+    let cfg = &function.cfg;
+    if function.params > function.locals {
+        return Err(error(cfg.entry.borrow().id, ErrorKind::TooManyParams));
+    }
+
+    let exit = cfg.exit.borrow().id;
+    let mut heights: HashMap<usize, u32> = HashMap::new();
+    heights.insert(cfg.entry.borrow().id, 0);
+    let mut worklist = vec![cfg.entry.clone()];
+    while let Some(block) = worklist.pop() {
+        let block = block.borrow();
+        let id = block.id;
+        let mut height = heights[&id];
+        for (i, ins) in block.instructions.iter().enumerate() {
+            check_operand(module, function, ins).map_err(|kind| error(id, kind))?;
+            if ins.is_terminator() && i + 1 != block.instructions.len() {
+                return Err(error(id, ErrorKind::MisplacedTerminator));
+            }
+
+            let (pops, pushes) = module.stack_effect(ins).unwrap();
+            if pops > height {
+                return Err(error(id, ErrorKind::StackUnderflow));
+            }
+            height = height - pops + pushes;
+        }
+        check_edges(&block, exit).map_err(|kind| error(id, kind))?;
+
+        for edge in block.out_edges.iter() {
+            let edge = edge.borrow();
+            // a handler starts with just the thrown value on the stack
+            let height = match edge.ty {
+                EdgeType::Return => continue,
+                EdgeType::Exception => 1,
+                _ => height,
+            };
+            let succ = edge.tail.as_ref().unwrap();
+            let succ_id = succ.borrow().id;
+            match heights.get(&succ_id) {
+                Some(expected) if *expected != height => {
+                    return Err(error(
+                        succ_id,
+                        ErrorKind::StackMismatch {
+                            expected: *expected,
+                            found: height,
+                        },
+                    ));
+                }
+                Some(_) => (),
+                None => {
+                    heights.insert(succ_id, height);
+                    worklist.push(succ.clone());
+                }
+            }
+        }
+    }
+    Ok(heights)
+}
+
+fn check_operand(module: &Module, function: &Function, ins: &Instruction) -> Result<(), ErrorKind> {
+    use Instruction::*;
+    match ins {
+        Call(n) | TailCall(n) if module.resolve(*n).is_none() => Err(ErrorKind::UnknownFunction(*n)),
+        // the closure copies the slots of the environment by position, or
+        // starts with nil ones without an environment to copy
+        MkClosure(n) => match module.resolve(*n) {
+            None => Err(ErrorKind::UnknownFunction(*n)),
+            Some(closure)
+                if !function.env.is_empty() && !function.env.starts_with(&closure.env) =>
+            {
+                Err(ErrorKind::ClosureEnvMismatch(*n))
+            }
+            Some(_) => Ok(()),
+        },
+        LdGlobal(n) if *n as usize >= module.globals.len() => Err(ErrorKind::UnknownGlobal(*n)),
+        LdStatic(n) | StStatic(n) if *n as usize >= module.statics.len() => {
+            Err(ErrorKind::UnknownStatic(*n))
+        }
+        LdLocal(n) | StLocal(n) if *n >= function.locals => Err(ErrorKind::LocalOutOfRange(*n)),
+        LdEnv(n) | StEnv(n) if *n as usize >= function.env.len() => {
+            Err(ErrorKind::EnvOutOfRange(*n))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn check_edges(block: &CodeBlock, exit: usize) -> Result<(), ErrorKind> {
+    let edge_to = |ty: EdgeType| {
+        block
+            .out_edges
+            .iter()
+            .filter(|edge| edge.borrow().ty == ty)
+            .map(|edge| edge.borrow().tail.as_ref().unwrap().borrow().id)
+            .collect::<Vec<_>>()
+    };
+    let branches = edge_to(EdgeType::Branch);
+    let fallthroughs = edge_to(EdgeType::FallThrough);
+    let returns = edge_to(EdgeType::Return);
+
+    let handler = match block.handler {
+        Some(handler) if block.may_throw() => vec![handler],
+        _ => vec![],
+    };
+    if edge_to(EdgeType::Exception) != handler {
+        return Err(ErrorKind::HandlerMismatch);
+    }
+
+    let last = block.instructions.last();
+    if block.switch.is_some() && last != Some(&Instruction::Switch) {
+        return Err(ErrorKind::InvalidSwitchTable);
+    }
+    // a handled throw stays in the function
+    let handled = last == Some(&Instruction::Throw) && block.handler.is_some();
+    if last.is_some_and(|ins| ins.is_return() || *ins == Instruction::Throw) {
+        if !branches.is_empty()
+            || !fallthroughs.is_empty()
+            || returns.iter().any(|id| *id != exit)
+            || (handled && !returns.is_empty())
+        {
+            return Err(ErrorKind::BranchMismatch);
+        }
+        return Ok(());
+    }
+    if !returns.is_empty() {
+        return Err(ErrorKind::BranchMismatch);
+    }
+
+    let (targets, falls_through) = match last {
+        Some(Instruction::Jmp(target)) => (vec![*target], false),
+        Some(Instruction::JmpZ(target)) | Some(Instruction::JmpNz(target)) => (vec![*target], true),
+        Some(Instruction::Switch) => match block.switch.as_ref() {
+            Some(table) if table.is_well_formed() => (table.targets(), true),
+            _ => return Err(ErrorKind::InvalidSwitchTable),
+        },
+        _ => (vec![], true),
+    };
+    // a switch has one edge per distinct target
+    let mut branches = branches;
+    branches.sort_unstable();
+    if branches.len() != targets.len()
+        || branches.iter().zip(targets.iter()).any(|(a, b)| *a != *b as usize)
+    {
+        return Err(ErrorKind::BranchMismatch);
+    }
+    if fallthroughs.len() > 1 || (!falls_through && !fallthroughs.is_empty()) {
+        return Err(ErrorKind::BranchMismatch);
+    }
+    if falls_through && fallthroughs.is_empty() && block.id != exit {
+        return Err(ErrorKind::MissingFallThrough);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cfg::ControlFlowGraph;
+    use EdgeType::*;
+    use Instruction::*;
+
+    fn straight_line(instructions: Vec<Instruction>) -> Module {
+        let cfg = ControlFlowGraph::from_blocks(
+            vec![instructions],
+            &[(0, 2, FallThrough), (2, 1, FallThrough)],
+        );
+        let mut module = Module::new();
+        module.add_function(Function::new("f", 1, cfg));
+        module.add_static("s", Literal::Nil);
+        module
+    }
+
+    fn error(block: usize, kind: ErrorKind) -> Result<(), VerifyError> {
+        Err(VerifyError {
+            function: 0,
+            block,
+            kind,
+        })
+    }
+
+    #[test]
+    fn accepts_balanced_code() {
+        let module = straight_line(vec![LdLocal(0), LdInt(1), Add, StStatic(0)]);
+        assert_eq!(verify(&module), Ok(()));
+    }
+
+    #[test]
+    fn rejects_unknown_operands() {
+        let module = straight_line(vec![LdInt(1), Add]);
+        assert_eq!(verify(&module), error(2, ErrorKind::StackUnderflow));
+
+        let module = straight_line(vec![LdStatic(1)]);
+        assert_eq!(verify(&module), error(2, ErrorKind::UnknownStatic(1)));
+
+        let module = straight_line(vec![Call(3)]);
+        assert_eq!(verify(&module), error(2, ErrorKind::UnknownFunction(3)));
+
+        let mut module = straight_line(vec![LdLocal(1)]);
+        module.functions[0].locals = 1;
+        assert_eq!(verify(&module), error(2, ErrorKind::LocalOutOfRange(1)));
+    }
+
+    #[test]
+    fn rejects_unknown_function_index() {
+        let module = straight_line(vec![]);
+        let unknown = Err(VerifyError {
+            function: 1,
+            block: 0,
+            kind: ErrorKind::UnknownFunction(1),
+        });
+        assert_eq!(verify_function(&module, 1), unknown);
+    }
+
+    #[test]
+    fn closures_copy_slots_of_the_same_name() {
+        let mut module = straight_line(vec![MkClosure(1), Pop(1)]);
+        let cfg = ControlFlowGraph::from_blocks(
+            vec![vec![LdEnv(1)]],
+            &[(0, 2, FallThrough), (2, 1, FallThrough)],
+        );
+        module.add_function(Function::new("g", 0, cfg));
+        let env = |names: &[&str]| names.iter().map(|name| name.to_string()).collect();
+        module.functions[1].env = env(&["a", "b"]);
+
+        module.functions[0].env = env(&["a", "b", "c"]);
+        assert_eq!(verify(&module), Ok(()));
+        module.functions[0].env = env(&["a"]);
+        assert_eq!(verify(&module), error(2, ErrorKind::ClosureEnvMismatch(1)));
+        module.functions[0].env = env(&["b", "a"]);
+        assert_eq!(verify(&module), error(2, ErrorKind::ClosureEnvMismatch(1)));
+        module.functions[0].env = vec![];
+        assert_eq!(verify(&module), Ok(()));
+    }
+
+    #[test]
+    fn rejects_different_stack_heights_at_join() {
+        let cfg = ControlFlowGraph::from_blocks(
+            vec![vec![LdLocal(0), JmpZ(4)], vec![LdInt(1)], vec![]],
+            &[
+                (0, 2, FallThrough),
+                (2, 4, Branch),
+                (2, 3, FallThrough),
+                (3, 4, FallThrough),
+                (4, 1, FallThrough),
+            ],
+        );
+        let mut module = Module::new();
+        module.add_function(Function::new("f", 1, cfg));
+        let kind = ErrorKind::StackMismatch {
+            expected: 0,
+            found: 1,
+        };
+        assert_eq!(verify(&module), error(4, kind));
+    }
+
+    #[test]
+    fn rejects_branch_without_edge() {
+        let module = straight_line(vec![Jmp(1)]);
+        assert_eq!(verify(&module), error(2, ErrorKind::BranchMismatch));
+    }
+}